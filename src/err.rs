@@ -1,6 +1,7 @@
 //! Errors
 use std::convert::From;
 use std::fmt::{self, Display};
+use std::io;
 use std::num::{ParseIntError, ParseFloatError};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -49,4 +50,78 @@ impl From<ParseFloatError> for ParseNumErr {
       kind: ParseNumErrKind::BadFormat(format!("{}", e))
     }
   }
-}
\ No newline at end of file
+}
+
+/// Errors raised by the react runtime itself, as opposed to the errors
+/// returned by actors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReactErr { kind: ReactErrKind }
+
+impl ReactErr {
+  pub fn new(kind: ReactErrKind) -> ReactErr {
+    ReactErr {
+      kind: kind
+    }
+  }
+
+  pub fn timeout() -> ReactErr {
+    ReactErr::new(ReactErrKind::Timeout)
+  }
+
+  pub fn kind(&self) -> &ReactErrKind {
+    &self.kind
+  }
+}
+
+impl From<io::Error> for ReactErr {
+  fn from(e: io::Error) -> Self {
+    ReactErr::new(ReactErrKind::Io(format!("{}", e)))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReactErrKind {
+  /// No reply arrived before the deadline of an ask.
+  Timeout,
+  /// Every receiver of an ask dropped its reply channel without answering.
+  NoReply,
+  /// The other side of a channel has gone away.
+  Disconnected,
+  /// `reply` was called while the actor was not handling an ask.
+  NotAsked,
+  /// No actor lives at the given address.
+  UnknownActor(String),
+  /// A bounded mailbox is full and refuses the message.
+  MailboxFull,
+  /// A network operation failed.
+  Io(String),
+  /// A message could not be encoded or decoded.
+  Codec(String),
+  /// A topic or topic pattern is malformed.
+  InvalidTopic(String),
+  /// The name of a child actor is empty or contains a `/`.
+  InvalidName(String),
+  /// A child with the same name is already running under the parent.
+  NameTaken(String),
+  /// A child was spawned with another error type than its dispatcher.
+  WrongErrorType,
+}
+
+impl Display for ReactErr {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.kind {
+      ReactErrKind::Timeout => write!(f, "timed out waiting for a reply"),
+      ReactErrKind::NoReply => write!(f, "no actor replied to the ask"),
+      ReactErrKind::Disconnected => write!(f, "the other side of the channel is gone"),
+      ReactErrKind::NotAsked => write!(f, "there is no pending ask to reply to"),
+      ReactErrKind::UnknownActor(ref uri) => write!(f, "no actor at {}", uri),
+      ReactErrKind::MailboxFull => write!(f, "the mailbox is full"),
+      ReactErrKind::Io(ref s) => write!(f, "I/O error: {}", s),
+      ReactErrKind::Codec(ref s) => write!(f, "codec error: {}", s),
+      ReactErrKind::InvalidTopic(ref s) => write!(f, "invalid topic: {}", s),
+      ReactErrKind::InvalidName(ref s) => write!(f, "invalid actor name: {}", s),
+      ReactErrKind::NameTaken(ref uri) => write!(f, "an actor already runs at {}", uri),
+      ReactErrKind::WrongErrorType => write!(f, "a child must have the error type of its dispatcher"),
+    }
+  }
+}
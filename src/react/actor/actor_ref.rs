@@ -0,0 +1,192 @@
+//!
+//! Handles to single actors.
+//!
+//! An `ActorRef` pairs the address of an actor with the courier of its
+//! dispatcher, which delivers messages without knowing the error type of
+//! the dispatcher. Couriers only hold weak references, so a handle kept
+//! somewhere, even by an actor of the same dispatcher, does not keep the
+//! dispatcher alive; once it is gone, sending fails like sending to an
+//! unknown actor.
+//!
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+
+use err::ReactErr;
+use react::MsgTrait;
+use react::reply::{self, ReplyTo, ReplyHandle};
+use super::ActorUri;
+
+/// Delivers messages to the actors of one dispatcher.
+pub trait Courier<M: MsgTrait>: Send + Sync {
+  /// Queues `m` for the actor at `to` only, regardless of its filter, with
+  /// the reply channel of an ask if there is one.
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr>;
+
+  /// Unsubscribes the actor at `to`.
+  fn stop(&self, to: &ActorUri) -> Result<(), ReactErr>;
+}
+
+/// A handle to one actor, returned when it is subscribed. Handles are equal
+/// when they point to the same actor of the same dispatcher.
+pub struct ActorRef<M: MsgTrait> {
+  path: ActorUri,
+  courier: Arc<Courier<M>>
+}
+
+impl<M: MsgTrait> ActorRef<M> {
+  pub fn new(path: ActorUri, courier: Arc<Courier<M>>) -> ActorRef<M> {
+    ActorRef {
+      path: path,
+      courier: courier
+    }
+  }
+
+  /// The address of the actor in its dispatcher.
+  pub fn path(&self) -> &ActorUri {
+    &self.path
+  }
+
+  /// A handle to the child named `name` of the actor, which may not exist.
+  pub fn child(&self, name: &str) -> ActorRef<M> {
+    let path = ActorUri::new(self.path.host_name(), self.path.port(),
+                             &format!("{}/{}", self.path.path(), name));
+    ActorRef::new(path, self.courier.clone())
+  }
+
+  /// Sends `m` to the actor. Fails if the actor is no longer subscribed, or
+  /// its mailbox refuses the message.
+  pub fn tell(&self, m: M) -> Result<(), ReactErr> {
+    self.courier.forward(&self.path, m, None)
+  }
+
+  /// Sends `m` to the actor and returns a handle for its reply, which fails
+  /// with the error of `tell` if the ask could not be queued.
+  pub fn ask(&self, m: M, timeout: Duration) -> ReplyHandle<M> {
+    let (reply_to, handle) = reply::channel(timeout);
+    match self.courier.forward(&self.path, m, Some(reply_to)) {
+      Ok(()) => handle,
+      Err(e) => handle.fail(e)
+    }
+  }
+
+  /// Sends `m` together with the reply channel of another ask, so that the
+  /// actor answers the original sender.
+  pub fn forward(&self, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    self.courier.forward(&self.path, m, reply_to)
+  }
+
+  /// Stops the actor and its children, like unsubscribing it from its
+  /// dispatcher.
+  pub fn stop(&self) -> Result<(), ReactErr> {
+    self.courier.stop(&self.path)
+  }
+
+  /// Tells the dispatchers apart, as the addresses are only unique within
+  /// one of them.
+  fn courier_id(&self) -> usize {
+    &*self.courier as *const Courier<M> as *const () as usize
+  }
+}
+
+impl<M: MsgTrait> Clone for ActorRef<M> {
+  fn clone(&self) -> ActorRef<M> {
+    ActorRef {
+      path: self.path.clone(),
+      courier: self.courier.clone()
+    }
+  }
+}
+
+impl<M: MsgTrait> PartialEq for ActorRef<M> {
+  fn eq(&self, other: &ActorRef<M>) -> bool {
+    self.path == other.path && self.courier_id() == other.courier_id()
+  }
+}
+
+impl<M: MsgTrait> Eq for ActorRef<M> {}
+
+impl<M: MsgTrait> Hash for ActorRef<M> {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.path.hash(state);
+    self.courier_id().hash(state);
+  }
+}
+
+impl<M: MsgTrait> fmt::Debug for ActorRef<M> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ActorRef({})", self.path.display())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::Duration;
+
+  use err::ReactErrKind;
+  use react::dispatcher::{Dispatcher, AsyncDispatcher, PoolDispatcher, CallingThreadDispatcher};
+  use react::dispatcher::tests::{Msg, Err, Echo, Recorder, wait_until};
+  use super::ActorRef;
+
+  fn check_tell_ask<D: Dispatcher<Msg, Err>>(dispatcher: &D) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let recorder = dispatcher.subscribe(Box::new(Recorder::new(received.clone())), None);
+    let echo = dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    // a handle reaches its actor only, from any thread
+    let handle = recorder.clone();
+    thread::spawn(move || handle.tell(Msg::Ping(1)).ok().unwrap()).join().unwrap();
+    wait_until(|| received.lock().unwrap().len() == 1);
+    assert_eq!(Msg::Pong(2), echo.ask(Msg::Ping(2), Duration::from_secs(5)).wait().ok().unwrap());
+
+    dispatcher.unsubscribe(recorder.path()).ok().unwrap();
+    assert_eq!(&ReactErrKind::UnknownActor(recorder.path().display()),
+      recorder.tell(Msg::Ping(3)).err().unwrap().kind());
+    assert!(recorder.ask(Msg::Ping(4), Duration::from_secs(5)).wait().is_err());
+    assert_eq!(vec![1], *received.lock().unwrap());
+  }
+
+  #[test]
+  fn test_tell_ask() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    check_tell_ask(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    check_tell_ask(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+
+    check_tell_ask(&CallingThreadDispatcher::new());
+  }
+
+  #[test]
+  fn test_equality() {
+    let first: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let second: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let a = first.subscribe(Box::new(Echo::new()), None);
+    let b = first.subscribe(Box::new(Echo::new()), None);
+    // the same address in another dispatcher
+    let c = second.subscribe(Box::new(Echo::new()), None);
+    assert_eq!(a.path(), c.path());
+
+    assert_eq!(a, a.clone());
+    assert!(a != b && a != c);
+    let refs: HashSet<ActorRef<Msg>> = vec![a.clone(), b, c, a].into_iter().collect();
+    assert_eq!(3, refs.len());
+  }
+
+  #[test]
+  fn test_dispatcher_gone() {
+    let dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(1);
+    let echo = dispatcher.subscribe(Box::new(Echo::new()), None);
+    drop(dispatcher);
+    assert!(echo.tell(Msg::Ping(1)).is_err());
+  }
+}
@@ -0,0 +1,224 @@
+//!
+//! Receive functions swapped at runtime.
+//!
+//! An actor whose protocol depends on its state keeps a `Behavior`, and
+//! hands every message to its current receive function from `on_receive`.
+//! A receive function changes the behavior for the next messages, either
+//! for good with `replace`, or on top of the current one with `push`, so
+//! that `pop` returns to it, like become and unbecome elsewhere. Messages
+//! the current behavior cannot handle yet are put aside with
+//! `ActorContext::stash`, and handed back with `unstash_all` after the
+//! switch.
+//!
+
+use react::{MsgTrait, Error};
+
+/// Handles a message for an actor of type `A`.
+pub type Receive<A, M, E> = fn(&mut A, &M) -> Result<(), E>;
+
+pub struct Behavior<A, M: MsgTrait, E: Error> {
+  /// Never empty, the initial behavior is at the bottom.
+  stack: Vec<Receive<A, M, E>>
+}
+
+impl<A, M: MsgTrait, E: Error> Behavior<A, M, E> {
+  pub fn new(initial: Receive<A, M, E>) -> Behavior<A, M, E> {
+    Behavior {
+      stack: vec![initial]
+    }
+  }
+
+  /// The receive function for the next message. It is copied out, so that
+  /// it can be called with the actor that holds the behavior.
+  pub fn current(&self) -> Receive<A, M, E> {
+    *self.stack.last().unwrap()
+  }
+
+  /// Replaces the current receive function.
+  pub fn replace(&mut self, receive: Receive<A, M, E>) {
+    *self.stack.last_mut().unwrap() = receive;
+  }
+
+  /// Switches to `receive`, keeping the current function for `pop`.
+  pub fn push(&mut self, receive: Receive<A, M, E>) {
+    self.stack.push(receive);
+  }
+
+  /// Returns to the function before the last `push`, and returns whether
+  /// there was one. The initial function stays.
+  pub fn pop(&mut self) -> bool {
+    if self.stack.len() > 1 {
+      self.stack.pop();
+      true
+    } else {
+      false
+    }
+  }
+
+  /// Returns to the initial function.
+  pub fn reset(&mut self) {
+    self.stack.truncate(1);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use react::actor::{Actor, ActorContext};
+  use react::dispatcher::{Dispatcher, PoolDispatcher, CallingThreadDispatcher, DeadLetterReason};
+  use react::dispatcher::tests::{Msg, Err, wait_until};
+  use react::supervision::Supervisor;
+  use super::Behavior;
+
+  /// Stashes pings while closed. `Ignore` opens it and `Defer` closes it
+  /// again. Once open, a ping of zero fails it.
+  struct Gate {
+    context: ActorContext<Msg>,
+    behavior: Behavior<Gate, Msg, Err>,
+    pings: Arc<Mutex<Vec<u32>>>
+  }
+
+  impl Gate {
+    fn new(pings: Arc<Mutex<Vec<u32>>>) -> Gate {
+      Gate {
+        context: ActorContext::new(),
+        behavior: Behavior::new(Gate::closed),
+        pings: pings
+      }
+    }
+
+    fn closed(&mut self, m: &Msg) -> Result<(), Err> {
+      match *m {
+        Msg::Ping(_) => self.context.stash(),
+        Msg::Ignore => {
+          self.behavior.push(Gate::open);
+          self.context.unstash_all();
+        }
+        Msg::Fail => return Err(Err::Fatal),
+        _ => {}
+      }
+      Ok(())
+    }
+
+    fn open(&mut self, m: &Msg) -> Result<(), Err> {
+      match *m {
+        Msg::Ping(0) => return Err(Err::Fatal),
+        Msg::Ping(n) => {
+          self.pings.lock().unwrap().push(n);
+          self.context.reply(Msg::Pong(n)).ok();
+        }
+        Msg::Defer => {
+          self.behavior.pop();
+        }
+        _ => {}
+      }
+      Ok(())
+    }
+  }
+
+  impl Actor<Msg, Err> for Gate {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      let receive = self.behavior.current();
+      receive(self, m)
+    }
+  }
+
+  fn check_gate<D: Dispatcher<Msg, Err>>(dispatcher: &D) {
+    let pings = Arc::new(Mutex::new(Vec::new()));
+    let gate = dispatcher.subscribe(Box::new(Gate::new(pings.clone())), None);
+
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    gate.tell(Msg::Ping(2)).ok().unwrap();
+    // a stashed ask is answered once it is handled
+    let reply = gate.ask(Msg::Ping(3), Duration::from_secs(5));
+    gate.tell(Msg::Ignore).ok().unwrap();
+    gate.tell(Msg::Ping(4)).ok().unwrap();
+    assert_eq!(Msg::Pong(3), reply.wait().ok().unwrap());
+    wait_until(|| pings.lock().unwrap().len() == 4);
+
+    // closed again
+    gate.tell(Msg::Defer).ok().unwrap();
+    gate.tell(Msg::Ping(5)).ok().unwrap();
+    gate.tell(Msg::Ping(6)).ok().unwrap();
+    gate.tell(Msg::Ignore).ok().unwrap();
+    wait_until(|| pings.lock().unwrap().len() == 6);
+    assert_eq!(vec![1, 2, 3, 4, 5, 6], *pings.lock().unwrap());
+  }
+
+  #[test]
+  fn test_stash() {
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    check_gate(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+
+    check_gate(&CallingThreadDispatcher::new());
+  }
+
+  #[test]
+  fn test_stash_across_restart() {
+    let pings = Arc::new(Mutex::new(Vec::new()));
+    let factory_pings = pings.clone();
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let gate = dispatcher.subscribe_supervised(Box::new(Gate::new(pings.clone())), None,
+      Supervisor::restart(Box::new(move || Box::new(Gate::new(factory_pings.clone())))));
+
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    gate.tell(Msg::Fail).ok().unwrap();
+    // the new instance starts closed, and stashes the ping again
+    assert!(pings.lock().unwrap().is_empty());
+    gate.tell(Msg::Ignore).ok().unwrap();
+    assert_eq!(vec![1], *pings.lock().unwrap());
+  }
+
+  #[test]
+  fn test_stash_dead_letters() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let letters = dispatcher.dead_letters().subscribe();
+    let pings = Arc::new(Mutex::new(Vec::new()));
+
+    // stopped while unstashing
+    let gate = dispatcher.subscribe_supervised(Box::new(Gate::new(pings.clone())), None, Supervisor::stop());
+    for n in &[1, 0, 2] {
+      gate.tell(Msg::Ping(*n)).ok().unwrap();
+    }
+    gate.tell(Msg::Ignore).ok().unwrap();
+    assert_eq!(vec![1], *pings.lock().unwrap());
+
+    // stopped with stashed messages
+    let gate = dispatcher.subscribe(Box::new(Gate::new(pings.clone())), None);
+    gate.tell(Msg::Ping(3)).ok().unwrap();
+    dispatcher.unsubscribe(gate.path()).ok().unwrap();
+
+    let letters: Vec<_> = letters.try_iter().map(|l| (l.message().clone(), l.reason())).collect();
+    assert_eq!(vec![(Msg::Ping(2), DeadLetterReason::ActorStopped), (Msg::Ping(3), DeadLetterReason::ActorStopped)],
+               letters);
+  }
+
+  #[test]
+  fn test_pop() {
+    fn first(_: &mut u32, _: &Msg) -> Result<(), Err> { Ok(()) }
+    fn second(n: &mut u32, _: &Msg) -> Result<(), Err> { *n += 1; Ok(()) }
+
+    let mut behavior: Behavior<u32, Msg, Err> = Behavior::new(first);
+    assert!(!behavior.pop());
+    behavior.push(second);
+    behavior.push(second);
+    let mut n = 0;
+    behavior.current()(&mut n, &Msg::Ignore).ok().unwrap();
+    assert_eq!(1, n);
+
+    assert!(behavior.pop());
+    behavior.replace(first);
+    behavior.current()(&mut n, &Msg::Ignore).ok().unwrap();
+    assert_eq!(1, n);
+    behavior.reset();
+    assert!(!behavior.pop());
+  }
+}
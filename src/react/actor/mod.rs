@@ -1,5 +1,25 @@
+pub mod actor_ref;
+pub mod behavior;
+
+use std::any::Any;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use err::{ReactErr, ReactErrKind};
 use super::{MsgTrait, Error};
+use super::dispatcher::Spawner;
+use super::reply::ReplyTo;
+use super::supervision::{Directive, Factory};
+
+pub use self::actor_ref::{ActorRef, Courier};
+pub use self::behavior::{Behavior, Receive};
+
+/// A message put aside by `ActorContext::stash`, with the reply channel of
+/// its ask.
+pub type Stashed<M> = (Arc<M>, Option<ReplyTo<M>>);
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ActorUri {
   host_name: String,
   port: i32,
@@ -7,17 +27,499 @@ pub struct ActorUri {
 }
 
 impl ActorUri {
+  pub fn new(host_name: &str, port: i32, path: &str) -> ActorUri {
+    ActorUri {
+      host_name: host_name.to_owned(),
+      port: port,
+      path: path.to_owned()
+    }
+  }
+
+  /// An address in the local process.
+  pub fn local(path: &str) -> ActorUri {
+    ActorUri::new("localhost", 0, path)
+  }
+
+  pub fn host_name(&self) -> &str {
+    &self.host_name
+  }
+
+  pub fn port(&self) -> i32 {
+    self.port
+  }
+
+  pub fn path(&self) -> &str {
+    &self.path
+  }
+
   pub fn display(&self) -> String {
     format!("react://{}:{}/{}", self.host_name, self.port, self.path)
   }
 }
 
-pub struct ActorContext {
-  uri: ActorUri  
+pub struct ActorContext<M: MsgTrait> {
+  self_ref: Mutex<Option<ActorRef<M>>>,
+  parent: Mutex<Option<ActorRef<M>>>,
+  /// The `Arc<Spawner<M, E>>` of the dispatcher, whose error type the
+  /// context does not know.
+  spawner: Mutex<Option<Arc<Any + Send + Sync>>>,
+  /// The message being handled, until it is stashed.
+  current: Mutex<Option<Arc<M>>>,
+  reply_to: Mutex<Option<ReplyTo<M>>>,
+  stash: Mutex<Vec<Stashed<M>>>,
+  /// Set by `unstash_all` for the dispatcher.
+  unstash: AtomicBool
+}
+
+impl<M: MsgTrait> ActorContext<M> {
+  pub fn new() -> ActorContext<M> {
+    ActorContext {
+      self_ref: Mutex::new(None),
+      parent: Mutex::new(None),
+      spawner: Mutex::new(None),
+      current: Mutex::new(None),
+      reply_to: Mutex::new(None),
+      stash: Mutex::new(Vec::new()),
+      unstash: AtomicBool::new(false)
+    }
+  }
+
+  /// The address assigned by the dispatcher, or `None` before the actor is
+  /// subscribed.
+  pub fn uri(&self) -> Option<ActorUri> {
+    self.self_ref.lock().unwrap().as_ref().map(|r| r.path().clone())
+  }
+
+  /// A handle to the actor itself, to hand to other actors, or `None`
+  /// before the actor is subscribed.
+  pub fn self_ref(&self) -> Option<ActorRef<M>> {
+    self.self_ref.lock().unwrap().clone()
+  }
+
+  /// The actor that spawned this one, or `None` for an actor subscribed to
+  /// the dispatcher directly.
+  pub fn parent(&self) -> Option<ActorRef<M>> {
+    self.parent.lock().unwrap().clone()
+  }
+
+  /// Called by a dispatcher when it subscribes the actor.
+  pub fn attach(&self, self_ref: ActorRef<M>, parent: Option<ActorRef<M>>, spawner: Arc<Any + Send + Sync>) {
+    *self.self_ref.lock().unwrap() = Some(self_ref);
+    *self.parent.lock().unwrap() = parent;
+    *self.spawner.lock().unwrap() = Some(spawner);
+  }
+
+  /// Spawns a child actor at `name` under the path of this one. The child
+  /// only gets the messages sent to it directly, its failures are decided
+  /// by `Actor::supervise_child` of this actor, and it stops with it. `E`
+  /// must be the error type of the dispatcher.
+  pub fn spawn<E: Error>(&self, name: &str, actor: Box<Actor<M, E>>) -> Result<ActorRef<M>, ReactErr> {
+    self.spawn_child(name, actor, None)
+  }
+
+  /// Like `spawn`, with a child built by `factory`, which can then be
+  /// restarted.
+  pub fn spawn_with_factory<E: Error>(&self, name: &str, factory: Box<Factory<M, E>>)
+      -> Result<ActorRef<M>, ReactErr> {
+    self.spawn_child(name, factory(), Some(factory))
+  }
+
+  fn spawn_child<E: Error>(&self, name: &str, actor: Box<Actor<M, E>>, factory: Option<Box<Factory<M, E>>>)
+      -> Result<ActorRef<M>, ReactErr> {
+    let parent = match self.uri() {
+      Some(uri) => uri,
+      None => return Err(ReactErr::new(ReactErrKind::Disconnected))
+    };
+    let spawner = self.spawner.lock().unwrap().clone().unwrap();
+    match spawner.downcast_ref::<Arc<Spawner<M, E>>>() {
+      Some(spawner) => spawner.spawn(&parent, name, actor, factory),
+      None => Err(ReactErr::new(ReactErrKind::WrongErrorType))
+    }
+  }
+
+  /// Answers the ask currently being handled by `on_receive`.
+  pub fn reply(&self, m: M) -> Result<(), ReactErr> {
+    match *self.reply_to.lock().unwrap() {
+      Some(ref reply_to) => reply_to.send(m),
+      None => Err(ReactErr::new(ReactErrKind::NotAsked))
+    }
+  }
+
+  /// Returns the reply channel of the ask currently being handled, so that
+  /// an actor can keep it and answer later.
+  pub fn reply_to(&self) -> Option<ReplyTo<M>> {
+    self.reply_to.lock().unwrap().clone()
+  }
+
+  /// Puts the message being handled aside, with its reply channel, until
+  /// `unstash_all`. Does nothing outside of `on_receive`, or when the
+  /// message is already stashed.
+  pub fn stash(&self) {
+    if let Some(m) = self.current.lock().unwrap().take() {
+      let reply_to = self.reply_to.lock().unwrap().clone();
+      self.stash.lock().unwrap().push((m, reply_to));
+    }
+  }
+
+  /// Hands the stashed messages back to the actor, in the order they were
+  /// stashed, once `on_receive` returns and before the next queued message.
+  pub fn unstash_all(&self) {
+    self.unstash.store(true, Ordering::SeqCst);
+  }
+
+  /// The number of stashed messages.
+  pub fn stashed(&self) -> usize {
+    self.stash.lock().unwrap().len()
+  }
+
+  /// Called by a dispatcher around `on_receive`.
+  pub fn set_current(&self, m: Option<Arc<M>>, reply_to: Option<ReplyTo<M>>) {
+    *self.current.lock().unwrap() = m;
+    *self.reply_to.lock().unwrap() = reply_to;
+  }
+
+  /// Called by a dispatcher after `on_receive`, for the messages to handle
+  /// again if the actor unstashed them.
+  pub fn take_unstashed(&self) -> Vec<Stashed<M>> {
+    if self.unstash.swap(false, Ordering::SeqCst) {
+      self.take_stash()
+    } else {
+      Vec::new()
+    }
+  }
+
+  /// Called by a dispatcher to take the stash of a failed actor.
+  pub fn take_stash(&self) -> Vec<Stashed<M>> {
+    mem::replace(&mut *self.stash.lock().unwrap(), Vec::new())
+  }
+
+  /// Called by a dispatcher to hand the stash of a failed actor to its new
+  /// instance, which handles it right away.
+  pub fn inherit_stash(&self, stashed: Vec<Stashed<M>>) {
+    if !stashed.is_empty() {
+      self.stash.lock().unwrap().extend(stashed);
+      self.unstash_all();
+    }
+  }
 }
 
 pub trait Actor<M: MsgTrait, E: Error>: Send + Sync {
-  fn context(&self) -> &ActorContext;
+  fn context(&self) -> &ActorContext<M>;
   fn accept(&self, m: &M) -> bool { true }
   fn on_receive(&mut self, m: &M) -> Result<(), E>;
-}
\ No newline at end of file
+
+  /// Called once when the actor is subscribed, before any message.
+  fn pre_start(&mut self) {}
+
+  /// Called once after the actor has handled its last message, when it is
+  /// unsubscribed, stopped by its supervisor or its dispatcher shuts down.
+  fn post_stop(&mut self) {}
+
+  /// Called on a failed actor right before a restart replaces it. The new
+  /// instance gets `pre_start`. By default this releases the failed
+  /// instance through `post_stop`.
+  fn pre_restart(&mut self, err: &E) {
+    self.post_stop();
+  }
+
+  /// Decides what happens to a child spawned through the context that
+  /// failed with `err`, within the restart limits of the child. `Restart`
+  /// needs a child spawned with a factory, and `Escalate` fails this actor
+  /// with the error in turn. By default the child resumes.
+  ///
+  /// This runs on the thread of the child, which waits for this actor to
+  /// be free. An actor that blocks in `on_receive` on an ask to one of its
+  /// children, with `wait`, deadlocks if that child fails meanwhile.
+  fn supervise_child(&mut self, _child: &ActorUri, _err: &E) -> Directive {
+    Directive::Resume
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use err::ReactErrKind;
+  use react::Error;
+  use react::dispatcher::{Dispatcher, AsyncDispatcher, PoolDispatcher, CallingThreadDispatcher};
+  use react::dispatcher::tests::{Msg, Err, wait_until};
+  use react::supervision::{Directive, Supervisor};
+  use super::{Actor, ActorContext, ActorUri};
+
+  /// Records its lifecycle events.
+  pub struct Lifecycle {
+    context: ActorContext<Msg>,
+    events: Arc<Mutex<Vec<&'static str>>>
+  }
+
+  impl Lifecycle {
+    pub fn new(events: Arc<Mutex<Vec<&'static str>>>) -> Lifecycle {
+      Lifecycle {
+        context: ActorContext::new(),
+        events: events
+      }
+    }
+  }
+
+  impl Actor<Msg, Err> for Lifecycle {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      match *m {
+        Msg::Fail => Err(Err::Fatal),
+        _ => {
+          self.events.lock().unwrap().push("receive");
+          self.context.reply(Msg::Pong(0)).ok();
+          Ok(())
+        }
+      }
+    }
+
+    fn pre_start(&mut self) {
+      self.events.lock().unwrap().push("pre_start");
+    }
+
+    fn post_stop(&mut self) {
+      self.events.lock().unwrap().push("post_stop");
+    }
+
+    fn pre_restart(&mut self, _: &Err) {
+      self.events.lock().unwrap().push("pre_restart");
+    }
+  }
+
+  /// Spawns a `Lifecycle` child in `pre_start`, and supervises it with a
+  /// fixed directive.
+  struct Parent {
+    context: ActorContext<Msg>,
+    events: Arc<Mutex<Vec<&'static str>>>,
+    spawned: Arc<Mutex<Vec<Result<ActorUri, ReactErrKind>>>>,
+    directive: Directive
+  }
+
+  impl Parent {
+    fn new(events: Arc<Mutex<Vec<&'static str>>>, spawned: Arc<Mutex<Vec<Result<ActorUri, ReactErrKind>>>>,
+           directive: Directive) -> Parent {
+      Parent {
+        context: ActorContext::new(),
+        events: events,
+        spawned: spawned,
+        directive: directive
+      }
+    }
+  }
+
+  impl Actor<Msg, Err> for Parent {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      match *m {
+        Msg::Fail => Err(Err::Fatal),
+        _ => {
+          self.events.lock().unwrap().push("parent_receive");
+          Ok(())
+        }
+      }
+    }
+
+    fn pre_start(&mut self) {
+      let events = self.events.clone();
+      let results = vec![
+        self.context.spawn_with_factory("child", Box::new(move || {
+          Box::new(Lifecycle::new(events.clone())) as Box<Actor<Msg, Err>>
+        })),
+        self.context.spawn::<Err>("child", Box::new(Lifecycle::new(self.events.clone()))),
+        self.context.spawn::<Err>("a/b", Box::new(Lifecycle::new(self.events.clone()))),
+        self.context.spawn::<OtherErr>("other", Box::new(Other { context: ActorContext::new() }))
+      ];
+      let mut spawned = self.spawned.lock().unwrap();
+      spawned.extend(results.into_iter().map(|r| r.map(|c| c.path().clone()).map_err(|e| e.kind().clone())));
+    }
+
+    fn post_stop(&mut self) {
+      self.events.lock().unwrap().push("parent_stop");
+    }
+
+    fn supervise_child(&mut self, _: &ActorUri, _: &Err) -> Directive {
+      self.events.lock().unwrap().push("supervise");
+      self.directive
+    }
+  }
+
+  /// An error type that the dispatchers of the tests do not have.
+  struct OtherErr;
+
+  impl Error for OtherErr {
+    fn from_panic(_: String) -> OtherErr {
+      OtherErr
+    }
+  }
+
+  struct Other {
+    context: ActorContext<Msg>
+  }
+
+  impl Actor<Msg, OtherErr> for Other {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, _: &Msg) -> Result<(), OtherErr> {
+      Ok(())
+    }
+  }
+
+  fn count(events: &Arc<Mutex<Vec<&'static str>>>, event: &str) -> usize {
+    events.lock().unwrap().iter().filter(|e| **e == event).count()
+  }
+
+  fn check_children<D: Dispatcher<Msg, Err>>(dispatcher: &D) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let spawned = Arc::new(Mutex::new(Vec::new()));
+    let parent = dispatcher.subscribe(Box::new(Parent::new(events.clone(), spawned.clone(), Directive::Resume)),
+                                      None);
+    let child = parent.child("child");
+    assert_eq!(format!("{}/child", parent.path().path()), child.path().path());
+    assert_eq!(vec![Ok(child.path().clone()), Err(ReactErrKind::NameTaken(child.path().display())),
+                    Err(ReactErrKind::InvalidName("a/b".to_owned())), Err(ReactErrKind::WrongErrorType)],
+               *spawned.lock().unwrap());
+
+    // the child only gets what is sent to it directly
+    dispatcher.send(Msg::Ping(1)).ok().unwrap();
+    assert_eq!(Msg::Pong(0), child.ask(Msg::Ping(2), Duration::from_secs(5)).wait().ok().unwrap());
+    wait_until(|| count(&events, "parent_receive") == 1);
+    assert_eq!(1, count(&events, "receive"));
+
+    // the child stops first
+    parent.stop().ok().unwrap();
+    wait_until(|| count(&events, "parent_stop") == 1);
+    assert_eq!(Some(&"post_stop"), events.lock().unwrap().iter().rev().nth(1));
+    assert!(child.tell(Msg::Ping(3)).is_err());
+  }
+
+  #[test]
+  fn test_children() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    check_children(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    check_children(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+
+    check_children(&CallingThreadDispatcher::new());
+  }
+
+  #[test]
+  fn test_supervise_child() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let spawned = Arc::new(Mutex::new(Vec::new()));
+
+    let parent = dispatcher.subscribe(Box::new(Parent::new(events.clone(), spawned.clone(), Directive::Restart)),
+                                      None);
+    parent.child("child").tell(Msg::Fail).ok().unwrap();
+    assert_eq!(vec!["pre_start", "supervise", "pre_restart", "pre_start"], *events.lock().unwrap());
+    parent.child("child").tell(Msg::Ping(1)).ok().unwrap();
+    assert_eq!(1, count(&events, "receive"));
+
+    events.lock().unwrap().clear();
+    let parent = dispatcher.subscribe(Box::new(Parent::new(events.clone(), spawned.clone(), Directive::Stop)),
+                                      None);
+    parent.child("child").tell(Msg::Fail).ok().unwrap();
+    assert_eq!(vec!["pre_start", "supervise", "post_stop"], *events.lock().unwrap());
+    assert!(parent.child("child").tell(Msg::Ping(1)).is_err());
+    parent.tell(Msg::Ping(1)).ok().unwrap();
+  }
+
+  #[test]
+  fn test_escalate_to_parent() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let spawned = Arc::new(Mutex::new(Vec::new()));
+
+    // the supervisor of the parent stops it with the child, which is still
+    // busy failing and so finishes last
+    let parent = dispatcher.subscribe_supervised(
+      Box::new(Parent::new(events.clone(), spawned.clone(), Directive::Escalate)), None, Supervisor::stop());
+    parent.child("child").tell(Msg::Fail).ok().unwrap();
+    assert_eq!(vec!["pre_start", "supervise", "parent_stop", "post_stop"], *events.lock().unwrap());
+    assert!(parent.tell(Msg::Ping(1)).is_err());
+
+    // and up to the dispatcher
+    let parent = dispatcher.subscribe_supervised(
+      Box::new(Parent::new(events.clone(), spawned.clone(), Directive::Escalate)), None, Supervisor::escalate());
+    parent.child("child").tell(Msg::Fail).ok().unwrap();
+    assert!(dispatcher.join().is_err());
+  }
+
+  fn check_unsubscribe<D: Dispatcher<Msg, Err>>(dispatcher: &D) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let uri = dispatcher.subscribe(Box::new(Lifecycle::new(events.clone())), None).path().clone();
+
+    dispatcher.ask(Msg::Ping(1), Duration::from_secs(5)).wait().ok().unwrap();
+    dispatcher.unsubscribe(&uri).ok().unwrap();
+    // the actor may still be finishing the ask when it is unsubscribed
+    wait_until(|| events.lock().unwrap().len() == 3);
+    assert_eq!(vec!["pre_start", "receive", "post_stop"], *events.lock().unwrap());
+
+    let err = dispatcher.ask(Msg::Ping(2), Duration::from_secs(5)).wait().err().unwrap();
+    assert_eq!(&ReactErrKind::NoReply, err.kind());
+    assert_eq!(&ReactErrKind::UnknownActor(uri.display()),
+      dispatcher.unsubscribe(&uri).err().unwrap().kind());
+  }
+
+  #[test]
+  fn test_unsubscribe() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    check_unsubscribe(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    check_unsubscribe(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_restart_hooks() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let factory_events = events.clone();
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    dispatcher.subscribe_supervised(Box::new(Lifecycle::new(events.clone())), None,
+      Supervisor::restart(Box::new(move || Box::new(Lifecycle::new(factory_events.clone())))));
+
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    dispatcher.ask(Msg::Ping(1), Duration::from_secs(5)).wait().ok().unwrap();
+    assert_eq!(vec!["pre_start", "pre_restart", "pre_start", "receive"], *events.lock().unwrap());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+    assert_eq!(Some(&"post_stop"), events.lock().unwrap().last());
+  }
+
+  #[test]
+  fn test_stop_by_supervisor() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    let actor = dispatcher.subscribe_supervised(Box::new(Lifecycle::new(events.clone())), None,
+      Supervisor::stop());
+
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    wait_until(|| events.lock().unwrap().len() == 2);
+    assert_eq!(vec!["pre_start", "post_stop"], *events.lock().unwrap());
+    assert!(actor.tell(Msg::Ping(1)).is_err());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+    assert_eq!(2, events.lock().unwrap().len());
+  }
+}
@@ -0,0 +1,640 @@
+//!
+//! Leader election with Raft-style terms.
+//!
+//! A node that hears nothing from a leader within its election timeout
+//! starts a new term and asks the others for their votes. Every node votes
+//! at most once per term, so a term has at most one leader: the node that
+//! got the votes of a majority. The leader sends heartbeats to keep its
+//! followers, and steps down when a majority stops answering them, so that
+//! a leader cut off from the others does not go on believing it leads.
+//!
+//! The set of nodes is fixed when the election starts.
+//!
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use err::ReactErr;
+use react::{MsgTrait, Error};
+use react::actor::{Actor, ActorContext};
+use react::dispatcher::{Dispatcher, AsyncDispatcher};
+use react::remote::Transport;
+use react::route::Random;
+use super::{ClusterErr, split_address};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ElectionConfig {
+  heartbeat_interval: Duration,
+  election_timeout: Duration
+}
+
+impl ElectionConfig {
+  pub fn new() -> ElectionConfig {
+    ElectionConfig {
+      heartbeat_interval: Duration::from_millis(100),
+      election_timeout: Duration::from_millis(500)
+    }
+  }
+
+  /// A follower waits between `election_timeout` and twice as long for a
+  /// heartbeat before it runs for leader.
+  pub fn with_timeouts(mut self, heartbeat_interval: Duration, election_timeout: Duration)
+      -> ElectionConfig {
+    assert!(heartbeat_interval < election_timeout, "heartbeats must come before elections");
+    self.heartbeat_interval = heartbeat_interval;
+    self.election_timeout = election_timeout;
+    self
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+  Follower,
+  Candidate,
+  Leader
+}
+
+/// Sent to subscribers whenever the leader known to a node changes. The
+/// leader is `None` while an election is going on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderChanged {
+  pub term: u64,
+  pub leader: Option<String>
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct Ballot {
+  term: u64,
+  /// The address of the sender.
+  from: String,
+  granted: bool
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub enum ElectionMsg {
+  RequestVote(Ballot),
+  Vote(Ballot),
+  Heartbeat(Ballot),
+  HeartbeatAck(Ballot),
+  /// Checks the timeouts.
+  Tick
+}
+
+impl MsgTrait for ElectionMsg {}
+
+type Subscriber = Box<Fn(&LeaderChanged) -> bool + Send>;
+
+struct Node {
+  address: String,
+  config: ElectionConfig,
+  /// The other nodes, empty until the election starts.
+  peers: Vec<String>,
+  started: bool,
+  role: Role,
+  term: u64,
+  voted_for: Option<String>,
+  leader: Option<String>,
+  votes: HashSet<String>,
+  /// When a follower or candidate runs for leader.
+  deadline: Instant,
+  next_heartbeat: Instant,
+  /// When each follower last answered a heartbeat of this leader.
+  acks: HashMap<String, Instant>,
+  rng: Random,
+  /// The leader changes not yet sent to the subscribers.
+  changes: Vec<LeaderChanged>
+}
+
+type Outbox = Vec<(String, ElectionMsg)>;
+
+impl Node {
+  fn new(address: &str, config: ElectionConfig) -> Node {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let seed = address.bytes().fold(now.subsec_nanos() as u64, |h, b| h.wrapping_mul(31) ^ b as u64);
+    Node {
+      address: address.to_owned(),
+      config: config,
+      peers: Vec::new(),
+      started: false,
+      role: Role::Follower,
+      term: 0,
+      voted_for: None,
+      leader: None,
+      votes: HashSet::new(),
+      deadline: Instant::now(),
+      next_heartbeat: Instant::now(),
+      acks: HashMap::new(),
+      rng: Random::with_seed(seed),
+      changes: Vec::new()
+    }
+  }
+
+  fn majority(&self) -> usize {
+    (self.peers.len() + 1) / 2 + 1
+  }
+
+  fn ballot(&self, granted: bool) -> Ballot {
+    Ballot {
+      term: self.term,
+      from: self.address.clone(),
+      granted: granted
+    }
+  }
+
+  fn reset_deadline(&mut self) {
+    let timeout = self.config.election_timeout;
+    let millis = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000;
+    let jitter = self.rng.next() % (millis + 1);
+    self.deadline = Instant::now() + timeout + Duration::from_millis(jitter);
+  }
+
+  fn set_leader(&mut self, leader: Option<String>) {
+    if self.leader == leader {
+      return;
+    }
+
+    info!("{}: leader of term {} is {:?}", self.address, self.term, leader);
+    self.leader = leader;
+    self.changes.push(LeaderChanged {
+      term: self.term,
+      leader: self.leader.clone()
+    });
+  }
+
+  /// Follows the term `term`, which is newer than the current one.
+  fn step_down(&mut self, term: u64) {
+    self.term = term;
+    self.role = Role::Follower;
+    self.voted_for = None;
+    self.set_leader(None);
+    self.reset_deadline();
+  }
+
+  fn start(&mut self, nodes: Vec<String>) {
+    let address = self.address.clone();
+    self.peers = nodes.into_iter().filter(|n| *n != address).collect();
+    self.peers.sort();
+    self.peers.dedup();
+    self.started = true;
+    self.reset_deadline();
+  }
+
+  fn tick(&mut self) -> Outbox {
+    let mut out = Vec::new();
+    if !self.started {
+      return out;
+    }
+
+    let now = Instant::now();
+    match self.role {
+      Role::Leader => {
+        let timeout = self.config.election_timeout;
+        let alive = self.acks.values().filter(|t| now.duration_since(**t) < timeout).count();
+        if alive + 1 < self.majority() {
+          warn!("{}: lost the majority, stepping down", self.address);
+          self.role = Role::Follower;
+          self.set_leader(None);
+          self.reset_deadline();
+        } else if now >= self.next_heartbeat {
+          self.next_heartbeat = now + self.config.heartbeat_interval;
+          for peer in self.peers.iter() {
+            out.push((peer.clone(), ElectionMsg::Heartbeat(self.ballot(true))));
+          }
+        }
+      }
+      _ => {
+        if now >= self.deadline {
+          out = self.run();
+        }
+      }
+    }
+    out
+  }
+
+  fn run(&mut self) -> Outbox {
+    self.term += 1;
+    self.role = Role::Candidate;
+    self.voted_for = Some(self.address.clone());
+    self.votes.clear();
+    let address = self.address.clone();
+    self.votes.insert(address);
+    self.set_leader(None);
+    self.reset_deadline();
+    debug!("{}: running for term {}", self.address, self.term);
+
+    if self.votes.len() >= self.majority() {
+      return self.lead();
+    }
+    self.peers.iter().map(|peer| (peer.clone(), ElectionMsg::RequestVote(self.ballot(true)))).collect()
+  }
+
+  fn lead(&mut self) -> Outbox {
+    self.role = Role::Leader;
+    let now = Instant::now();
+    // whoever voted for this node was alive just now
+    self.acks = self.votes.iter()
+      .filter(|v| **v != self.address)
+      .map(|v| (v.clone(), now))
+      .collect();
+    self.next_heartbeat = now + self.config.heartbeat_interval;
+    let address = self.address.clone();
+    self.set_leader(Some(address));
+
+    self.peers.iter().map(|peer| (peer.clone(), ElectionMsg::Heartbeat(self.ballot(true)))).collect()
+  }
+
+  fn receive(&mut self, m: &ElectionMsg) -> Outbox {
+    let ballot = match *m {
+      ElectionMsg::Tick => return self.tick(),
+      ElectionMsg::RequestVote(ref b) | ElectionMsg::Vote(ref b) |
+      ElectionMsg::Heartbeat(ref b) | ElectionMsg::HeartbeatAck(ref b) => b
+    };
+    if !self.started {
+      return Vec::new();
+    }
+    if ballot.term > self.term {
+      self.step_down(ballot.term);
+    }
+
+    match *m {
+      ElectionMsg::RequestVote(_) => {
+        let granted = ballot.term == self.term &&
+          self.voted_for.as_ref().map_or(true, |v| *v == ballot.from);
+        if granted {
+          self.voted_for = Some(ballot.from.clone());
+          self.reset_deadline();
+        }
+        vec![(ballot.from.clone(), ElectionMsg::Vote(self.ballot(granted)))]
+      }
+      ElectionMsg::Vote(_) => {
+        if self.role == Role::Candidate && ballot.term == self.term && ballot.granted {
+          self.votes.insert(ballot.from.clone());
+          if self.votes.len() >= self.majority() {
+            return self.lead();
+          }
+        }
+        Vec::new()
+      }
+      ElectionMsg::Heartbeat(_) => {
+        // a stale leader learns about the newer term from the answer
+        if ballot.term == self.term {
+          self.role = Role::Follower;
+          self.set_leader(Some(ballot.from.clone()));
+          self.reset_deadline();
+        }
+        vec![(ballot.from.clone(), ElectionMsg::HeartbeatAck(self.ballot(true)))]
+      }
+      ElectionMsg::HeartbeatAck(_) => {
+        if self.role == Role::Leader && ballot.term == self.term {
+          self.acks.insert(ballot.from.clone(), Instant::now());
+        }
+        Vec::new()
+      }
+      ElectionMsg::Tick => unreachable!()
+    }
+  }
+}
+
+struct ElectionActor {
+  context: ActorContext<ElectionMsg>,
+  node: Arc<Mutex<Node>>,
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+  transport: Arc<Transport<ElectionMsg>>
+}
+
+impl ElectionActor {
+  /// Sends `changes` to the subscribers. The node is not locked meanwhile,
+  /// and neither are the subscribers, so that a subscriber may ask the
+  /// election about the new leader, or subscribe another one.
+  fn publish(&self, changes: Vec<LeaderChanged>) {
+    if changes.is_empty() {
+      return;
+    }
+
+    let mut subscribers = mem::replace(&mut *self.subscribers.lock().unwrap(), Vec::new());
+    for event in &changes {
+      subscribers.retain(|f| f(event));
+    }
+    let mut current = self.subscribers.lock().unwrap();
+    let added = mem::replace(&mut *current, subscribers);
+    current.extend(added);
+  }
+}
+
+impl Actor<ElectionMsg, ClusterErr> for ElectionActor {
+  fn context(&self) -> &ActorContext<ElectionMsg> {
+    &self.context
+  }
+
+  fn on_receive(&mut self, m: &ElectionMsg) -> Result<(), ClusterErr> {
+    let (out, changes) = {
+      let mut node = self.node.lock().unwrap();
+      let out = node.receive(m);
+      (out, mem::replace(&mut node.changes, Vec::new()))
+    };
+    self.publish(changes);
+    for (to, msg) in out {
+      match split_address(&to) {
+        Some((host, port)) => {
+          if let Err(e) = self.transport.publish(host, port, &msg) {
+            warn!("cannot send to {}: {}", to, e);
+          }
+        }
+        None => warn!("bad node address: {}", to)
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The election service of a node.
+pub struct Election {
+  address: String,
+  node: Arc<Mutex<Node>>,
+  subscribers: Arc<Mutex<Vec<Subscriber>>>,
+  stop: Arc<AtomicBool>,
+  ticker: Option<JoinHandle<()>>,
+  _dispatcher: Arc<Box<Dispatcher<ElectionMsg, ClusterErr>>>,
+  _transport: Arc<Transport<ElectionMsg>>
+}
+
+impl Election {
+  /// Listens on `host:port`. The node takes part in elections once it is
+  /// started.
+  pub fn bind(host: &str, port: i32, config: ElectionConfig) -> Result<Election, ReactErr> {
+    let dispatcher: Arc<Box<Dispatcher<ElectionMsg, ClusterErr>>> =
+      Arc::new(Box::new(AsyncDispatcher::new()));
+    let transport = Arc::new(Transport::bind(host, port, Arc::downgrade(&dispatcher))?);
+    let address = format!("{}:{}", host, transport.local_addr().port());
+
+    let node = Arc::new(Mutex::new(Node::new(&address, config)));
+    let subscribers = Arc::new(Mutex::new(Vec::new()));
+    dispatcher.subscribe(Box::new(ElectionActor {
+      context: ActorContext::new(),
+      node: node.clone(),
+      subscribers: subscribers.clone(),
+      transport: transport.clone()
+    }), None);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let ticker_stop = stop.clone();
+    let ticker_dispatcher = dispatcher.clone();
+    let interval = config.heartbeat_interval / 2;
+    let ticker = thread::Builder::new()
+      .name(format!("react-election-{}", address))
+      .spawn(move || {
+        while !ticker_stop.load(Ordering::SeqCst) {
+          if let Err(e) = ticker_dispatcher.send(ElectionMsg::Tick) {
+            warn!("cannot check the election timeouts: {}", e);
+          }
+          thread::park_timeout(interval);
+        }
+      })?;
+
+    Ok(Election {
+      address: address,
+      node: node,
+      subscribers: subscribers,
+      stop: stop,
+      ticker: Some(ticker),
+      _dispatcher: dispatcher,
+      _transport: transport
+    })
+  }
+
+  /// `host:port` of this node.
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  /// Takes part in elections among `nodes`, given as `host:port`. `nodes`
+  /// may include this node.
+  pub fn start(&self, nodes: Vec<String>) {
+    self.node.lock().unwrap().start(nodes);
+  }
+
+  pub fn term(&self) -> u64 {
+    self.node.lock().unwrap().term
+  }
+
+  pub fn role(&self) -> Role {
+    self.node.lock().unwrap().role
+  }
+
+  /// The leader of the current term as far as this node knows.
+  pub fn leader(&self) -> Option<String> {
+    self.node.lock().unwrap().leader.clone()
+  }
+
+  pub fn is_leader(&self) -> bool {
+    self.role() == Role::Leader
+  }
+
+  /// Returns the leader changes from now on.
+  pub fn subscribe(&self) -> Receiver<LeaderChanged> {
+    let (tx, rx) = mpsc::channel();
+    self.subscribers.lock().unwrap().push(Box::new(move |e| tx.send(e.clone()).is_ok()));
+    rx
+  }
+
+  /// Sends the message `f` makes of every leader change to the actors of
+  /// `dispatcher`, as long as the dispatcher is alive.
+  pub fn notify<M, E>(&self, dispatcher: &Arc<Box<Dispatcher<M, E>>>,
+                      f: Box<Fn(&LeaderChanged) -> M + Send>)
+      where M: MsgTrait, E: Error {
+    let dispatcher: Weak<Box<Dispatcher<M, E>>> = Arc::downgrade(dispatcher);
+    self.subscribers.lock().unwrap().push(Box::new(move |e| {
+      match dispatcher.upgrade() {
+        Some(dispatcher) => {
+          if let Err(err) = dispatcher.send(f(e)) {
+            warn!("cannot notify a leader change: {}", err);
+          }
+          true
+        }
+        None => false
+      }
+    }));
+  }
+}
+
+impl Drop for Election {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::SeqCst);
+    if let Some(ticker) = self.ticker.take() {
+      ticker.thread().unpark();
+      if ticker.join().is_err() {
+        error!("the election ticker panicked");
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use std::sync::{Arc, Mutex};
+  use std::sync::mpsc::Receiver;
+  use std::time::Duration;
+
+  use react::{ActorSystem, Actor};
+  use react::actor::ActorContext;
+  use react::dispatcher::CallingThreadDispatcher;
+  use react::dispatcher::tests::{Msg, Err, wait_until};
+  use super::{Election, ElectionConfig, LeaderChanged};
+
+  fn nodes(n: usize) -> Vec<Election> {
+    let config = ElectionConfig::new()
+      .with_timeouts(Duration::from_millis(20), Duration::from_millis(150));
+    let nodes: Vec<Election> = (0..n)
+      .map(|_| Election::bind("127.0.0.1", 0, config).ok().unwrap())
+      .collect();
+    let addresses: Vec<String> = nodes.iter().map(|n| n.address().to_owned()).collect();
+    for node in nodes.iter() {
+      node.start(addresses.clone());
+    }
+    nodes
+  }
+
+  /// Waits until the nodes agree on one leader, and returns its position.
+  fn agreed(nodes: &[Election]) -> usize {
+    wait_until(|| {
+      let leaders: Vec<_> = nodes.iter().map(|n| n.leader()).collect();
+      leaders[0].is_some() && leaders.iter().all(|l| *l == leaders[0]) &&
+        nodes.iter().filter(|n| n.is_leader()).count() == 1
+    });
+    nodes.iter().position(|n| n.is_leader()).unwrap()
+  }
+
+  /// Checks that no term had two leaders.
+  fn check_terms(events: &[Receiver<LeaderChanged>], leaders: &mut HashMap<u64, String>) {
+    for rx in events {
+      while let Ok(e) = rx.try_recv() {
+        if let Some(leader) = e.leader {
+          let prev = leaders.entry(e.term).or_insert(leader.clone());
+          assert_eq!(*prev, leader, "two leaders in term {}", e.term);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_single_node() {
+    let nodes = nodes(1);
+    agreed(&nodes);
+  }
+
+  #[test]
+  fn test_leader_crash() {
+    let mut nodes = nodes(3);
+    let events: Vec<_> = nodes.iter().map(|n| n.subscribe()).collect();
+    let mut leaders = HashMap::new();
+
+    let first = agreed(&nodes);
+    let term = nodes[first].term();
+    nodes.remove(first);
+
+    let second = agreed(&nodes);
+    assert!(nodes[second].term() > term);
+    check_terms(&events, &mut leaders);
+    assert!(leaders.len() >= 2);
+  }
+
+  #[test]
+  fn test_minority() {
+    let mut nodes = nodes(3);
+    let leader = agreed(&nodes);
+
+    // the node left alone cannot win an election, nor keep leading
+    let last = nodes.remove((leader + 1) % 3);
+    drop(nodes);
+    wait_until(|| last.leader().is_none());
+    ::std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(None, last.leader());
+  }
+
+  struct Watcher {
+    context: ActorContext<Msg>,
+    terms: Arc<Mutex<Vec<u32>>>
+  }
+
+  impl Actor<Msg, Err> for Watcher {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      if let Msg::Ping(term) = *m {
+        self.terms.lock().unwrap().push(term);
+      }
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_notify() {
+    let system: ActorSystem<Msg, Err> = ActorSystem::new("test");
+    let terms = Arc::new(Mutex::new(Vec::new()));
+    system.dispatcher().subscribe(Box::new(Watcher {
+      context: ActorContext::new(),
+      terms: terms.clone()
+    }), None);
+
+    let nodes = nodes(1);
+    nodes[0].notify(&system.dispatcher(), Box::new(|e: &LeaderChanged| {
+      match e.leader {
+        Some(_) => Msg::Ping(e.term as u32),
+        None => Msg::Ignore
+      }
+    }));
+    wait_until(|| !terms.lock().unwrap().is_empty());
+  }
+
+  /// Asks the election for the leader it was told about.
+  struct Asker {
+    context: ActorContext<Msg>,
+    election: Arc<Election>,
+    leaders: Arc<Mutex<Vec<Option<String>>>>
+  }
+
+  impl Actor<Msg, Err> for Asker {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      if let Msg::Ping(_) = *m {
+        self.leaders.lock().unwrap().push(self.election.leader());
+      }
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_notify_calling_thread() {
+    let election = Arc::new(nodes(1).remove(0));
+    let leaders = Arc::new(Mutex::new(Vec::new()));
+    let system: ActorSystem<Msg, Err> =
+      ActorSystem::with_dispatcher("test", Box::new(CallingThreadDispatcher::new()));
+    system.dispatcher().subscribe(Box::new(Asker {
+      context: ActorContext::new(),
+      election: election.clone(),
+      leaders: leaders.clone()
+    }), None);
+
+    // the actor runs on the thread of the election, and reads its state
+    election.notify(&system.dispatcher(), Box::new(|e: &LeaderChanged| {
+      match e.leader {
+        Some(_) => Msg::Ping(e.term as u32),
+        None => Msg::Ignore
+      }
+    }));
+    wait_until(|| !leaders.lock().unwrap().is_empty());
+    assert_eq!(vec![Some(election.address().to_owned())], *leaders.lock().unwrap());
+    drop(system);
+  }
+}
@@ -0,0 +1,627 @@
+//!
+//! Cluster membership by gossip.
+//!
+//! Every node runs a membership actor in an actor system of its own, which
+//! listens on the address of the node. A node joins through seed addresses,
+//! and then regularly sends its member list to one other member in turn.
+//! Each member announces itself with a heartbeat that grows with every
+//! gossip round, so a member whose heartbeat stops growing is marked
+//! unreachable, and later removed. Heartbeats start over with every
+//! incarnation of a node, that is every time it starts, and a later
+//! incarnation wins over any heartbeat of an earlier one.
+//!
+//! Nodes only merge gossip of their own group, such as `"/react/members"`.
+//!
+
+pub mod election;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use err::ReactErr;
+use super::{MsgTrait, Error};
+use super::actor::{Actor, ActorContext};
+use super::dispatcher::{Dispatcher, AsyncDispatcher};
+use super::remote::Transport;
+
+/// The group of the example nodes.
+pub const MEMBERS_GROUP: &'static str = "/react/members";
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberStatus {
+  /// Known, but not in touch with the cluster yet.
+  Joining,
+  Up,
+  /// No heartbeat arrived for a while. The member is up again as soon as
+  /// one does.
+  Unreachable,
+  /// Gone for good. A node that comes back joins as a new member.
+  Removed
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+  /// `host:port` of the node.
+  address: String,
+  status: MemberStatus,
+  /// When the node started, in milliseconds since the epoch.
+  incarnation: u64,
+  heartbeat: u64
+}
+
+impl Member {
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  pub fn status(&self) -> MemberStatus {
+    self.status
+  }
+
+  pub fn incarnation(&self) -> u64 {
+    self.incarnation
+  }
+
+  pub fn heartbeat(&self) -> u64 {
+    self.heartbeat
+  }
+}
+
+/// Sent to subscribers whenever a member changes its status as seen by this
+/// node, including members seen for the first time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberEvent {
+  pub address: String,
+  pub status: MemberStatus
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClusterConfig {
+  gossip_interval: Duration,
+  unreachable_after: Duration,
+  remove_after: Duration
+}
+
+impl ClusterConfig {
+  pub fn new() -> ClusterConfig {
+    ClusterConfig {
+      gossip_interval: Duration::from_millis(500),
+      unreachable_after: Duration::from_secs(5),
+      remove_after: Duration::from_secs(30)
+    }
+  }
+
+  pub fn with_gossip_interval(mut self, interval: Duration) -> ClusterConfig {
+    self.gossip_interval = interval;
+    self
+  }
+
+  /// Marks a member unreachable once its heartbeat has not grown for
+  /// `unreachable_after`, and removes it once it has not for `remove_after`.
+  pub fn with_timeouts(mut self, unreachable_after: Duration, remove_after: Duration) -> ClusterConfig {
+    assert!(unreachable_after < remove_after, "members are unreachable before they are removed");
+    self.unreachable_after = unreachable_after;
+    self.remove_after = remove_after;
+    self
+  }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct Gossip {
+  group: String,
+  from: String,
+  /// The members as they announced themselves, either joining or up.
+  members: Vec<Member>
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub enum ClusterMsg {
+  Gossip(Gossip),
+  /// Starts a gossip round.
+  Tick
+}
+
+impl MsgTrait for ClusterMsg {}
+
+/// The membership actors only fail when they panic.
+#[derive(Debug)]
+pub enum ClusterErr {
+  Panicked(String)
+}
+
+impl Error for ClusterErr {
+  fn from_panic(message: String) -> ClusterErr {
+    ClusterErr::Panicked(message)
+  }
+}
+
+struct Entry {
+  /// The status the member announced, either joining or up.
+  announced: MemberStatus,
+  status: MemberStatus,
+  incarnation: u64,
+  heartbeat: u64,
+  /// When the heartbeat last grew.
+  seen: Instant
+}
+
+struct Membership {
+  group: String,
+  address: String,
+  seeds: Vec<String>,
+  config: ClusterConfig,
+  members: HashMap<String, Entry>,
+  subscribers: Vec<Sender<MemberEvent>>,
+  /// The members removed since the last gossip round, whose connections
+  /// are still open.
+  removed: Vec<String>,
+  /// The position of the next gossip target among the members.
+  next: usize
+}
+
+impl Membership {
+  fn new(group: &str, address: &str, seeds: Vec<String>, config: ClusterConfig) -> Membership {
+    // a restarted node must win over what others remember of it
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let incarnation = now.as_secs() * 1000 + now.subsec_nanos() as u64 / 1_000_000;
+    // the first node of a cluster has nobody to join
+    let status = if seeds.iter().all(|s| s == address) {
+      MemberStatus::Up
+    } else {
+      MemberStatus::Joining
+    };
+
+    let mut members = HashMap::new();
+    members.insert(address.to_owned(), Entry {
+      announced: status,
+      status: status,
+      incarnation: incarnation,
+      heartbeat: 0,
+      seen: Instant::now()
+    });
+
+    Membership {
+      group: group.to_owned(),
+      address: address.to_owned(),
+      seeds: seeds,
+      config: config,
+      members: members,
+      subscribers: Vec::new(),
+      removed: Vec::new(),
+      next: 0
+    }
+  }
+
+  fn members(&self) -> Vec<Member> {
+    let mut members: Vec<Member> = self.members.iter().map(|(address, e)| {
+      Member {
+        address: address.clone(),
+        status: e.status,
+        incarnation: e.incarnation,
+        heartbeat: e.heartbeat
+      }
+    }).collect();
+    members.sort_by(|a, b| a.address.cmp(&b.address));
+    members
+  }
+
+  fn subscribe(&mut self) -> Receiver<MemberEvent> {
+    let (tx, rx) = mpsc::channel();
+    for m in self.members() {
+      let _ = tx.send(MemberEvent { address: m.address, status: m.status });
+    }
+    self.subscribers.push(tx);
+    rx
+  }
+
+  fn publish(&mut self, address: &str, status: MemberStatus) {
+    info!("member {} is {:?}", address, status);
+    let event = MemberEvent {
+      address: address.to_owned(),
+      status: status
+    };
+    self.subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+  }
+
+  fn set_status(&mut self, address: &str, status: MemberStatus) {
+    let changed = match self.members.get_mut(address) {
+      Some(e) if e.status != status => {
+        e.status = status;
+        true
+      }
+      _ => false
+    };
+    if changed {
+      if status == MemberStatus::Removed {
+        self.removed.push(address.to_owned());
+      }
+      self.publish(address, status);
+    }
+  }
+
+  fn merge(&mut self, gossip: &Gossip) {
+    if gossip.group != self.group || gossip.from == self.address {
+      return;
+    }
+
+    // somebody knows about this node, so it has joined
+    if self.members[&self.address].status == MemberStatus::Joining {
+      self.members.get_mut(&self.address).unwrap().announced = MemberStatus::Up;
+      let address = self.address.clone();
+      self.set_status(&address, MemberStatus::Up);
+    }
+
+    let now = Instant::now();
+    for m in gossip.members.iter() {
+      if m.address == self.address {
+        continue;
+      }
+
+      let newer = match self.members.get(&m.address) {
+        Some(e) => (m.incarnation, m.heartbeat) > (e.incarnation, e.heartbeat),
+        None => true
+      };
+      if !newer {
+        continue;
+      }
+
+      let changed = match self.members.get(&m.address) {
+        Some(e) => e.status != m.status,
+        None => true
+      };
+      self.members.insert(m.address.clone(), Entry {
+        announced: m.status,
+        status: m.status,
+        incarnation: m.incarnation,
+        heartbeat: m.heartbeat,
+        seen: now
+      });
+      if changed {
+        self.publish(&m.address, m.status);
+      }
+    }
+  }
+
+  /// Runs the failure detection, and returns the gossip to send along with
+  /// the addresses to send it to.
+  fn tick(&mut self) -> (Gossip, Vec<String>) {
+    let now = Instant::now();
+    {
+      let own = self.members.get_mut(&self.address).unwrap();
+      own.heartbeat += 1;
+      own.seen = now;
+    }
+
+    // removed members are kept for another `remove_after`, so that gossip
+    // that still lists them cannot bring them back meanwhile
+    let tombstone = self.config.remove_after * 2;
+    self.members.retain(|_, e| e.status != MemberStatus::Removed || now.duration_since(e.seen) <= tombstone);
+
+    let mut changes = Vec::new();
+    for (address, e) in self.members.iter() {
+      let silence = now.duration_since(e.seen);
+      if e.status == MemberStatus::Removed {
+        continue;
+      }
+      if silence > self.config.remove_after {
+        changes.push((address.clone(), MemberStatus::Removed));
+      } else if silence > self.config.unreachable_after && e.status != MemberStatus::Unreachable {
+        changes.push((address.clone(), MemberStatus::Unreachable));
+      }
+    }
+    for (address, status) in changes {
+      self.set_status(&address, status);
+    }
+
+    let gossip = Gossip {
+      group: self.group.clone(),
+      from: self.address.clone(),
+      members: self.members.iter()
+        .filter(|&(_, e)| e.status != MemberStatus::Removed)
+        .map(|(address, e)| Member {
+          address: address.clone(),
+          status: e.announced,
+          incarnation: e.incarnation,
+          heartbeat: e.heartbeat
+        })
+        .collect()
+    };
+
+    // unreachable members are still tried, so that they can come back
+    let mut others: Vec<&String> = self.members.iter()
+      .filter(|&(address, e)| *address != self.address && e.status != MemberStatus::Removed)
+      .map(|(address, _)| address)
+      .collect();
+    others.sort();
+
+    let mut targets = Vec::new();
+    if !others.is_empty() {
+      self.next = (self.next + 1) % others.len();
+      targets.push(others[self.next].clone());
+    }
+    // keep knocking at the seeds until somebody answers
+    if self.members[&self.address].status == MemberStatus::Joining || others.is_empty() {
+      for seed in self.seeds.iter() {
+        if *seed != self.address && !targets.contains(seed) {
+          targets.push(seed.clone());
+        }
+      }
+    }
+
+    (gossip, targets)
+  }
+
+  /// The members removed since the last call.
+  fn take_removed(&mut self) -> Vec<String> {
+    self.removed.drain(..).collect()
+  }
+}
+
+fn split_address(address: &str) -> Option<(&str, i32)> {
+  let idx = match address.rfind(':') {
+    Some(idx) => idx,
+    None => return None
+  };
+  address[idx + 1..].parse().ok().map(|port| (&address[..idx], port))
+}
+
+/// Merges incoming gossip, and gossips on every tick.
+struct MembershipActor {
+  context: ActorContext<ClusterMsg>,
+  state: Arc<Mutex<Membership>>,
+  transport: Arc<Transport<ClusterMsg>>
+}
+
+impl MembershipActor {
+  fn gossip(&self) {
+    let (gossip, targets) = self.state.lock().unwrap().tick();
+    let msg = ClusterMsg::Gossip(gossip);
+    for target in targets {
+      match split_address(&target) {
+        Some((host, port)) => {
+          if let Err(e) = self.transport.publish(host, port, &msg) {
+            warn!("cannot gossip to {}: {}", target, e);
+          }
+        }
+        None => warn!("bad member address: {}", target)
+      }
+    }
+
+    let removed = self.state.lock().unwrap().take_removed();
+    for address in removed {
+      if let Some((host, port)) = split_address(&address) {
+        self.transport.forget(host, port);
+      }
+    }
+  }
+}
+
+impl Actor<ClusterMsg, ClusterErr> for MembershipActor {
+  fn context(&self) -> &ActorContext<ClusterMsg> {
+    &self.context
+  }
+
+  fn on_receive(&mut self, m: &ClusterMsg) -> Result<(), ClusterErr> {
+    match *m {
+      ClusterMsg::Gossip(ref gossip) => self.state.lock().unwrap().merge(gossip),
+      ClusterMsg::Tick => self.gossip()
+    }
+    Ok(())
+  }
+}
+
+/// The membership service of a node.
+pub struct Cluster {
+  address: String,
+  state: Arc<Mutex<Membership>>,
+  stop: Arc<AtomicBool>,
+  ticker: Option<JoinHandle<()>>,
+  dispatcher: Arc<Box<Dispatcher<ClusterMsg, ClusterErr>>>,
+  _transport: Arc<Transport<ClusterMsg>>
+}
+
+impl Cluster {
+  /// Starts a node of `group` listening on `host:port`, which joins the
+  /// cluster through `seeds`, given as `host:port`. A node without seeds
+  /// starts a new cluster.
+  pub fn join(group: &str, host: &str, port: i32, seeds: Vec<String>, config: ClusterConfig)
+      -> Result<Cluster, ReactErr> {
+    let dispatcher: Arc<Box<Dispatcher<ClusterMsg, ClusterErr>>> =
+      Arc::new(Box::new(AsyncDispatcher::new()));
+    let transport = Arc::new(Transport::bind(host, port, Arc::downgrade(&dispatcher))?);
+    let address = format!("{}:{}", host, transport.local_addr().port());
+
+    let state = Arc::new(Mutex::new(Membership::new(group, &address, seeds, config)));
+    dispatcher.subscribe(Box::new(MembershipActor {
+      context: ActorContext::new(),
+      state: state.clone(),
+      transport: transport.clone()
+    }), None);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let ticker_stop = stop.clone();
+    let ticker_dispatcher = dispatcher.clone();
+    let ticker = thread::Builder::new()
+      .name(format!("react-cluster-{}", address))
+      .spawn(move || {
+        while !ticker_stop.load(Ordering::SeqCst) {
+          if let Err(e) = ticker_dispatcher.send(ClusterMsg::Tick) {
+            warn!("cannot start a gossip round: {}", e);
+          }
+          thread::park_timeout(config.gossip_interval);
+        }
+      })?;
+
+    Ok(Cluster {
+      address: address,
+      state: state,
+      stop: stop,
+      ticker: Some(ticker),
+      dispatcher: dispatcher,
+      _transport: transport
+    })
+  }
+
+  /// `host:port` of this node.
+  pub fn address(&self) -> &str {
+    &self.address
+  }
+
+  /// All known members including this node, ordered by address.
+  pub fn members(&self) -> Vec<Member> {
+    self.state.lock().unwrap().members()
+  }
+
+  /// Returns the member changes from now on, starting with the current
+  /// status of every known member.
+  pub fn subscribe(&self) -> Receiver<MemberEvent> {
+    self.state.lock().unwrap().subscribe()
+  }
+
+  pub fn dispatcher(&self) -> Arc<Box<Dispatcher<ClusterMsg, ClusterErr>>> {
+    self.dispatcher.clone()
+  }
+}
+
+impl Drop for Cluster {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::SeqCst);
+    if let Some(ticker) = self.ticker.take() {
+      ticker.thread().unpark();
+      if ticker.join().is_err() {
+        error!("the cluster ticker panicked");
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc::Receiver;
+  use std::thread;
+  use std::time::Duration;
+
+  use react::dispatcher::tests::wait_until;
+  use super::{Cluster, ClusterConfig, Gossip, Member, MemberEvent, MemberStatus, Membership, MEMBERS_GROUP};
+
+  fn config() -> ClusterConfig {
+    ClusterConfig::new()
+      .with_gossip_interval(Duration::from_millis(20))
+      .with_timeouts(Duration::from_millis(300), Duration::from_millis(600))
+  }
+
+  fn node(seeds: Vec<String>) -> Cluster {
+    Cluster::join(MEMBERS_GROUP, "127.0.0.1", 0, seeds, config()).ok().unwrap()
+  }
+
+  fn statuses(cluster: &Cluster) -> Vec<MemberStatus> {
+    cluster.members().iter().map(|m| m.status()).collect()
+  }
+
+  fn gossip(member: &Member) -> Gossip {
+    Gossip {
+      group: MEMBERS_GROUP.to_owned(),
+      from: member.address.clone(),
+      members: vec![member.clone()]
+    }
+  }
+
+  fn wait_for(events: &Receiver<MemberEvent>, address: &str, status: MemberStatus) {
+    let expected = MemberEvent { address: address.to_owned(), status: status };
+    loop {
+      let event = events.recv_timeout(Duration::from_secs(5)).ok().expect("no member event");
+      if event == expected {
+        return;
+      }
+    }
+  }
+
+  #[test]
+  fn test_join() {
+    let a = node(vec![]);
+    let b = node(vec![a.address().to_owned()]);
+    let c = node(vec![a.address().to_owned()]);
+
+    let up = vec![MemberStatus::Up; 3];
+    wait_until(|| statuses(&a) == up && statuses(&b) == up && statuses(&c) == up);
+    // c has only talked to a, and learned about b by gossip
+    assert_eq!(a.members().iter().map(|m| m.address().to_owned()).collect::<Vec<_>>(),
+      c.members().iter().map(|m| m.address().to_owned()).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn test_failure() {
+    let a = node(vec![]);
+    let b = node(vec![a.address().to_owned()]);
+    let events = a.subscribe();
+    wait_for(&events, b.address(), MemberStatus::Up);
+
+    let address = b.address().to_owned();
+    drop(b);
+    wait_for(&events, &address, MemberStatus::Unreachable);
+    wait_for(&events, &address, MemberStatus::Removed);
+    let removed = a.members().into_iter().find(|m| m.address() == address).unwrap();
+    assert_eq!(MemberStatus::Removed, removed.status());
+  }
+
+  #[test]
+  fn test_incarnation() {
+    let mut membership = Membership::new(MEMBERS_GROUP, "a:1", vec![], config());
+    let old = Member {
+      address: "b:1".to_owned(),
+      status: MemberStatus::Up,
+      incarnation: 10,
+      heartbeat: 50
+    };
+    membership.merge(&gossip(&old));
+
+    // the heartbeats of a restarted node start over
+    membership.merge(&gossip(&Member { incarnation: 20, heartbeat: 1, ..old.clone() }));
+    // and late gossip about the old incarnation is ignored
+    membership.merge(&gossip(&Member { heartbeat: 60, ..old.clone() }));
+    let b = membership.members().into_iter().find(|m| m.address() == "b:1").unwrap();
+    assert_eq!((20, 1), (b.incarnation(), b.heartbeat()));
+  }
+
+  #[test]
+  fn test_tombstone() {
+    let config = ClusterConfig::new()
+      .with_timeouts(Duration::from_millis(10), Duration::from_millis(20));
+    let mut membership = Membership::new(MEMBERS_GROUP, "a:1", vec![], config);
+    let b = Member {
+      address: "b:1".to_owned(),
+      status: MemberStatus::Up,
+      incarnation: 10,
+      heartbeat: 50
+    };
+    membership.merge(&gossip(&b));
+
+    thread::sleep(Duration::from_millis(30));
+    membership.tick();
+    assert_eq!(vec!["b:1".to_owned()], membership.take_removed());
+    assert!(membership.take_removed().is_empty());
+    // gossip that still lists the member cannot bring it back
+    membership.merge(&gossip(&b));
+    assert_eq!(vec![MemberStatus::Up, MemberStatus::Removed],
+      membership.members().iter().map(|m| m.status()).collect::<Vec<_>>());
+
+    thread::sleep(Duration::from_millis(30));
+    membership.tick();
+    assert_eq!(1, membership.members().len());
+  }
+
+  #[test]
+  fn test_other_group() {
+    let a = node(vec![]);
+    let b = Cluster::join("other", "127.0.0.1", 0, vec![a.address().to_owned()], config())
+      .ok().unwrap();
+
+    ::std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(1, a.members().len());
+    assert_eq!(vec![MemberStatus::Joining], statuses(&b));
+  }
+}
@@ -0,0 +1,574 @@
+//!
+//! Encodings of messages and events.
+//!
+//! A codec turns a value tree into bytes and back. Values are first encoded
+//! into a `Json` tree with rustc-serialize, which keeps the names of fields,
+//! so that a decoder ignores the fields it does not know and a missing
+//! `Option` field decodes as `None`. `JsonCodec` writes the tree as JSON
+//! text, and `BinaryCodec` as tagged values with variable-length integers.
+//!
+//! Every encoded value starts with a header: the id of the codec, the
+//! version of the value type and its type tag. A decoder picks the codec by
+//! its id, so peers may each send in their own format, and the version lets
+//! `MsgTrait::upgrade` rewrite a message of another version before it is
+//! decoded.
+//!
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::str;
+
+use rustc_serialize::{Encodable, Decodable};
+use rustc_serialize::json::{self, Json};
+
+use err::{ReactErr, ReactErrKind};
+use super::MsgTrait;
+
+/// The id of `JsonCodec`.
+pub const JSON: u8 = 1;
+/// The id of `BinaryCodec`.
+pub const BINARY: u8 = 2;
+
+/// The deepest nesting of arrays and objects a codec reads. Both readers
+/// recurse, so deeper values from a peer could overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+pub fn codec_err<D: Display>(e: D) -> ReactErr {
+  ReactErr::new(ReactErrKind::Codec(format!("{}", e)))
+}
+
+/// Rejects JSON text nested deeper than `MAX_DEPTH`, before the parser
+/// recurses into it. The brackets in strings do not count.
+fn check_depth(s: &str) -> Result<(), ReactErr> {
+  let mut depth = 0;
+  let mut in_string = false;
+  let mut escaped = false;
+  for b in s.bytes() {
+    if in_string {
+      if escaped {
+        escaped = false;
+      } else if b == b'\\' {
+        escaped = true;
+      } else if b == b'"' {
+        in_string = false;
+      }
+      continue;
+    }
+
+    match b {
+      b'"' => in_string = true,
+      b'[' | b'{' => {
+        depth += 1;
+        if depth > MAX_DEPTH {
+          return Err(codec_err("nesting too deep"));
+        }
+      }
+      // unbalanced brackets are left to the parser
+      b']' | b'}' if depth > 0 => depth -= 1,
+      _ => {}
+    }
+  }
+  Ok(())
+}
+
+pub trait Codec: Send + Sync {
+  /// Tells the format apart in the header. It must be unique among the
+  /// codecs a system uses.
+  fn id(&self) -> u8;
+  fn name(&self) -> &'static str;
+  fn write(&self, value: &Json, out: &mut Vec<u8>) -> Result<(), ReactErr>;
+  fn read(&self, bytes: &[u8]) -> Result<Json, ReactErr>;
+}
+
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+  fn id(&self) -> u8 {
+    JSON
+  }
+
+  fn name(&self) -> &'static str {
+    "json"
+  }
+
+  fn write(&self, value: &Json, out: &mut Vec<u8>) -> Result<(), ReactErr> {
+    out.extend_from_slice(value.to_string().as_bytes());
+    Ok(())
+  }
+
+  fn read(&self, bytes: &[u8]) -> Result<Json, ReactErr> {
+    let s = str::from_utf8(bytes).map_err(codec_err)?;
+    check_depth(s)?;
+    Json::from_str(s).map_err(codec_err)
+  }
+}
+
+const NULL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const I64: u8 = 3;
+const U64: u8 = 4;
+const F64: u8 = 5;
+const STRING: u8 = 6;
+const ARRAY: u8 = 7;
+const OBJECT: u8 = 8;
+
+/// Writes every value as a tag byte and its content. Integers and lengths
+/// are LEB128 varints, negative integers zigzag-encoded first, and floats
+/// take eight big-endian bytes.
+pub struct BinaryCodec;
+
+fn write_varint(mut n: u64, out: &mut Vec<u8>) {
+  while n >= 0x80 {
+    out.push((n as u8) | 0x80);
+    n >>= 7;
+  }
+  out.push(n as u8);
+}
+
+fn write_str(s: &str, out: &mut Vec<u8>) {
+  write_varint(s.len() as u64, out);
+  out.extend_from_slice(s.as_bytes());
+}
+
+fn write_value(value: &Json, out: &mut Vec<u8>) {
+  match *value {
+    Json::Null => out.push(NULL),
+    Json::Boolean(false) => out.push(FALSE),
+    Json::Boolean(true) => out.push(TRUE),
+    Json::I64(n) => {
+      out.push(I64);
+      write_varint(((n << 1) ^ (n >> 63)) as u64, out);
+    }
+    Json::U64(n) => {
+      out.push(U64);
+      write_varint(n, out);
+    }
+    Json::F64(f) => {
+      out.push(F64);
+      let bits: u64 = f.to_bits();
+      for i in (0..8).rev() {
+        out.push((bits >> (i * 8)) as u8);
+      }
+    }
+    Json::String(ref s) => {
+      out.push(STRING);
+      write_str(s, out);
+    }
+    Json::Array(ref items) => {
+      out.push(ARRAY);
+      write_varint(items.len() as u64, out);
+      for item in items {
+        write_value(item, out);
+      }
+    }
+    Json::Object(ref fields) => {
+      out.push(OBJECT);
+      write_varint(fields.len() as u64, out);
+      for (k, v) in fields {
+        write_str(k, out);
+        write_value(v, out);
+      }
+    }
+  }
+}
+
+struct BinaryReader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+  /// The arrays and objects the reader is in.
+  depth: usize
+}
+
+impl<'a> BinaryReader<'a> {
+  fn byte(&mut self) -> Result<u8, ReactErr> {
+    match self.bytes.get(self.pos) {
+      Some(&b) => {
+        self.pos += 1;
+        Ok(b)
+      }
+      None => Err(codec_err("truncated binary value"))
+    }
+  }
+
+  fn varint(&mut self) -> Result<u64, ReactErr> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+      let b = self.byte()?;
+      if shift >= 64 {
+        return Err(codec_err("varint too long"));
+      }
+      n |= ((b & 0x7f) as u64) << shift;
+      if b & 0x80 == 0 {
+        return Ok(n);
+      }
+      shift += 7;
+    }
+  }
+
+  /// A length, which cannot be more than the bytes left.
+  fn len(&mut self) -> Result<usize, ReactErr> {
+    let n = self.varint()?;
+    if n > (self.bytes.len() - self.pos) as u64 {
+      return Err(codec_err("length past the end"));
+    }
+    Ok(n as usize)
+  }
+
+  fn string(&mut self) -> Result<String, ReactErr> {
+    let len = self.len()?;
+    let s = str::from_utf8(&self.bytes[self.pos..self.pos + len]).map_err(codec_err)?;
+    self.pos += len;
+    Ok(s.to_owned())
+  }
+
+  fn nest(&mut self) -> Result<(), ReactErr> {
+    self.depth += 1;
+    if self.depth > MAX_DEPTH {
+      return Err(codec_err("nesting too deep"));
+    }
+    Ok(())
+  }
+
+  fn value(&mut self) -> Result<Json, ReactErr> {
+    Ok(match self.byte()? {
+      NULL => Json::Null,
+      FALSE => Json::Boolean(false),
+      TRUE => Json::Boolean(true),
+      I64 => {
+        let z = self.varint()?;
+        Json::I64(((z >> 1) as i64) ^ -((z & 1) as i64))
+      }
+      U64 => Json::U64(self.varint()?),
+      F64 => {
+        let mut bits = 0u64;
+        for _ in 0..8 {
+          bits = (bits << 8) | self.byte()? as u64;
+        }
+        Json::F64(f64::from_bits(bits))
+      }
+      STRING => Json::String(self.string()?),
+      ARRAY => {
+        let len = self.len()?;
+        self.nest()?;
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+          items.push(self.value()?);
+        }
+        self.depth -= 1;
+        Json::Array(items)
+      }
+      OBJECT => {
+        let len = self.len()?;
+        self.nest()?;
+        let mut fields = BTreeMap::new();
+        for _ in 0..len {
+          let k = self.string()?;
+          fields.insert(k, self.value()?);
+        }
+        self.depth -= 1;
+        Json::Object(fields)
+      }
+      tag => return Err(codec_err(format!("unknown binary tag {}", tag)))
+    })
+  }
+}
+
+impl Codec for BinaryCodec {
+  fn id(&self) -> u8 {
+    BINARY
+  }
+
+  fn name(&self) -> &'static str {
+    "binary"
+  }
+
+  fn write(&self, value: &Json, out: &mut Vec<u8>) -> Result<(), ReactErr> {
+    write_value(value, out);
+    Ok(())
+  }
+
+  fn read(&self, bytes: &[u8]) -> Result<Json, ReactErr> {
+    let mut reader = BinaryReader {
+      bytes: bytes,
+      pos: 0,
+      depth: 0
+    };
+    let value = reader.value()?;
+    if reader.pos != bytes.len() {
+      return Err(codec_err("trailing bytes after a binary value"));
+    }
+    Ok(value)
+  }
+}
+
+pub fn to_json<T: Encodable>(value: &T) -> Result<Json, ReactErr> {
+  let s = json::encode(value).map_err(codec_err)?;
+  Json::from_str(&s).map_err(codec_err)
+}
+
+pub fn from_json<T: Decodable>(value: Json) -> Result<T, ReactErr> {
+  Decodable::decode(&mut json::Decoder::new(value)).map_err(codec_err)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+  /// The id of the codec that wrote the value.
+  pub codec: u8,
+  pub version: u16,
+  pub type_tag: String
+}
+
+/// The codecs of a system: one to write with, and all of them to read.
+pub struct Codecs {
+  codecs: Vec<Box<Codec>>,
+  default: u8
+}
+
+impl Codecs {
+  /// Writes with `default`, and reads only what it wrote.
+  pub fn new(default: Box<Codec>) -> Codecs {
+    let id = default.id();
+    Codecs {
+      codecs: vec![default],
+      default: id
+    }
+  }
+
+  /// Writes JSON, and reads both JSON and binary.
+  pub fn json() -> Codecs {
+    Codecs::new(Box::new(JsonCodec)).with_codec(Box::new(BinaryCodec))
+  }
+
+  /// Writes binary, and reads both binary and JSON.
+  pub fn binary() -> Codecs {
+    Codecs::new(Box::new(BinaryCodec)).with_codec(Box::new(JsonCodec))
+  }
+
+  /// Reads `codec` as well, replacing a codec with the same id.
+  pub fn with_codec(mut self, codec: Box<Codec>) -> Codecs {
+    self.codecs.retain(|c| c.id() != codec.id());
+    self.codecs.push(codec);
+    self
+  }
+
+  fn codec(&self, id: u8) -> Result<&Codec, ReactErr> {
+    match self.codecs.iter().find(|c| c.id() == id) {
+      Some(codec) => Ok(&**codec),
+      None => Err(codec_err(format!("unknown codec {}", id)))
+    }
+  }
+
+  /// Encodes `value` behind a header with its type tag and version.
+  pub fn encode<T: Encodable>(&self, type_tag: &str, version: u16, value: &T) -> Result<Vec<u8>, ReactErr> {
+    if type_tag.len() > 255 {
+      return Err(codec_err("type tag longer than 255 bytes"));
+    }
+
+    let mut out = Vec::new();
+    out.push(self.default);
+    out.push((version >> 8) as u8);
+    out.push(version as u8);
+    out.push(type_tag.len() as u8);
+    out.extend_from_slice(type_tag.as_bytes());
+    self.codec(self.default)?.write(&to_json(value)?, &mut out)?;
+    Ok(out)
+  }
+
+  /// Decodes the header and the value tree, with the codec the header names.
+  pub fn decode_value(&self, bytes: &[u8]) -> Result<(Header, Json), ReactErr> {
+    if bytes.len() < 4 || bytes.len() < 4 + bytes[3] as usize {
+      return Err(codec_err("truncated header"));
+    }
+    let body = 4 + bytes[3] as usize;
+    let header = Header {
+      codec: bytes[0],
+      version: ((bytes[1] as u16) << 8) | bytes[2] as u16,
+      type_tag: str::from_utf8(&bytes[4..body]).map_err(codec_err)?.to_owned()
+    };
+    let value = self.codec(header.codec)?.read(&bytes[body..])?;
+    Ok((header, value))
+  }
+
+  pub fn decode<T: Decodable>(&self, bytes: &[u8]) -> Result<(Header, T), ReactErr> {
+    let (header, value) = self.decode_value(bytes)?;
+    Ok((header, from_json(value)?))
+  }
+
+  pub fn encode_msg<M: MsgTrait>(&self, m: &M) -> Result<Vec<u8>, ReactErr> {
+    self.encode(M::type_tag(), M::version(), m)
+  }
+
+  pub fn decode_msg<M: MsgTrait>(&self, bytes: &[u8]) -> Result<M, ReactErr> {
+    let (header, value) = self.decode_value(bytes)?;
+    decode_msg_value(&header, value)
+  }
+}
+
+impl fmt::Debug for Codecs {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let names: Vec<_> = self.codecs.iter().map(|c| c.name()).collect();
+    write!(f, "Codecs {{ default: {}, codecs: {:?} }}", self.default, names)
+  }
+}
+
+/// Decodes the value tree of a message of type `M`, after checking its type
+/// tag and upgrading it from the version it was written with.
+pub fn decode_msg_value<M: MsgTrait>(header: &Header, value: Json) -> Result<M, ReactErr> {
+  let tag = M::type_tag();
+  if !tag.is_empty() && !header.type_tag.is_empty() && tag != header.type_tag {
+    return Err(codec_err(format!("expected a {} message, got a {} one", tag, header.type_tag)));
+  }
+
+  let value = if header.version != M::version() {
+    M::upgrade(header.version, value).map_err(codec_err)?
+  } else {
+    value
+  };
+  from_json(value)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::iter;
+
+  use rustc_serialize::json::Json;
+
+  use err::ReactErrKind;
+  use react::MsgTrait;
+  use super::{Codec, Codecs, JsonCodec, BinaryCodec, Header, JSON, BINARY, ARRAY, NULL, MAX_DEPTH, to_json};
+
+  #[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq)]
+  struct Update {
+    node: String,
+    load: i64,
+    ratio: f64,
+    tags: Vec<String>,
+    leader: Option<u32>
+  }
+
+  impl MsgTrait for Update {
+    fn type_tag() -> &'static str {
+      "update"
+    }
+
+    fn version() -> u16 {
+      2
+    }
+
+    /// Version 1 called the load `cpu`.
+    fn upgrade(version: u16, value: Json) -> Result<Json, String> {
+      match (version, value) {
+        (1, Json::Object(mut fields)) => {
+          let cpu = fields.remove("cpu").ok_or("no cpu")?;
+          fields.insert("load".to_owned(), cpu);
+          Ok(Json::Object(fields))
+        }
+        (v, _) => Err(format!("unknown version {}", v))
+      }
+    }
+  }
+
+  /// The older version of `Update`.
+  #[derive(RustcDecodable, RustcEncodable)]
+  struct UpdateV1 {
+    node: String,
+    cpu: i64,
+    ratio: f64,
+    tags: Vec<String>
+  }
+
+  fn update() -> Update {
+    Update {
+      node: "n1".to_owned(),
+      load: -300,
+      ratio: 0.25,
+      tags: vec!["a".to_owned(), "é".to_owned()],
+      leader: Some(7)
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    for codec in &[Box::new(JsonCodec) as Box<Codec>, Box::new(BinaryCodec)] {
+      let value = to_json(&update()).ok().unwrap();
+      let mut out = Vec::new();
+      codec.write(&value, &mut out).ok().unwrap();
+      assert_eq!(value, codec.read(&out).ok().unwrap());
+    }
+  }
+
+  #[test]
+  fn test_binary_is_compact() {
+    let json = Codecs::json().encode_msg(&update()).ok().unwrap();
+    let binary = Codecs::binary().encode_msg(&update()).ok().unwrap();
+    assert!(binary.len() < json.len(), "{} vs {}", binary.len(), json.len());
+  }
+
+  #[test]
+  fn test_header() {
+    let bytes = Codecs::binary().encode_msg(&update()).ok().unwrap();
+    let (header, _) = Codecs::json().decode_value(&bytes).ok().unwrap();
+    assert_eq!(Header { codec: BINARY, version: 2, type_tag: "update".to_owned() }, header);
+
+    // either side reads what the other writes
+    assert_eq!(update(), Codecs::json().decode_msg(&bytes).ok().unwrap());
+    let bytes = Codecs::json().encode_msg(&update()).ok().unwrap();
+    assert_eq!(JSON, bytes[0]);
+    assert_eq!(update(), Codecs::binary().decode_msg(&bytes).ok().unwrap());
+
+    let only_json = Codecs::new(Box::new(JsonCodec));
+    let bytes = Codecs::binary().encode_msg(&update()).ok().unwrap();
+    assert!(only_json.decode_msg::<Update>(&bytes).is_err());
+  }
+
+  #[test]
+  fn test_versions() {
+    let old = UpdateV1 {
+      node: "n1".to_owned(),
+      cpu: 5,
+      ratio: 0.5,
+      tags: Vec::new()
+    };
+    let bytes = Codecs::binary().encode("update", 1, &old).ok().unwrap();
+    let m: Update = Codecs::json().decode_msg(&bytes).ok().unwrap();
+    assert_eq!(5, m.load);
+    assert_eq!(None, m.leader);
+
+    let bytes = Codecs::json().encode("member", 2, &old).ok().unwrap();
+    let err = Codecs::json().decode_msg::<Update>(&bytes).err().unwrap();
+    assert!(match *err.kind() { ReactErrKind::Codec(_) => true, _ => false });
+  }
+
+  #[test]
+  fn test_garbage() {
+    let codecs = Codecs::json();
+    for bytes in &[&[][..], &[BINARY, 0, 1, 9, b'x'][..], &[BINARY, 0, 1, 0, 7, 200][..],
+                   &[BINARY, 0, 1, 0, 42][..], &[9, 0, 1, 0][..]] {
+      assert!(codecs.decode_value(bytes).is_err(), "{:?}", bytes);
+    }
+
+    // nested arrays deep enough to overflow the stack of a recursive reader
+    let mut binary = vec![BINARY, 0, 1, 0];
+    for _ in 0..100000 {
+      binary.extend_from_slice(&[ARRAY, 1]);
+    }
+    binary.push(NULL);
+    let mut json = vec![JSON, 0, 1, 0];
+    json.extend(iter::repeat(b'[').take(100000));
+    for bytes in &[binary, json] {
+      match *codecs.decode_value(bytes).err().unwrap().kind() {
+        ReactErrKind::Codec(ref e) => assert_eq!("nesting too deep", e),
+        _ => panic!("not a codec error")
+      }
+    }
+
+    // brackets in strings do not count, and the limit itself is fine
+    let mut json = vec![JSON, 0, 1, 0];
+    json.extend(iter::repeat(b'[').take(MAX_DEPTH));
+    json.extend_from_slice(b"\"[[\\\"[\"");
+    json.extend(iter::repeat(b']').take(MAX_DEPTH));
+    assert!(codecs.decode_value(&json).is_ok());
+  }
+}
@@ -0,0 +1,382 @@
+//!
+//! A dispatcher without threads, for deterministic tests.
+//!
+//! By default a message is handled on the thread that sends it, before
+//! `send` returns. Messages that actors send while handling one are queued
+//! and handled right after it, in order. A manual dispatcher only queues,
+//! and its messages are handled one at a time by `step`.
+//!
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use err::ReactErr;
+use react::{MsgTrait, Error, Predicate};
+use react::actor::{Actor, ActorUri, ActorRef, Courier};
+use react::metrics::{DispatcherStats, DispatcherSnapshot};
+use react::reply::{self, ReplyTo, ReplyHandle};
+use react::supervision::{Factory, Supervisor};
+use super::{Dispatcher, ActorPair, DeadLetters, MessageFrame, MessageBase, Shutdown, Spawner, deliver,
+            next_uri, remove, start_child, stopped_actor, unknown_actor};
+
+/// The queue and actors of a dispatcher, shared with its courier.
+struct State<M: MsgTrait, E: Error> {
+  actors: RwLock<Vec<Arc<ActorPair<M, E>>>>,
+  queue: Mutex<VecDeque<MessageFrame<M>>>,
+  dead_letters: Arc<DeadLetters<M>>,
+  stats: DispatcherStats,
+  /// Handles messages as they are sent, instead of waiting for `step`.
+  auto: bool,
+  /// Set while a thread is handling the queue.
+  running: AtomicBool,
+  stopped: AtomicBool,
+  /// The error of an actor whose supervisor escalated it.
+  failure: Mutex<Option<E>>,
+  seq: AtomicUsize
+}
+
+unsafe impl<M: MsgTrait, E: Error> Sync for State<M, E> {}
+unsafe impl<M: MsgTrait, E: Error> Send for State<M, E> {}
+
+impl<M: MsgTrait, E: Error> State<M, E> {
+  fn step(&self) -> bool {
+    if self.stopped.load(Ordering::SeqCst) {
+      return false;
+    }
+
+    // the queue is unlocked while the actors run, so that they can send
+    let frame = match self.queue.lock().unwrap().pop_front() {
+      Some(frame) => frame,
+      None => return false
+    };
+    if let Err(e) = deliver(frame, &self.actors, &self.dead_letters, &self.stats) {
+      *self.failure.lock().unwrap() = Some(e);
+      self.stopped.store(true, Ordering::SeqCst);
+    }
+    true
+  }
+
+  fn run_until_idle(&self) -> usize {
+    let mut handled = 0;
+    while self.step() {
+      handled += 1;
+    }
+    handled
+  }
+
+  fn pending(&self) -> usize {
+    self.queue.lock().unwrap().len()
+  }
+
+  fn push(&self, to: Option<ActorUri>, msg: MessageBase<M>) {
+    self.queue.lock().unwrap().push_back(MessageFrame {
+      to: to,
+      msg: msg,
+      // `mailbox_len` scans the queue instead
+      queued: None
+    });
+
+    if !self.auto {
+      return;
+    }
+    // a send from an actor only queues, and the outer loop picks it up
+    while !self.running.swap(true, Ordering::SeqCst) {
+      self.run_until_idle();
+      self.running.store(false, Ordering::SeqCst);
+
+      // another thread may have queued after the last step
+      if self.pending() == 0 || self.stopped.load(Ordering::SeqCst) {
+        break;
+      }
+    }
+  }
+
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    if !self.actors.read().unwrap().iter().any(|p| p.uri() == to && !p.is_stopped()) {
+      return Err(stopped_actor(&self.dead_letters, to, m));
+    }
+
+    let msg = match reply_to {
+      Some(reply_to) => MessageBase::Ask(m, reply_to),
+      None => MessageBase::OneWay(m)
+    };
+    self.push(Some(to.clone()), msg);
+    Ok(())
+  }
+
+  fn stop(&self, uri: &ActorUri) -> Result<(), ReactErr> {
+    match remove(&self.actors, uri) {
+      Some(pair) => {
+        pair.stop();
+        Ok(())
+      }
+      None => Err(unknown_actor(uri))
+    }
+  }
+
+  fn stop_actors(&self) {
+    let stopped: Vec<_> = self.actors.write().unwrap().drain(..).collect();
+    for pair in stopped {
+      pair.stop();
+    }
+  }
+}
+
+impl<M: MsgTrait, E: Error> Courier<M> for Weak<State<M, E>> {
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    match self.upgrade() {
+      Some(state) => state.forward(to, m, reply_to),
+      None => Err(unknown_actor(to))
+    }
+  }
+
+  fn stop(&self, to: &ActorUri) -> Result<(), ReactErr> {
+    match self.upgrade() {
+      Some(state) => state.stop(to),
+      None => Err(unknown_actor(to))
+    }
+  }
+}
+
+impl<M: MsgTrait, E: Error> Spawner<M, E> for Weak<State<M, E>> {
+  fn spawn(&self, parent: &ActorUri, name: &str, actor: Box<Actor<M, E>>,
+           factory: Option<Box<Factory<M, E>>>) -> Result<ActorRef<M>, ReactErr> {
+    let state = match self.upgrade() {
+      Some(state) => state,
+      None => return Err(unknown_actor(parent))
+    };
+    let parent = match state.actors.read().unwrap().iter().find(|p| p.uri() == parent) {
+      Some(pair) => pair.clone(),
+      None => return Err(unknown_actor(parent))
+    };
+    start_child(&parent, name, actor, factory, |child| state.actors.write().unwrap().push(child))
+  }
+}
+
+pub struct CallingThreadDispatcher<M: MsgTrait, E: Error> {
+  state: Arc<State<M, E>>,
+  courier: Arc<Courier<M>>,
+  spawner: Arc<Spawner<M, E>>
+}
+
+impl<M: MsgTrait, E: Error> CallingThreadDispatcher<M, E> {
+  /// Handles every message on the thread that sends it.
+  pub fn new() -> CallingThreadDispatcher<M, E> {
+    CallingThreadDispatcher::with_mode(true)
+  }
+
+  /// Queues every message until `step` or `run_until_idle` handles it.
+  pub fn manual() -> CallingThreadDispatcher<M, E> {
+    CallingThreadDispatcher::with_mode(false)
+  }
+
+  fn with_mode(auto: bool) -> CallingThreadDispatcher<M, E> {
+    let state = Arc::new(State {
+      actors: RwLock::new(Vec::new()),
+      queue: Mutex::new(VecDeque::new()),
+      dead_letters: Arc::new(DeadLetters::new()),
+      stats: DispatcherStats::new(),
+      auto: auto,
+      running: AtomicBool::new(false),
+      stopped: AtomicBool::new(false),
+      failure: Mutex::new(None),
+      seq: AtomicUsize::new(0)
+    });
+
+    CallingThreadDispatcher {
+      courier: Arc::new(Arc::downgrade(&state)),
+      spawner: Arc::new(Arc::downgrade(&state)),
+      state: state
+    }
+  }
+
+  /// Handles the next queued message, and returns whether there was one.
+  pub fn step(&self) -> bool {
+    self.state.step()
+  }
+
+  /// Handles queued messages until none is left, including the ones sent
+  /// meanwhile, and returns how many there were.
+  pub fn run_until_idle(&self) -> usize {
+    self.state.run_until_idle()
+  }
+
+  /// The number of queued messages.
+  pub fn pending(&self) -> usize {
+    self.state.pending()
+  }
+}
+
+impl<M: MsgTrait, E: Error> Dispatcher<M, E> for CallingThreadDispatcher<M, E> {
+  /// A drain handles the queued messages right away, whatever the deadline.
+  fn shutdown(&mut self, mode: Shutdown) {
+    if let Shutdown::Drain(_) = mode {
+      self.state.run_until_idle();
+    }
+    self.state.stopped.store(true, Ordering::SeqCst);
+  }
+
+  fn join(self) -> Result<usize, E> {
+    self.state.stop_actors();
+    let dropped = self.state.queue.lock().unwrap().drain(..).count();
+    match self.state.failure.lock().unwrap().take() {
+      Some(e) => Err(e),
+      None => Ok(dropped)
+    }
+  }
+
+  fn send(&self, m: M) -> Result<(), ReactErr> {
+    self.state.push(None, MessageBase::OneWay(m));
+    Ok(())
+  }
+
+  fn ask(&self, m: M, timeout: Duration) -> ReplyHandle<M> {
+    let (reply_to, handle) = reply::channel(timeout);
+    self.state.push(None, MessageBase::Ask(m, reply_to));
+    handle
+  }
+
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    self.state.forward(to, m, reply_to)
+  }
+
+  fn subscribe_supervised(&self, actor: Box<Actor<M, E>>, filter: Option<Box<Predicate<M>>>,
+                          supervisor: Supervisor<M, E>) -> ActorRef<M> {
+    let actor_ref = ActorRef::new(next_uri(&self.state.seq), self.courier.clone());
+    let pair = ActorPair::new(actor_ref.clone(), actor, filter, supervisor, self.spawner.clone(),
+                              self.state.dead_letters.clone());
+    pair.start(|pair| self.state.actors.write().unwrap().push(pair));
+    actor_ref
+  }
+
+  fn unsubscribe(&self, uri: &ActorUri) -> Result<(), ReactErr> {
+    self.state.stop(uri)
+  }
+
+  /// The queue is unbounded, so there is no overflow policy to drop
+  /// anything. The messages still queued at shutdown are counted by `join`,
+  /// like for the other dispatchers.
+  fn dropped(&self) -> usize {
+    0
+  }
+
+  fn mailbox_len(&self, uri: &ActorUri) -> Result<usize, ReactErr> {
+    if !self.state.actors.read().unwrap().iter().any(|p| p.uri() == uri) {
+      return Err(unknown_actor(uri));
+    }
+    let queue = self.state.queue.lock().unwrap();
+    Ok(queue.iter().filter(|f| f.to.as_ref().map_or(true, |to| to == uri)).count())
+  }
+
+  fn dead_letters(&self) -> &DeadLetters<M> {
+    &self.state.dead_letters
+  }
+
+  /// There are no threads here, so the idle time stays zero.
+  fn metrics(&self) -> DispatcherSnapshot {
+    let actors = self.state.actors.read().unwrap().iter().map(|p| p.snapshot()).collect();
+    self.state.stats.snapshot(self.state.pending(), actors)
+  }
+}
+
+impl<M: MsgTrait, E: Error> Drop for CallingThreadDispatcher<M, E> {
+  fn drop(&mut self) {
+    self.state.stop_actors();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use react::actor::tests::Lifecycle;
+  use react::dispatcher::Dispatcher;
+  use react::dispatcher::tests::{Msg, Err, Echo, Recorder};
+  use react::dispatcher::DeadLetterReason;
+  use react::supervision::Supervisor;
+  use super::CallingThreadDispatcher;
+
+  #[test]
+  fn test_calling_thread() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    dispatcher.subscribe(Box::new(Recorder::new(received.clone())), None);
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    dispatcher.send(Msg::Ping(1)).ok().unwrap();
+    assert_eq!(vec![1], *received.lock().unwrap());
+
+    // the reply is there before `ask` returns
+    let reply = dispatcher.ask(Msg::Ping(2), Duration::from_secs(0));
+    assert_eq!(Some(Msg::Pong(2)), reply.poll().ok().unwrap());
+    assert_eq!(0, dispatcher.pending());
+  }
+
+  #[test]
+  fn test_manual() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::manual();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    dispatcher.subscribe(Box::new(Recorder::new(received.clone())), None);
+
+    for i in 0..3 {
+      dispatcher.send(Msg::Ping(i)).ok().unwrap();
+    }
+    assert!(received.lock().unwrap().is_empty());
+    assert_eq!(3, dispatcher.pending());
+
+    assert!(dispatcher.step());
+    assert_eq!(vec![0], *received.lock().unwrap());
+    assert_eq!(2, dispatcher.run_until_idle());
+    assert_eq!(vec![0, 1, 2], *received.lock().unwrap());
+    assert!(!dispatcher.step());
+  }
+
+  #[test]
+  fn test_dead_letters() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::manual();
+    let letters = dispatcher.dead_letters().subscribe();
+    let uri = dispatcher.subscribe(Box::new(Echo::new()), None).path().clone();
+
+    dispatcher.send_to(&uri, Msg::Ping(1)).ok().unwrap();
+    dispatcher.unsubscribe(&uri).ok().unwrap();
+    dispatcher.send(Msg::Ping(2)).ok().unwrap();
+    assert_eq!(2, dispatcher.run_until_idle());
+
+    let reasons: Vec<_> = letters.try_iter().map(|l| (l.recipient().cloned(), l.reason())).collect();
+    assert_eq!(vec![(Some(uri.clone()), DeadLetterReason::ActorStopped), (None, DeadLetterReason::Unhandled)],
+               reasons);
+
+    assert!(dispatcher.send_to(&uri, Msg::Ping(3)).is_err());
+    let letter = letters.try_recv().unwrap();
+    assert_eq!((&Msg::Ping(3), DeadLetterReason::ActorStopped), (letter.message(), letter.reason()));
+  }
+
+  #[test]
+  fn test_escalate() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    dispatcher.subscribe_supervised(Box::new(Lifecycle::new(events.clone())), None,
+      Supervisor::escalate());
+
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    dispatcher.send(Msg::Ping(1)).ok().unwrap();
+    assert_eq!(1, dispatcher.pending());
+    assert!(dispatcher.join().is_err());
+    assert_eq!(vec!["pre_start", "post_stop"], *events.lock().unwrap());
+  }
+
+  #[test]
+  fn test_shutdown() {
+    let mut dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::manual();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    dispatcher.subscribe(Box::new(Lifecycle::new(events.clone())), None);
+
+    dispatcher.send(Msg::Ping(1)).ok().unwrap();
+    dispatcher.stop();
+    assert_eq!(1, dispatcher.join().ok().unwrap());
+    assert_eq!(vec!["pre_start", "post_stop"], *events.lock().unwrap());
+  }
+}
@@ -0,0 +1,173 @@
+//!
+//! Messages that could not be delivered.
+//!
+//! A dispatcher publishes a dead letter when no actor accepts a message, or
+//! when the actor it was addressed to has stopped. Subscribers get every
+//! dead letter published after they subscribed, and the dispatcher logs a
+//! warning at most once per `WARN_INTERVAL`.
+//!
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::time::{Duration, Instant};
+
+use react::MsgTrait;
+use react::actor::ActorUri;
+
+/// The shortest time between two warnings about dead letters.
+pub const WARN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadLetterReason {
+  /// The message was published, and no actor accepted it.
+  Unhandled,
+  /// The actor the message was addressed to has stopped.
+  ActorStopped
+}
+
+impl fmt::Display for DeadLetterReason {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      DeadLetterReason::Unhandled => write!(f, "no actor accepted it"),
+      DeadLetterReason::ActorStopped => write!(f, "the actor has stopped")
+    }
+  }
+}
+
+pub struct DeadLetter<M: MsgTrait> {
+  message: Arc<M>,
+  recipient: Option<ActorUri>,
+  reason: DeadLetterReason
+}
+
+impl<M: MsgTrait> DeadLetter<M> {
+  pub fn new(message: Arc<M>, recipient: Option<ActorUri>, reason: DeadLetterReason) -> DeadLetter<M> {
+    DeadLetter {
+      message: message,
+      recipient: recipient,
+      reason: reason
+    }
+  }
+
+  pub fn message(&self) -> &M {
+    &self.message
+  }
+
+  /// The actor the message was addressed to, or `None` for a published
+  /// message.
+  pub fn recipient(&self) -> Option<&ActorUri> {
+    self.recipient.as_ref()
+  }
+
+  pub fn reason(&self) -> DeadLetterReason {
+    self.reason
+  }
+}
+
+impl<M: MsgTrait> Clone for DeadLetter<M> {
+  fn clone(&self) -> DeadLetter<M> {
+    DeadLetter {
+      message: self.message.clone(),
+      recipient: self.recipient.clone(),
+      reason: self.reason
+    }
+  }
+}
+
+struct Warning {
+  last: Option<Instant>,
+  /// Dead letters since the last warning.
+  suppressed: usize
+}
+
+/// The dead-letter stream of a dispatcher.
+pub struct DeadLetters<M: MsgTrait> {
+  subscribers: Mutex<Vec<Sender<DeadLetter<M>>>>,
+  warning: Mutex<Warning>,
+  count: AtomicUsize
+}
+
+impl<M: MsgTrait> DeadLetters<M> {
+  pub fn new() -> DeadLetters<M> {
+    DeadLetters {
+      subscribers: Mutex::new(Vec::new()),
+      warning: Mutex::new(Warning {
+        last: None,
+        suppressed: 0
+      }),
+      count: AtomicUsize::new(0)
+    }
+  }
+
+  /// Returns a receiver of the dead letters published from now on. It is
+  /// unsubscribed when dropped.
+  pub fn subscribe(&self) -> Receiver<DeadLetter<M>> {
+    let (tx, rx) = mpsc::channel();
+    self.subscribers.lock().unwrap().push(tx);
+    rx
+  }
+
+  /// The number of dead letters so far.
+  pub fn count(&self) -> usize {
+    self.count.load(Ordering::SeqCst)
+  }
+
+  pub fn publish(&self, letter: DeadLetter<M>) {
+    self.count.fetch_add(1, Ordering::SeqCst);
+    self.warn(&letter);
+    self.subscribers.lock().unwrap().retain(|tx| tx.send(letter.clone()).is_ok());
+  }
+
+  fn warn(&self, letter: &DeadLetter<M>) {
+    let mut warning = self.warning.lock().unwrap();
+    let now = Instant::now();
+    if warning.last.map_or(false, |last| now.duration_since(last) < WARN_INTERVAL) {
+      warning.suppressed += 1;
+      return;
+    }
+
+    let recipient = letter.recipient().map_or("all actors".to_owned(), |uri| uri.display());
+    if warning.suppressed > 0 {
+      warn!("dead letter to {}: {} ({} more since the last warning)", recipient, letter.reason(),
+            warning.suppressed);
+    } else {
+      warn!("dead letter to {}: {}", recipient, letter.reason());
+    }
+    warning.last = Some(now);
+    warning.suppressed = 0;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use react::actor::ActorUri;
+  use react::dispatcher::tests::Msg;
+  use super::{DeadLetters, DeadLetter, DeadLetterReason};
+
+  #[test]
+  fn test_subscribe() {
+    let letters: DeadLetters<Msg> = DeadLetters::new();
+    let rx1 = letters.subscribe();
+    let rx2 = letters.subscribe();
+    drop(rx2);
+
+    let uri = ActorUri::local("user/0");
+    letters.publish(DeadLetter::new(Arc::new(Msg::Ping(1)), Some(uri.clone()),
+                                    DeadLetterReason::ActorStopped));
+    for _ in 0..10 {
+      letters.publish(DeadLetter::new(Arc::new(Msg::Ping(2)), None, DeadLetterReason::Unhandled));
+    }
+
+    let letter = rx1.recv().unwrap();
+    assert_eq!(&Msg::Ping(1), letter.message());
+    assert_eq!(Some(&uri), letter.recipient());
+    assert_eq!(DeadLetterReason::ActorStopped, letter.reason());
+    assert_eq!(10, rx1.try_iter().count());
+    assert_eq!(11, letters.count());
+    assert_eq!(1, letters.subscribers.lock().unwrap().len());
+  }
+}
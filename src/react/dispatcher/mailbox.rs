@@ -0,0 +1,256 @@
+//!
+//! Message queues with an optional capacity.
+//!
+//! A bounded mailbox applies its `Overflow` policy when a message arrives
+//! while it is full. Messages dropped by the policy are counted. The
+//! threads of a dispatcher never block on a full mailbox, since they may be
+//! the only ones to make room in it.
+//!
+
+use std::cell::Cell;
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use crossbeam::sync::MsQueue;
+
+use err::{ReactErr, ReactErrKind};
+
+/// What a full mailbox does with a new message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+  /// Block the sender until there is room. A sender that takes messages
+  /// out of mailboxes itself, like an actor, goes past the capacity instead.
+  Block,
+  /// Fail the send with a `MailboxFull` error.
+  Fail,
+  /// Drop the new message.
+  DropNewest,
+  /// Drop the oldest queued message to make room for the new one.
+  DropOldest
+}
+
+thread_local! {
+  /// Set on the threads that take messages out of mailboxes.
+  static CONSUMER: Cell<bool> = Cell::new(false)
+}
+
+/// Marks the current thread as one that takes messages out of mailboxes.
+/// A full `Block` mailbox takes its messages past the capacity instead of
+/// blocking it.
+pub fn mark_consumer() {
+  CONSUMER.with(|c| c.set(true));
+}
+
+fn is_consumer() -> bool {
+  CONSUMER.with(|c| c.get())
+}
+
+pub struct Mailbox<T> {
+  queue: MsQueue<T>,
+  len: AtomicUsize,
+  capacity: Option<usize>,
+  overflow: Overflow,
+  dropped: AtomicUsize,
+  closed: AtomicBool,
+  room: Mutex<()>,
+  room_cond: Condvar
+}
+
+impl<T: Send> Mailbox<T> {
+  pub fn unbounded() -> Mailbox<T> {
+    Mailbox::new(None, Overflow::Fail)
+  }
+
+  pub fn bounded(capacity: usize, overflow: Overflow) -> Mailbox<T> {
+    assert!(capacity > 0, "a bounded mailbox needs room for one message");
+    Mailbox::new(Some(capacity), overflow)
+  }
+
+  fn new(capacity: Option<usize>, overflow: Overflow) -> Mailbox<T> {
+    Mailbox {
+      queue: MsQueue::new(),
+      len: AtomicUsize::new(0),
+      capacity: capacity,
+      overflow: overflow,
+      dropped: AtomicUsize::new(0),
+      closed: AtomicBool::new(false),
+      room: Mutex::new(()),
+      room_cond: Condvar::new()
+    }
+  }
+
+  pub fn push(&self, t: T) -> Result<(), ReactErr> {
+    let capacity = match self.capacity {
+      Some(capacity) => capacity,
+      None => {
+        self.len.fetch_add(1, Ordering::SeqCst);
+        self.queue.push(t);
+        return Ok(());
+      }
+    };
+
+    loop {
+      if self.reserve(capacity) {
+        self.queue.push(t);
+        return Ok(());
+      }
+
+      match self.overflow {
+        Overflow::Fail => return Err(ReactErr::new(ReactErrKind::MailboxFull)),
+        Overflow::DropNewest => {
+          self.dropped.fetch_add(1, Ordering::SeqCst);
+          return Ok(());
+        }
+        Overflow::DropOldest => {
+          if self.pop().is_some() {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+          }
+        }
+        Overflow::Block if is_consumer() => {
+          self.len.fetch_add(1, Ordering::SeqCst);
+          self.queue.push(t);
+          return Ok(());
+        }
+        Overflow::Block => {
+          // nobody takes messages out of a closed mailbox
+          if self.closed.load(Ordering::SeqCst) {
+            return Err(ReactErr::new(ReactErrKind::MailboxFull));
+          }
+
+          let room = self.room.lock().unwrap();
+          if self.len.load(Ordering::SeqCst) >= capacity {
+            // the timeout covers a wake-up that slips in before the wait
+            let _ = self.room_cond.wait_timeout(room, Duration::from_millis(10)).unwrap();
+          }
+        }
+      }
+    }
+  }
+
+  /// Takes a slot if the mailbox is not full.
+  fn reserve(&self, capacity: usize) -> bool {
+    let mut len = self.len.load(Ordering::SeqCst);
+    while len < capacity {
+      match self.len.compare_exchange(len, len + 1, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => return true,
+        Err(prev) => len = prev
+      }
+    }
+    false
+  }
+
+  pub fn pop(&self) -> Option<T> {
+    let t = self.queue.try_pop();
+    if t.is_some() {
+      self.len.fetch_sub(1, Ordering::SeqCst);
+      if self.overflow == Overflow::Block {
+        let _room = self.room.lock().unwrap();
+        self.room_cond.notify_one();
+      }
+    }
+    t
+  }
+
+  pub fn len(&self) -> usize {
+    self.len.load(Ordering::SeqCst)
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.queue.is_empty()
+  }
+
+  /// The number of messages dropped by the overflow policy.
+  pub fn dropped(&self) -> usize {
+    self.dropped.load(Ordering::SeqCst)
+  }
+
+  /// Releases the senders blocked on a full mailbox once nobody is going to
+  /// take messages out of it anymore.
+  pub fn close(&self) {
+    self.closed.store(true, Ordering::SeqCst);
+    let _room = self.room.lock().unwrap();
+    self.room_cond.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+
+  use err::ReactErrKind;
+  use super::{Mailbox, Overflow};
+
+  fn drain(mailbox: &Mailbox<i32>) -> Vec<i32> {
+    let mut v = Vec::new();
+    while let Some(t) = mailbox.pop() {
+      v.push(t);
+    }
+    v
+  }
+
+  #[test]
+  fn test_unbounded() {
+    let mailbox = Mailbox::unbounded();
+    for i in 0..100 {
+      mailbox.push(i).ok().unwrap();
+    }
+    assert_eq!(100, mailbox.len());
+    assert_eq!((0..100).collect::<Vec<i32>>(), drain(&mailbox));
+    assert!(mailbox.is_empty());
+  }
+
+  #[test]
+  fn test_fail() {
+    let mailbox = Mailbox::bounded(2, Overflow::Fail);
+    mailbox.push(1).ok().unwrap();
+    mailbox.push(2).ok().unwrap();
+    assert_eq!(&ReactErrKind::MailboxFull, mailbox.push(3).err().unwrap().kind());
+    assert_eq!(vec![1, 2], drain(&mailbox));
+    assert_eq!(0, mailbox.dropped());
+  }
+
+  #[test]
+  fn test_drop_newest() {
+    let mailbox = Mailbox::bounded(2, Overflow::DropNewest);
+    for i in 1..5 {
+      mailbox.push(i).ok().unwrap();
+    }
+    assert_eq!(vec![1, 2], drain(&mailbox));
+    assert_eq!(2, mailbox.dropped());
+  }
+
+  #[test]
+  fn test_drop_oldest() {
+    let mailbox = Mailbox::bounded(2, Overflow::DropOldest);
+    for i in 1..5 {
+      mailbox.push(i).ok().unwrap();
+    }
+    assert_eq!(vec![3, 4], drain(&mailbox));
+    assert_eq!(2, mailbox.dropped());
+  }
+
+  #[test]
+  fn test_block() {
+    let mailbox = Arc::new(Mailbox::bounded(1, Overflow::Block));
+    mailbox.push(1).ok().unwrap();
+
+    let sender = mailbox.clone();
+    let t = thread::spawn(move || sender.push(2).ok().unwrap());
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(1, mailbox.len());
+
+    assert_eq!(Some(1), mailbox.pop());
+    t.join().unwrap();
+    assert_eq!(vec![2], drain(&mailbox));
+  }
+
+  #[test]
+  fn test_block_closed() {
+    let mailbox = Mailbox::bounded(1, Overflow::Block);
+    mailbox.push(1).ok().unwrap();
+    mailbox.close();
+    assert_eq!(&ReactErrKind::MailboxFull, mailbox.push(2).err().unwrap().kind());
+  }
+}
@@ -0,0 +1,1441 @@
+pub mod calling;
+pub mod dead_letter;
+pub mod mailbox;
+pub mod pool;
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle, Thread};
+use std::time::{Duration, Instant};
+
+use err::{ReactErr, ReactErrKind};
+use super::{MsgTrait, Error, Predicate};
+use super::actor::{ActorUri, ActorRef, Actor, Courier, Stashed};
+use super::metrics::{ActorStats, ActorSnapshot, DispatcherStats, DispatcherSnapshot};
+use super::reply::{self, ReplyTo, ReplyHandle};
+use super::supervision::{Directive, Factory, Supervisor};
+
+pub use self::mailbox::{Mailbox, Overflow};
+pub use self::calling::CallingThreadDispatcher;
+pub use self::dead_letter::{DeadLetter, DeadLetters, DeadLetterReason};
+pub use self::pool::PoolDispatcher;
+
+pub struct MessageFrame<M: MsgTrait> {  
+  /// `None` publishes the message to every actor whose filter accepts it.
+  to: Option<ActorUri>,
+  msg: MessageBase<M>,
+  /// Counts the frame in the mailbox length of its actors while it waits.
+  queued: Option<Queued>
+}
+
+/// Increments a counter of waiting frames, and decrements it when dropped,
+/// whether the frame is delivered or discarded by its mailbox.
+struct Queued(Arc<AtomicUsize>);
+
+impl Queued {
+  fn new(count: &Arc<AtomicUsize>) -> Queued {
+    count.fetch_add(1, Ordering::SeqCst);
+    Queued(count.clone())
+  }
+}
+
+impl Drop for Queued {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+pub enum MessageBase<M: MsgTrait> {
+  OneWay(M),
+  Ask(M, ReplyTo<M>)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+  /// Stop after the messages being handled. Queued messages are dropped.
+  Immediate,
+  /// Keep handling queued messages until none is left or the deadline
+  /// passes, then drop the rest.
+  Drain(Duration)
+}
+
+pub trait Dispatcher<M: MsgTrait, E: Error>: Send + Sync {
+  fn stop(&mut self) {
+    self.shutdown(Shutdown::Immediate);
+  }
+
+  fn shutdown(&mut self, mode: Shutdown);
+  /// Waits for the dispatcher to stop, and returns the number of messages
+  /// that were dropped without being handled.
+  fn join(self) -> Result<usize, E>;
+
+  /// Sends `m` to every actor whose filter accepts it. Fails only when a
+  /// bounded mailbox refuses the message.
+  fn send(&self, m: M) -> Result<(), ReactErr>;
+  /// Sends `m` and returns a handle for the first reply to it. The reply
+  /// fails with a timeout error if nobody answers within `timeout`, or with
+  /// the error of `send` if the ask could not be queued.
+  fn ask(&self, m: M, timeout: Duration) -> ReplyHandle<M>;
+  /// Sends `m` to the actor at `to` only, regardless of its filter. A
+  /// message to an actor that is not subscribed goes to the dead letters.
+  fn send_to(&self, to: &ActorUri, m: M) -> Result<(), ReactErr> {
+    self.forward(to, m, None)
+  }
+  /// Sends `m` to the actor at `to` together with the reply channel of an
+  /// ask, so that the actor answers the original sender.
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr>;
+
+  /// Subscribes an actor and returns a handle to it.
+  fn subscribe(&self, actor: Box<Actor<M, E>>, filter: Option<Box<Predicate<M>>>) -> ActorRef<M> {
+    self.subscribe_supervised(actor, filter, Supervisor::resume())
+  }
+
+  /// Subscribes an actor whose failures are handled by `supervisor`.
+  fn subscribe_supervised(&self, actor: Box<Actor<M, E>>, filter: Option<Box<Predicate<M>>>,
+                          supervisor: Supervisor<M, E>) -> ActorRef<M>;
+
+  /// Detaches the actor at `uri` and its children. Its queued messages are
+  /// dropped, and its `post_stop` runs once the message it may be handling
+  /// is done.
+  fn unsubscribe(&self, uri: &ActorUri) -> Result<(), ReactErr>;
+
+  /// The number of messages dropped by the overflow policy of full
+  /// mailboxes so far.
+  fn dropped(&self) -> usize;
+
+  /// The number of messages waiting for the actor at `uri`.
+  fn mailbox_len(&self, uri: &ActorUri) -> Result<usize, ReactErr>;
+
+  /// The messages that no actor accepted, or whose actor had stopped.
+  fn dead_letters(&self) -> &DeadLetters<M>;
+
+  /// A snapshot of the counters of the dispatcher and its actors.
+  fn metrics(&self) -> DispatcherSnapshot;
+}
+
+/// Subscribes the children that actors spawn through their context.
+pub trait Spawner<M: MsgTrait, E: Error>: Send + Sync {
+  /// Spawns a child named `name` under the actor at `parent`.
+  fn spawn(&self, parent: &ActorUri, name: &str, actor: Box<Actor<M, E>>,
+           factory: Option<Box<Factory<M, E>>>) -> Result<ActorRef<M>, ReactErr>;
+}
+
+/// Starts a child of `parent` named `name`, which `register` adds to the
+/// dispatcher. The child only accepts the messages sent to it directly.
+pub fn start_child<M, E, F>(parent: &Arc<ActorPair<M, E>>, name: &str, actor: Box<Actor<M, E>>,
+                            factory: Option<Box<Factory<M, E>>>, register: F) -> Result<ActorRef<M>, ReactErr>
+    where M: MsgTrait, E: Error, F: FnOnce(Arc<ActorPair<M, E>>) {
+  if name.is_empty() || name.contains('/') {
+    return Err(ReactErr::new(ReactErrKind::InvalidName(name.to_owned())));
+  }
+  if parent.is_stopped() {
+    return Err(unknown_actor(parent.uri()));
+  }
+  let actor_ref = parent.actor_ref.child(name);
+  if parent.children.lock().unwrap().iter().any(|c| c.uri() == actor_ref.path()) {
+    return Err(ReactErr::new(ReactErrKind::NameTaken(actor_ref.path().display())));
+  }
+
+  let supervisor = match factory {
+    Some(factory) => Supervisor::resume().with_factory(factory),
+    None => Supervisor::resume()
+  };
+  let child = ActorPair::with_parent(actor_ref.clone(), actor, Some(Box::new(|_: &M| false)), supervisor,
+                                     parent.spawner.clone(), parent.dead_letters.clone(), Some(parent));
+  child.start(|child| {
+    parent.children.lock().unwrap().push(child.clone());
+    register(child);
+  });
+  Ok(actor_ref)
+}
+
+/// Assigns the next unique address of a dispatcher.
+pub fn next_uri(seq: &AtomicUsize) -> ActorUri {
+  ActorUri::local(&format!("user/{}", seq.fetch_add(1, Ordering::SeqCst)))
+}
+
+pub fn unknown_actor(uri: &ActorUri) -> ReactErr {
+  ReactErr::new(ReactErrKind::UnknownActor(uri.display()))
+}
+
+/// Publishes `m`, sent to an actor that has stopped or never was, as a dead
+/// letter, and returns the error for its sender.
+pub fn stopped_actor<M: MsgTrait>(dead_letters: &DeadLetters<M>, to: &ActorUri, m: M) -> ReactErr {
+  dead_letters.publish(DeadLetter::new(Arc::new(m), Some(to.clone()), DeadLetterReason::ActorStopped));
+  unknown_actor(to)
+}
+
+/// A shutdown request shared by a dispatcher and its threads.
+pub struct StopFlag {
+  requested: AtomicBool,
+  deadline: Mutex<Option<Instant>>
+}
+
+impl StopFlag {
+  pub fn new() -> StopFlag {
+    StopFlag {
+      requested: AtomicBool::new(false),
+      deadline: Mutex::new(None)
+    }
+  }
+
+  pub fn request(&self, mode: Shutdown) {
+    let deadline = match mode {
+      Shutdown::Immediate => Instant::now(),
+      Shutdown::Drain(timeout) => Instant::now() + timeout
+    };
+
+    let mut current = self.deadline.lock().unwrap();
+    // a later request can only bring the deadline forward
+    if current.map_or(true, |d| deadline < d) {
+      *current = Some(deadline);
+    }
+    self.requested.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_requested(&self) -> bool {
+    self.requested.load(Ordering::SeqCst)
+  }
+
+  /// Whether no more messages should be handled.
+  pub fn is_expired(&self) -> bool {
+    self.is_requested() &&
+      self.deadline.lock().unwrap().map_or(false, |d| Instant::now() >= d)
+  }
+}
+
+pub struct ActorPair<M: MsgTrait, E: Error> {  
+  actor_ref: ActorRef<M>,
+  actor: Mutex<Box<Actor<M, E>>>,
+  filter: Option<Box<Predicate<M>>>,
+  supervisor: Mutex<Supervisor<M, E>>,
+  /// The actor that spawned this one, which supervises it.
+  parent: Option<Weak<ActorPair<M, E>>>,
+  children: Mutex<Vec<Arc<ActorPair<M, E>>>>,
+  /// The spawner of the dispatcher, for the context of the actor.
+  spawner: Arc<Any + Send + Sync>,
+  /// Where the stashed messages go when the actor stops.
+  dead_letters: Arc<DeadLetters<M>>,
+  stopped: AtomicBool,
+  finished: AtomicBool,
+  /// The frames addressed to this actor alone that wait in a shared queue.
+  queued: Arc<AtomicUsize>,
+  stats: ActorStats
+}
+
+impl<M: MsgTrait, E: Error> ActorPair<M, E> {
+  pub fn new(actor_ref: ActorRef<M>, actor: Box<Actor<M, E>>, filter: Option<Box<Predicate<M>>>,
+             supervisor: Supervisor<M, E>, spawner: Arc<Spawner<M, E>>,
+             dead_letters: Arc<DeadLetters<M>>) -> ActorPair<M, E> {
+    ActorPair::with_parent(actor_ref, actor, filter, supervisor, Arc::new(spawner), dead_letters, None)
+  }
+
+  fn with_parent(actor_ref: ActorRef<M>, actor: Box<Actor<M, E>>, filter: Option<Box<Predicate<M>>>,
+                 supervisor: Supervisor<M, E>, spawner: Arc<Any + Send + Sync>,
+                 dead_letters: Arc<DeadLetters<M>>, parent: Option<&Arc<ActorPair<M, E>>>) -> ActorPair<M, E> {
+    let pair = ActorPair {
+      actor_ref: actor_ref,
+      actor: Mutex::new(actor),
+      filter: filter,
+      supervisor: Mutex::new(supervisor),
+      parent: parent.map(Arc::downgrade),
+      children: Mutex::new(Vec::new()),
+      spawner: spawner,
+      dead_letters: dead_letters,
+      stopped: AtomicBool::new(false),
+      finished: AtomicBool::new(false),
+      queued: Arc::new(AtomicUsize::new(0)),
+      stats: ActorStats::new()
+    };
+    pair.attach(&**pair.actor.lock().unwrap());
+    pair
+  }
+
+  /// Calls `register` to add the pair to its dispatcher, then `pre_start`.
+  /// The actor is locked meanwhile, so that it can spawn children from
+  /// `pre_start`, but no message reaches it before.
+  pub fn start<F: FnOnce(Arc<ActorPair<M, E>>)>(self, register: F) -> Arc<ActorPair<M, E>> {
+    let pair = Arc::new(self);
+    let started = {
+      let mut actor = pair.actor.lock().unwrap();
+      register(pair.clone());
+      pair.catch("pre_start", || actor.pre_start()).is_ok()
+    };
+    if !started {
+      // an actor that cannot start leaves the dispatcher right away
+      pair.stop();
+      let _ = pair.actor_ref.stop();
+    }
+    pair
+  }
+
+  fn attach(&self, actor: &Actor<M, E>) {
+    let parent = self.parent().map(|p| p.actor_ref.clone());
+    actor.context().attach(self.actor_ref.clone(), parent, self.spawner.clone());
+  }
+
+  fn parent(&self) -> Option<Arc<ActorPair<M, E>>> {
+    self.parent.as_ref().and_then(|p| p.upgrade())
+  }
+
+  pub fn accept(&self, m: &M) -> bool {
+    !self.is_stopped() && (self.filter.is_none() || self.filter.as_ref().unwrap()(m))
+  } 
+
+  pub fn uri(&self) -> &ActorUri {
+    self.actor_ref.path()
+  }
+
+  pub fn actor_ref(&self) -> &ActorRef<M> {
+    &self.actor_ref
+  }
+
+  pub fn is_stopped(&self) -> bool {
+    self.stopped.load(Ordering::SeqCst)
+  }
+
+  pub fn snapshot(&self) -> ActorSnapshot {
+    self.stats.snapshot(self.actor_ref.path())
+  }
+
+  /// Stops the actor and its children. If the actor is busy, which includes
+  /// stopping itself from `on_receive`, `post_stop` is left to `receive`.
+  pub fn stop(&self) {
+    self.stopped.store(true, Ordering::SeqCst);
+    self.stop_children();
+    if let Ok(mut actor) = self.actor.try_lock() {
+      self.finish(&mut actor);
+    }
+  }
+
+  /// Stops the children, each with its own subtree, and unsubscribes them
+  /// from the dispatcher.
+  fn stop_children(&self) {
+    let children: Vec<_> = self.children.lock().unwrap().drain(..).collect();
+    for child in children {
+      child.stop();
+      // already gone when the dispatcher is shutting down
+      let _ = child.actor_ref.stop();
+    }
+  }
+
+  fn finish(&self, actor: &mut Box<Actor<M, E>>) {
+    if !self.finished.swap(true, Ordering::SeqCst) {
+      self.stop_children();
+      self.abandon(actor.context().take_stash());
+      let _ = self.catch("post_stop", || actor.post_stop());
+      if let Some(parent) = self.parent() {
+        parent.children.lock().unwrap().retain(|c| c.uri() != self.uri());
+      }
+    }
+  }
+
+  /// Hands `m` to the actor, and returns whether it was still there to
+  /// handle it. A failure is resolved by the parent or the supervisor, and
+  /// only an escalated one is returned.
+  pub fn receive(&self, m: &Arc<M>, reply_to: Option<&ReplyTo<M>>) -> Result<bool, E> {
+    let res = self.invoke(m, reply_to);
+
+    // `stop` may have been called while the actor was busy
+    if self.is_stopped() {
+      self.finish(&mut self.actor.lock().unwrap());
+    }
+    res
+  }
+
+  fn invoke(&self, m: &Arc<M>, reply_to: Option<&ReplyTo<M>>) -> Result<bool, E> {
+    let mut actor = self.actor.lock().unwrap();
+    // messages may still be queued for an actor that was stopped
+    if self.is_stopped() {
+      return Ok(false);
+    }
+
+    let mut pending = VecDeque::new();
+    pending.push_back((m.clone(), reply_to.cloned()));
+    while let Some((m, reply_to)) = pending.pop_front() {
+      if self.is_stopped() {
+        pending.push_front((m, reply_to));
+        break;
+      }
+      if let Err(e) = self.handle(&mut actor, m, reply_to) {
+        self.abandon(pending);
+        return Err(e);
+      }
+
+      // unstashed messages are handled before the next queued one
+      for stashed in actor.context().take_unstashed().into_iter().rev() {
+        pending.push_front(stashed);
+      }
+    }
+    // an actor stopped while unstashing leaves the rest
+    self.abandon(pending);
+    Ok(true)
+  }
+
+  /// Publishes stashed messages that the actor is not going to handle.
+  fn abandon<I: IntoIterator<Item = Stashed<M>>>(&self, stashed: I) {
+    for (m, _) in stashed {
+      self.dead_letters.publish(DeadLetter::new(m, Some(self.uri().clone()), DeadLetterReason::ActorStopped));
+    }
+  }
+
+  fn handle(&self, actor: &mut Box<Actor<M, E>>, m: Arc<M>, reply_to: Option<ReplyTo<M>>) -> Result<(), E> {
+    actor.context().set_current(Some(m.clone()), reply_to);
+    let start = Instant::now();
+    // a panic only fails this actor, and the dispatcher goes on
+    let res = match self.catch("on_receive", || actor.on_receive(&m)) {
+      Ok(res) => res,
+      Err(message) => Err(E::from_panic(message))
+    };
+    self.stats.record(start.elapsed(), res.is_err());
+    actor.context().set_current(None, None);
+
+    match res {
+      Ok(()) => Ok(()),
+      Err(e) => self.fail(actor, e)
+    }
+  }
+
+  /// Handles a failure of the actor. The parent decides for its children,
+  /// and an escalation fails the parent in turn, up to the dispatcher.
+  fn fail(&self, actor: &mut Box<Actor<M, E>>, e: E) -> Result<(), E> {
+    let parent = self.parent();
+    // the guard of the parent is released before the child is handled. It
+    // is taken with the child locked, see `Actor::supervise_child`. A parent
+    // or a decider that panics stops the child.
+    let decided = parent.as_ref().map(|p| {
+      let mut parent_actor = p.actor.lock().unwrap();
+      self.catch("supervise_child", || parent_actor.supervise_child(self.uri(), &e)).unwrap_or(Directive::Stop)
+    });
+
+    let supervisor = &mut *self.supervisor.lock().unwrap();
+    let directive = match decided {
+      Some(directive) => supervisor.limit(directive),
+      None => self.catch("its decider", || supervisor.decide(&e)).unwrap_or(Directive::Stop)
+    };
+    match directive {
+      Directive::Resume => {}
+      Directive::Restart => {
+        match self.catch("its factory", || supervisor.new_actor()) {
+          Ok(Some(fresh)) => self.restart(actor, fresh, &e),
+          Ok(None) => {}
+          Err(_) => self.stopped.store(true, Ordering::SeqCst)
+        }
+      }
+      Directive::Stop => self.stopped.store(true, Ordering::SeqCst),
+      Directive::Escalate => {
+        return match parent {
+          Some(parent) => {
+            let res = parent.fail(&mut parent.actor.lock().unwrap(), e);
+            // a parent stopped in turn leaves the dispatcher with its subtree
+            if parent.is_stopped() {
+              let _ = parent.actor_ref.stop();
+            }
+            res
+          }
+          None => Err(e)
+        };
+      }
+    }
+    Ok(())
+  }
+
+  /// Replaces the actor with `fresh`, which gets the stash of the old one.
+  /// A fresh instance that panics in `pre_start` is stopped.
+  fn restart(&self, actor: &mut Box<Actor<M, E>>, fresh: Box<Actor<M, E>>, e: &E) {
+    // the old instance goes either way
+    let _ = self.catch("pre_restart", || actor.pre_restart(e));
+    // the new instance spawns the children it needs again
+    self.stop_children();
+    self.attach(&*fresh);
+    let stashed = actor.context().take_stash();
+    *actor = fresh;
+    if self.catch("pre_start", || actor.pre_start()).is_err() {
+      self.stopped.store(true, Ordering::SeqCst);
+    }
+    actor.context().inherit_stash(stashed);
+  }
+
+  /// Runs code of the actor or of its supervisors, and returns the message
+  /// of a panic. The caller keeps its guards, so that no mutex is poisoned.
+  fn catch<T, F: FnOnce() -> T>(&self, what: &str, f: F) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+      let message = panic_message(&*payload);
+      error!("{} panicked in {}: {}", self.uri().display(), what, message);
+      message
+    })
+  }
+}
+
+unsafe impl<M: MsgTrait, E: Error> Sync for ActorPair<M, E> {}
+unsafe impl<M: MsgTrait, E: Error> Send for ActorPair<M, E> {}
+
+/// Delivers the messages of the `ActorRef`s of an `AsyncDispatcher`.
+struct AsyncCourier<M: MsgTrait, E: Error> {
+  actors: Weak<RwLock<Vec<Arc<ActorPair<M, E>>>>>,
+  queue: Weak<Mailbox<MessageFrame<M>>>,
+  dead_letters: Arc<DeadLetters<M>>,
+  waker: Thread
+}
+
+unsafe impl<M: MsgTrait, E: Error> Sync for AsyncCourier<M, E> {}
+unsafe impl<M: MsgTrait, E: Error> Send for AsyncCourier<M, E> {}
+
+impl<M: MsgTrait, E: Error> Courier<M> for AsyncCourier<M, E> {
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    let (actors, queue) = match (self.actors.upgrade(), self.queue.upgrade()) {
+      (Some(actors), Some(queue)) => (actors, queue),
+      _ => return Err(unknown_actor(to))
+    };
+    let queued = match actors.read().unwrap().iter().find(|p| p.uri() == to && !p.is_stopped()) {
+      Some(pair) => Queued::new(&pair.queued),
+      None => return Err(stopped_actor(&self.dead_letters, to, m))
+    };
+
+    let msg = match reply_to {
+      Some(reply_to) => MessageBase::Ask(m, reply_to),
+      None => MessageBase::OneWay(m)
+    };
+    let res = queue.push(MessageFrame {
+      to: Some(to.clone()),
+      msg: msg,
+      queued: Some(queued)
+    });
+    self.waker.unpark();
+    res
+  }
+
+  fn stop(&self, to: &ActorUri) -> Result<(), ReactErr> {
+    match self.actors.upgrade().and_then(|actors| remove(&actors, to)) {
+      Some(pair) => {
+        pair.stop();
+        Ok(())
+      }
+      None => Err(unknown_actor(to))
+    }
+  }
+}
+
+impl<M: MsgTrait, E: Error> Spawner<M, E> for AsyncCourier<M, E> {
+  fn spawn(&self, parent: &ActorUri, name: &str, actor: Box<Actor<M, E>>,
+           factory: Option<Box<Factory<M, E>>>) -> Result<ActorRef<M>, ReactErr> {
+    let actors = match self.actors.upgrade() {
+      Some(actors) => actors,
+      None => return Err(unknown_actor(parent))
+    };
+    let parent = match actors.read().unwrap().iter().find(|p| p.uri() == parent) {
+      Some(pair) => pair.clone(),
+      None => return Err(unknown_actor(parent))
+    };
+
+    start_child(&parent, name, actor, factory, |child| actors.write().unwrap().push(child))
+  }
+}
+
+pub struct AsyncDispatcher<M: MsgTrait, E: Error> {
+  actors: Arc<RwLock<Vec<Arc<ActorPair<M, E>>>>>,
+  queue: Arc<Mailbox<MessageFrame<M>>>,
+  courier: Arc<Courier<M>>,
+  spawner: Arc<Spawner<M, E>>,
+  dead_letters: Arc<DeadLetters<M>>,
+  stats: Arc<DispatcherStats>,
+  /// The broadcasts that wait in the queue.
+  broadcasts: Arc<AtomicUsize>,
+  seq: AtomicUsize,
+  stop: Arc<StopFlag>,
+  waker: Thread,
+  thread: Option<JoinHandle<Result<(), E>>>,
+}
+
+unsafe impl<M: MsgTrait, E: Error> Sync for AsyncDispatcher<M, E> {}
+unsafe impl<M: MsgTrait, E: Error> Send for AsyncDispatcher<M, E> {}
+
+impl<M: MsgTrait, E: Error> AsyncDispatcher<M, E> {
+  
+  pub fn new() -> AsyncDispatcher<M, E> {
+    AsyncDispatcher::with_mailbox(Mailbox::unbounded())
+  }
+
+  /// Creates a dispatcher whose queue holds at most `capacity` messages.
+  pub fn bounded(capacity: usize, overflow: Overflow) -> AsyncDispatcher<M, E> {
+    AsyncDispatcher::with_mailbox(Mailbox::bounded(capacity, overflow))
+  }
+
+  fn with_mailbox(queue: Mailbox<MessageFrame<M>>) -> AsyncDispatcher<M, E> {
+    let actors = Arc::new(RwLock::new(Vec::new()));
+    let queue = Arc::new(queue);
+    let dead_letters = Arc::new(DeadLetters::new());
+    let stats = Arc::new(DispatcherStats::new());
+    let stop = Arc::new(StopFlag::new());
+    let thread = run(stop.clone(), queue.clone(), actors.clone(), dead_letters.clone(), stats.clone());
+    let courier = Arc::new(AsyncCourier {
+      actors: Arc::downgrade(&actors),
+      queue: Arc::downgrade(&queue),
+      dead_letters: dead_letters.clone(),
+      waker: thread.thread().clone()
+    });
+
+    AsyncDispatcher {
+      actors: actors,
+      queue: queue,
+      courier: courier.clone(),
+      spawner: courier,
+      dead_letters: dead_letters,
+      stats: stats,
+      broadcasts: Arc::new(AtomicUsize::new(0)),
+      seq: AtomicUsize::new(0),
+      stop: stop,
+      waker: thread.thread().clone(),
+      thread: Some(thread),
+    }
+  }  
+
+  /// Queues a message for every actor whose filter accepts it.
+  fn push(&self, msg: MessageBase<M>) -> Result<(), ReactErr> {
+    let res = self.queue.push(MessageFrame {
+      to: None,
+      msg: msg,
+      queued: Some(Queued::new(&self.broadcasts))
+    });
+    self.waker.unpark();
+    res
+  }
+}
+
+impl<M: MsgTrait, E: Error> Dispatcher<M, E> for AsyncDispatcher<M, E> {
+  fn shutdown(&mut self, mode: Shutdown) {
+    debug!("shutdown enter");
+    self.stop.request(mode);
+    self.waker.unpark();
+    debug!("shutdown leave");
+  }
+
+  fn join(mut self) -> Result<usize, E> {
+    let res = match self.thread.take().unwrap().join() {
+      Ok(res) => res,
+      Err(payload) => Err(E::from_panic(panic_message(&*payload)))
+    };
+
+    let mut dropped = 0;
+    while let Some(_) = self.queue.pop() {
+      dropped += 1;
+    }
+    if dropped > 0 {
+      info!("{} messages were dropped at shutdown", dropped);
+    }
+    res.map(|_| dropped)
+  }
+
+  fn send(&self, m: M) -> Result<(), ReactErr> {
+    self.push(MessageBase::OneWay(m))
+  }
+
+  fn ask(&self, m: M, timeout: Duration) -> ReplyHandle<M> {
+    let (reply_to, handle) = reply::channel(timeout);
+    match self.push(MessageBase::Ask(m, reply_to)) {
+      Ok(()) => handle,
+      Err(e) => handle.fail(e)
+    }
+  }
+
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    self.courier.forward(to, m, reply_to)
+  }
+
+  fn subscribe_supervised(&self, actor: Box<Actor<M, E>>, filter: Option<Box<Predicate<M>>>,
+                          supervisor: Supervisor<M, E>) -> ActorRef<M> {
+    let actor_ref = ActorRef::new(next_uri(&self.seq), self.courier.clone());
+    let pair = ActorPair::new(actor_ref.clone(), actor, filter, supervisor, self.spawner.clone(),
+                              self.dead_letters.clone());
+    pair.start(|pair| self.actors.write().unwrap().push(pair));
+    actor_ref
+  }
+
+  fn unsubscribe(&self, uri: &ActorUri) -> Result<(), ReactErr> {
+    self.courier.stop(uri)
+  }
+
+  fn dropped(&self) -> usize {
+    self.queue.dropped()
+  }
+
+  /// All actors share one queue here, so this counts the frames of that
+  /// queue addressed to the actor, and the broadcasts it may accept.
+  fn mailbox_len(&self, uri: &ActorUri) -> Result<usize, ReactErr> {
+    match self.actors.read().unwrap().iter().find(|p| p.uri() == uri) {
+      Some(pair) => Ok(pair.queued.load(Ordering::SeqCst) + self.broadcasts.load(Ordering::SeqCst)),
+      None => Err(unknown_actor(uri))
+    }
+  }
+
+  fn dead_letters(&self) -> &DeadLetters<M> {
+    &self.dead_letters
+  }
+
+  fn metrics(&self) -> DispatcherSnapshot {
+    let actors = self.actors.read().unwrap().iter().map(|p| p.snapshot()).collect();
+    self.stats.snapshot(self.queue.len(), actors)
+  }
+}
+
+impl<M: MsgTrait, E: Error> Drop for AsyncDispatcher<M, E> {
+  fn drop(&mut self) {
+    if let Some(thread) = self.thread.take() {
+      self.stop.request(Shutdown::Immediate);
+      self.waker.unpark();
+      if thread.join().is_err() {
+        error!("the dispatcher thread panicked");
+      }
+    }
+  }
+}
+
+/// The message of a panic, which is a `&str` or a `String` unless the
+/// panic carried another payload.
+pub fn panic_message(payload: &(Any + Send)) -> String {
+  match payload.downcast_ref::<&str>() {
+    Some(s) => s.to_string(),
+    None => match payload.downcast_ref::<String>() {
+      Some(s) => s.clone(),
+      None => "a panic without a message".to_owned()
+    }
+  }
+}
+
+fn remove<M, E>(actors: &RwLock<Vec<Arc<ActorPair<M, E>>>>, uri: &ActorUri)
+    -> Option<Arc<ActorPair<M, E>>> where M: MsgTrait, E: Error {
+  let mut actors = actors.write().unwrap();
+  match actors.iter().position(|p| p.uri() == uri) {
+    Some(idx) => Some(actors.remove(idx)),
+    None => None
+  }
+}
+
+/// Hands a frame to its actors, and returns the error of an actor whose
+/// supervisor escalated it. A message that no actor handled is published to
+/// `dead_letters`.
+fn deliver<M, E>(frame: MessageFrame<M>, actors: &RwLock<Vec<Arc<ActorPair<M, E>>>>,
+                 dead_letters: &DeadLetters<M>, stats: &DispatcherStats) -> Result<(), E>
+    where M: MsgTrait, E: Error {
+  let MessageFrame { to, msg, queued } = frame;
+  // no longer waiting
+  drop(queued);
+  let (m, reply_to) = match msg {
+    MessageBase::OneWay(m) => (Arc::new(m), None),
+    MessageBase::Ask(m, reply_to) => (Arc::new(m), Some(reply_to))
+  };
+
+  // the lock is released before the actors run, so that they can
+  // subscribe or send without a deadlock.
+  let targets: Vec<Arc<ActorPair<M, E>>> = match to {
+    Some(ref to) => actors.read().unwrap().iter()
+      .filter(|p| p.uri() == to).cloned().collect(),
+    None => actors.read().unwrap().iter()
+      .filter(|p| p.accept(&m)).cloned().collect()
+  };
+
+  let mut handled = false;
+  for pair in targets {
+    match pair.receive(&m, reply_to.as_ref()) {
+      Ok(true) => {
+        stats.record_message();
+        handled = true;
+      }
+      Ok(false) => {}
+      Err(e) => {
+        stats.record_message();
+        return Err(e);
+      }
+    }
+
+    // stopped by its supervisor or by itself
+    if pair.is_stopped() {
+      remove(actors, pair.uri());
+    }
+  }
+
+  if !handled {
+    let reason = match to {
+      Some(_) => DeadLetterReason::ActorStopped,
+      None => DeadLetterReason::Unhandled
+    };
+    dead_letters.publish(DeadLetter::new(m, to, reason));
+  }
+  Ok(())
+}
+
+pub fn run<M, E>(stop: Arc<StopFlag>, queue: Arc<Mailbox<MessageFrame<M>>>,
+    actors: Arc<RwLock<Vec<Arc<ActorPair<M, E>>>>>, dead_letters: Arc<DeadLetters<M>>,
+    stats: Arc<DispatcherStats>) -> JoinHandle<Result<(), E>> where M: MsgTrait, E: Error {
+
+  thread::spawn(move || -> Result<(), E> {
+    mailbox::mark_consumer();
+    let res = dispatch(stop, queue.clone(), actors.clone(), dead_letters, stats);
+    queue.close();
+
+    let stopped: Vec<_> = actors.write().unwrap().drain(..).collect();
+    for pair in stopped {
+      pair.stop();
+    }
+    res
+  })
+}
+
+fn dispatch<M, E>(stop: Arc<StopFlag>, queue: Arc<Mailbox<MessageFrame<M>>>,
+    actors: Arc<RwLock<Vec<Arc<ActorPair<M, E>>>>>, dead_letters: Arc<DeadLetters<M>>,
+    stats: Arc<DispatcherStats>) -> Result<(), E>
+    where M: MsgTrait, E: Error {
+     
+     loop {
+        if stop.is_expired() {
+          break;
+        }
+
+        if let Some(frame) = queue.pop() {
+          deliver(frame, &actors, &dead_letters, &stats)?;
+       } else if stop.is_requested() {
+         // drained before the deadline
+         break;
+       } else {
+         // `push` and `shutdown` unpark this thread. If an unpark races with
+         // the empty check above, `park` returns immediately.
+         let idle = stats.begin_idle();
+         thread::park();
+         stats.end_idle(idle);
+        }
+      }
+
+      Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use err::ReactErrKind;
+  use react::{ActorSystem, MsgTrait, Error};
+  use react::actor::{Actor, ActorContext, ActorUri};
+  use react::reply::ReplyTo;
+  use react::actor::tests::Lifecycle;
+  use react::supervision::{Directive, Supervisor};
+  use super::{Dispatcher, AsyncDispatcher, PoolDispatcher, CallingThreadDispatcher, Shutdown, Overflow,
+              DeadLetterReason};
+
+  #[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq)]
+  pub enum Msg {
+    Ping(u32),
+    Pong(u32),
+    Defer,
+    Fail,
+    WhoAmI,
+    Name(String),
+    Sleep(u64),
+    Ignore
+  }
+
+  impl MsgTrait for Msg {}
+
+  pub enum Err {
+    Fatal,
+    Panicked(String)
+  }
+
+  impl Error for Err {
+    fn from_panic(message: String) -> Err {
+      Err::Panicked(message)
+    }
+  }
+
+  pub struct Echo {
+    context: ActorContext<Msg>,
+    deferred: Mutex<Option<ReplyTo<Msg>>>
+  }
+
+  impl Echo {
+    pub fn new() -> Echo {
+      Echo {
+        context: ActorContext::new(),
+        deferred: Mutex::new(None)
+      }
+    }
+  }
+
+  impl Actor<Msg, Err> for Echo {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      match *m {
+        Msg::Ping(n) => {
+          // a ping may also come without an ask
+          self.context.reply(Msg::Pong(n)).ok();
+          Ok(())
+        }
+        Msg::Defer => {
+          *self.deferred.lock().unwrap() = self.context.reply_to();
+          Ok(())
+        }
+        Msg::Fail => Err(Err::Fatal),
+        Msg::Sleep(ms) => {
+          thread::sleep(Duration::from_millis(ms));
+          Ok(())
+        }
+        Msg::WhoAmI => {
+          let path = self.context.uri().unwrap().path().to_owned();
+          self.context.reply(Msg::Name(path)).ok().unwrap();
+          Ok(())
+        }
+        _ => Ok(())
+      }
+    }
+  }
+
+  /// Records the numbers of the pings it receives.
+  pub struct Recorder {
+    context: ActorContext<Msg>,
+    received: Arc<Mutex<Vec<u32>>>
+  }
+
+  impl Recorder {
+    pub fn new(received: Arc<Mutex<Vec<u32>>>) -> Recorder {
+      Recorder {
+        context: ActorContext::new(),
+        received: received
+      }
+    }
+  }
+
+  impl Actor<Msg, Err> for Recorder {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      if let Msg::Ping(n) = *m {
+        self.received.lock().unwrap().push(n);
+      }
+      Ok(())
+    }
+  }
+
+  /// Waits up to five seconds for `f` to hold.
+  pub fn wait_until<F: Fn() -> bool>(f: F) {
+    let start = Instant::now();
+    while !f() {
+      assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+
+  fn check_send_to<D: Dispatcher<Msg, Err>>(dispatcher: &D) {
+    let r1 = Arc::new(Mutex::new(Vec::new()));
+    let r2 = Arc::new(Mutex::new(Vec::new()));
+    let uri1 = dispatcher.subscribe(Box::new(Recorder::new(r1.clone())), None).path().clone();
+    let uri2 = dispatcher.subscribe(Box::new(Recorder::new(r2.clone())), None).path().clone();
+    assert!(uri1 != uri2);
+
+    dispatcher.send_to(&uri1, Msg::Ping(1)).ok().unwrap();
+    dispatcher.send_to(&uri2, Msg::Ping(2)).ok().unwrap();
+    wait_until(|| r1.lock().unwrap().len() == 1 && r2.lock().unwrap().len() == 1);
+    assert_eq!(vec![1], *r1.lock().unwrap());
+    assert_eq!(vec![2], *r2.lock().unwrap());
+
+    let unknown = ActorUri::local("user/unknown");
+    let err = dispatcher.send_to(&unknown, Msg::Ping(3)).err().unwrap();
+    assert_eq!(&ReactErrKind::UnknownActor(unknown.display()), err.kind());
+  }
+
+  #[test]
+  fn test_send_to() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    check_send_to(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    check_send_to(&dispatcher);
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_context_uri() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    let uri = dispatcher.subscribe(Box::new(Echo::new()), None).path().clone();
+
+    let reply = dispatcher.ask(Msg::WhoAmI, Duration::from_secs(5));
+    assert_eq!(Msg::Name(uri.path().to_owned()), reply.wait().ok().unwrap());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_ask() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    let reply = dispatcher.ask(Msg::Ping(1), Duration::from_secs(5));
+    assert_eq!(Msg::Pong(1), reply.wait().ok().unwrap());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_ask_no_reply() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    let reply = dispatcher.ask(Msg::Ignore, Duration::from_secs(5));
+    assert_eq!(&ReactErrKind::NoReply, reply.wait().err().unwrap().kind());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_ask_timeout() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    // the actor keeps the reply channel but never answers
+    let reply = dispatcher.ask(Msg::Defer, Duration::from_millis(100));
+    assert_eq!(&ReactErrKind::Timeout, reply.wait().err().unwrap().kind());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_stop_while_idle() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    thread::sleep(Duration::from_millis(10));
+
+    // the dispatcher thread is parked on an empty queue at this point
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_delivery_latency() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    let mut elapsed = Vec::new();
+    for i in 0..1000 {
+      let start = Instant::now();
+      let reply = dispatcher.ask(Msg::Ping(i), Duration::from_secs(5));
+      assert_eq!(Msg::Pong(i), reply.wait().ok().unwrap());
+      elapsed.push(start.elapsed());
+    }
+    elapsed.sort();
+
+    // a round trip covers two deliveries: the ask and its reply
+    let median = elapsed[elapsed.len() / 2];
+    assert!(median < Duration::from_millis(1), "median round trip: {:?}", median);
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_shutdown_immediate() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    dispatcher.send(Msg::Sleep(200)).ok().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    for i in 0..10 {
+      dispatcher.send(Msg::Ping(i)).ok().unwrap();
+    }
+
+    dispatcher.shutdown(Shutdown::Immediate);
+    assert_eq!(10, dispatcher.join().ok().unwrap());
+  }
+
+  fn check_drain<D: Dispatcher<Msg, Err>>(mut dispatcher: D) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    dispatcher.subscribe(Box::new(Recorder::new(received.clone())), None);
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    dispatcher.send(Msg::Sleep(50)).ok().unwrap();
+    for i in 0..100 {
+      dispatcher.send(Msg::Ping(i)).ok().unwrap();
+    }
+
+    dispatcher.shutdown(Shutdown::Drain(Duration::from_secs(5)));
+    assert_eq!(0, dispatcher.join().ok().unwrap());
+    assert_eq!(100, received.lock().unwrap().len());
+  }
+
+  #[test]
+  fn test_shutdown_drain() {
+    check_drain(AsyncDispatcher::new());
+    check_drain(PoolDispatcher::new(2));
+  }
+
+  #[test]
+  fn test_drain_deadline() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    for _ in 0..10 {
+      dispatcher.send(Msg::Sleep(100)).ok().unwrap();
+    }
+
+    dispatcher.shutdown(Shutdown::Drain(Duration::from_millis(250)));
+    let dropped = dispatcher.join().ok().unwrap();
+    assert!(dropped > 0 && dropped < 10, "dropped: {}", dropped);
+  }
+
+  #[test]
+  fn test_bounded_fail() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::bounded(2, Overflow::Fail);
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    dispatcher.send(Msg::Sleep(200)).ok().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    dispatcher.send(Msg::Ping(1)).ok().unwrap();
+    dispatcher.send(Msg::Ping(2)).ok().unwrap();
+    assert_eq!(&ReactErrKind::MailboxFull, dispatcher.send(Msg::Ping(3)).err().unwrap().kind());
+    let reply = dispatcher.ask(Msg::Ping(4), Duration::from_secs(5));
+    assert_eq!(&ReactErrKind::MailboxFull, reply.wait().err().unwrap().kind());
+    assert_eq!(0, dispatcher.dropped());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_bounded_drop_newest() {
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::bounded(2, 2, Overflow::DropNewest);
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    dispatcher.send(Msg::Sleep(200)).ok().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    for i in 1..6 {
+      dispatcher.send(Msg::Ping(i)).ok().unwrap();
+    }
+    // the mailbox keeps the first two pings while the actor sleeps
+    assert_eq!(3, dispatcher.dropped());
+    // a dropped ask is never answered
+    let reply = dispatcher.ask(Msg::Ping(6), Duration::from_secs(5));
+    assert_eq!(&ReactErrKind::NoReply, reply.wait().err().unwrap().kind());
+    assert_eq!(4, dispatcher.dropped());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_bounded_block() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::bounded(1, Overflow::Block);
+    let received = Arc::new(Mutex::new(Vec::new()));
+    dispatcher.subscribe(Box::new(Recorder::new(received.clone())), None);
+
+    for i in 0..100 {
+      dispatcher.send(Msg::Ping(i)).ok().unwrap();
+    }
+    wait_until(|| received.lock().unwrap().len() == 100);
+    assert_eq!((0..100).collect::<Vec<u32>>(), *received.lock().unwrap());
+    assert_eq!(0, dispatcher.dropped());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  /// Answers every ping below 3 with two pings to itself.
+  struct Doubler {
+    context: ActorContext<Msg>,
+    received: Arc<Mutex<Vec<u32>>>
+  }
+
+  impl Actor<Msg, Err> for Doubler {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      if let Msg::Ping(n) = *m {
+        self.received.lock().unwrap().push(n);
+        if n < 3 {
+          let self_ref = self.context.self_ref().unwrap();
+          self_ref.tell(Msg::Ping(n + 1)).ok().unwrap();
+          self_ref.tell(Msg::Ping(n + 1)).ok().unwrap();
+        }
+      }
+      Ok(())
+    }
+  }
+
+  fn check_send_to_self<D: Dispatcher<Msg, Err>>(mut dispatcher: D) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let doubler = dispatcher.subscribe(Box::new(Doubler {
+      context: ActorContext::new(),
+      received: received.clone()
+    }), None);
+
+    // the full mailbox takes the sends of its own consumer
+    doubler.tell(Msg::Ping(0)).ok().unwrap();
+    wait_until(|| received.lock().unwrap().len() == 15);
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_bounded_block_self() {
+    check_send_to_self(AsyncDispatcher::bounded(1, Overflow::Block));
+    check_send_to_self(PoolDispatcher::bounded(2, 1, Overflow::Block));
+  }
+
+  fn check_dead_letters<D: Dispatcher<Msg, Err>>(mut dispatcher: D) {
+    let letters = dispatcher.dead_letters().subscribe();
+    dispatcher.subscribe(Box::new(Echo::new()), Some(Box::new(|m: &Msg| *m != Msg::Ignore)));
+    let uri = dispatcher.subscribe(Box::new(Echo::new()), Some(Box::new(|_: &Msg| false))).path().clone();
+
+    dispatcher.send(Msg::Ignore).ok().unwrap();
+    let letter = letters.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(&Msg::Ignore, letter.message());
+    assert_eq!(None, letter.recipient());
+    assert_eq!(DeadLetterReason::Unhandled, letter.reason());
+
+    // the ping is queued behind the sleep when its actor goes away
+    dispatcher.send_to(&uri, Msg::Sleep(200)).ok().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    dispatcher.send_to(&uri, Msg::Ping(1)).ok().unwrap();
+    dispatcher.unsubscribe(&uri).ok().unwrap();
+    let letter = letters.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(&Msg::Ping(1), letter.message());
+    assert_eq!(Some(&uri), letter.recipient());
+    assert_eq!(DeadLetterReason::ActorStopped, letter.reason());
+    assert_eq!(2, dispatcher.dead_letters().count());
+
+    // and so are the messages sent after it went away
+    let err = dispatcher.send_to(&uri, Msg::Ping(2)).err().unwrap();
+    assert_eq!(&ReactErrKind::UnknownActor(uri.display()), err.kind());
+    let letter = letters.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(&Msg::Ping(2), letter.message());
+    assert_eq!(Some(&uri), letter.recipient());
+    assert_eq!(DeadLetterReason::ActorStopped, letter.reason());
+    assert_eq!(3, dispatcher.dead_letters().count());
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_dead_letters() {
+    check_dead_letters(AsyncDispatcher::new());
+    check_dead_letters(PoolDispatcher::new(2));
+  }
+
+  fn check_metrics(system: ActorSystem<Msg, Err>) {
+    let uri = system.dispatcher().subscribe(Box::new(Echo::new()), None).path().clone();
+    system.dispatcher().subscribe(Box::new(Echo::new()), None);
+    for i in 0..10 {
+      system.dispatcher().send(Msg::Ping(i)).ok().unwrap();
+    }
+    system.dispatcher().send(Msg::Fail).ok().unwrap();
+    // every broadcast counts for both actors
+    wait_until(|| system.metrics().dispatcher.processed == 22);
+    thread::sleep(Duration::from_millis(10));
+
+    let snapshot = system.metrics();
+    assert_eq!("metrics", snapshot.system);
+    assert_eq!(0, snapshot.dispatcher.mailbox_depth);
+    assert!(snapshot.dispatcher.throughput > 0.0);
+    assert!(snapshot.dispatcher.idle > Duration::from_millis(0));
+
+    let actor = &snapshot.dispatcher.actors[0];
+    assert_eq!(uri, actor.uri);
+    assert_eq!(11, actor.processed);
+    assert_eq!(1, actor.errors);
+    assert_eq!(11, actor.latency.count);
+  }
+
+  #[test]
+  fn test_metrics() {
+    check_metrics(ActorSystem::new("metrics"));
+    check_metrics(ActorSystem::with_dispatcher("metrics", Box::new(PoolDispatcher::new(2))));
+  }
+
+  /// Panics on a ping of zero, and records the other pings.
+  struct Panicker {
+    context: ActorContext<Msg>,
+    received: Arc<Mutex<Vec<u32>>>
+  }
+
+  impl Actor<Msg, Err> for Panicker {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      if let Msg::Ping(n) = *m {
+        assert!(n != 0, "boom");
+        self.received.lock().unwrap().push(n);
+      }
+      Ok(())
+    }
+  }
+
+  fn check_panic<D: Dispatcher<Msg, Err>>(mut dispatcher: D) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let panics = Arc::new(Mutex::new(Vec::new()));
+    let decider_panics = panics.clone();
+    let panicker = dispatcher.subscribe_supervised(Box::new(Panicker {
+      context: ActorContext::new(),
+      received: received.clone()
+    }), None, Supervisor::new(Box::new(move |e: &Err| {
+      if let Err::Panicked(ref message) = *e {
+        decider_panics.lock().unwrap().push(message.clone());
+      }
+      Directive::Resume
+    })));
+    let echo = dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    panicker.tell(Msg::Ping(0)).ok().unwrap();
+    panicker.tell(Msg::Ping(1)).ok().unwrap();
+    wait_until(|| received.lock().unwrap().len() == 1);
+    assert_eq!(vec!["boom".to_owned()], *panics.lock().unwrap());
+    // the other actors are still served
+    assert_eq!(Msg::Pong(2), echo.ask(Msg::Ping(2), Duration::from_secs(5)).wait().ok().unwrap());
+    assert_eq!(1, dispatcher.metrics().actors[0].errors);
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_panic() {
+    check_panic(AsyncDispatcher::new());
+    check_panic(PoolDispatcher::new(2));
+    check_panic(CallingThreadDispatcher::new());
+  }
+
+  #[test]
+  fn test_panic_escalated() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    let panicker = dispatcher.subscribe_supervised(Box::new(Panicker {
+      context: ActorContext::new(),
+      received: Arc::new(Mutex::new(Vec::new()))
+    }), None, Supervisor::escalate());
+    panicker.tell(Msg::Ping(0)).ok().unwrap();
+    // the dispatcher thread ends with the escalated failure
+    wait_until(|| panicker.tell(Msg::Ping(1)).is_err());
+    dispatcher.stop();
+    match dispatcher.join() {
+      Err(Err::Panicked(message)) => assert_eq!("boom", message),
+      _ => panic!("the panic was not escalated")
+    }
+  }
+
+  /// Panics in the hooks named in `panics`. `WhoAmI` spawns a child.
+  struct Fragile {
+    context: ActorContext<Msg>,
+    panics: &'static [&'static str]
+  }
+
+  impl Fragile {
+    fn new(panics: &'static [&'static str]) -> Fragile {
+      Fragile {
+        context: ActorContext::new(),
+        panics: panics
+      }
+    }
+
+    fn hook(&self, name: &str) {
+      assert!(!self.panics.contains(&name), "boom");
+    }
+  }
+
+  impl Actor<Msg, Err> for Fragile {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      match *m {
+        Msg::Ping(n) => {
+          self.context.reply(Msg::Pong(n)).ok();
+        }
+        Msg::WhoAmI => {
+          self.context.spawn("child", Box::new(Fragile::new(&[]))).ok().unwrap();
+        }
+        Msg::Fail => return Err(Err::Fatal),
+        _ => {}
+      }
+      Ok(())
+    }
+
+    fn pre_start(&mut self) {
+      self.hook("pre_start");
+    }
+
+    fn post_stop(&mut self) {
+      self.hook("post_stop");
+    }
+
+    fn supervise_child(&mut self, _: &ActorUri, _: &Err) -> Directive {
+      self.hook("supervise_child");
+      Directive::Resume
+    }
+  }
+
+  #[test]
+  fn test_panic_in_hooks() {
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+
+    let fragile = dispatcher.subscribe(Box::new(Fragile::new(&["post_stop"])), None);
+    fragile.stop().ok().unwrap();
+    let fragile = dispatcher.subscribe(Box::new(Fragile::new(&["pre_start"])), None);
+    assert!(fragile.tell(Msg::Ping(1)).is_err());
+
+    // a fresh instance that cannot start, a factory or a decider that
+    // panics all stop the actor
+    let supervisors: Vec<Supervisor<Msg, Err>> = vec![
+      Supervisor::restart(Box::new(|| Box::new(Fragile::new(&["pre_start"])))),
+      Supervisor::restart(Box::new(|| -> Box<Actor<Msg, Err>> { panic!("boom") })),
+      Supervisor::new(Box::new(|_: &Err| -> Directive { panic!("boom") }))
+    ];
+    for supervisor in supervisors {
+      let fragile = dispatcher.subscribe_supervised(Box::new(Fragile::new(&[])), None, supervisor);
+      fragile.tell(Msg::Fail).ok().unwrap();
+      assert!(fragile.tell(Msg::Ping(1)).is_err());
+    }
+
+    // so does a parent that panics deciding, which is still usable after
+    let parent = dispatcher.subscribe(Box::new(Fragile::new(&["supervise_child"])), None);
+    parent.tell(Msg::WhoAmI).ok().unwrap();
+    let child = parent.child("child");
+    child.tell(Msg::Fail).ok().unwrap();
+    assert!(child.tell(Msg::Ping(1)).is_err());
+    assert_eq!(Msg::Pong(2), parent.ask(Msg::Ping(2), Duration::from_secs(5)).wait().ok().unwrap());
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_drop() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+      let dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+      dispatcher.subscribe(Box::new(Lifecycle::new(events.clone())), None);
+    }
+    assert_eq!(vec!["pre_start", "post_stop"], *events.lock().unwrap());
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    {
+      let dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+      dispatcher.subscribe(Box::new(Lifecycle::new(events.clone())), None);
+    }
+    assert_eq!(vec!["pre_start", "post_stop"], *events.lock().unwrap());
+  }
+}
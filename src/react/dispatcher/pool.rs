@@ -0,0 +1,542 @@
+//!
+//! A dispatcher that runs actors on a pool of threads.
+//!
+//! Every actor has its own mailbox. An actor with pending messages is put
+//! on a ready queue, and is handed to only one worker at a time, so the
+//! messages of an actor are handled one by one and in the order they were
+//! sent. A slow actor only occupies the worker that runs it.
+//!
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, RwLock, Condvar, Weak};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use err::ReactErr;
+use react::{MsgTrait, Error, Predicate};
+use react::actor::{Actor, ActorUri, ActorRef, Courier};
+use react::metrics::{DispatcherStats, DispatcherSnapshot};
+use react::reply::{self, ReplyTo, ReplyHandle};
+use react::supervision::{Factory, Supervisor};
+use super::mailbox;
+use super::{Dispatcher, ActorPair, DeadLetter, DeadLetters, DeadLetterReason, Mailbox, Overflow, Shutdown,
+            Spawner, StopFlag, next_uri, panic_message, start_child, stopped_actor, unknown_actor};
+
+/// The number of messages a worker handles for one actor before it moves on
+/// to the next ready actor.
+pub const THROUGHPUT: usize = 16;
+
+struct Envelope<M: MsgTrait> {
+  msg: Arc<M>,
+  reply_to: Option<ReplyTo<M>>
+}
+
+struct ActorCell<M: MsgTrait, E: Error> {
+  pair: Arc<ActorPair<M, E>>,
+  mailbox: Mailbox<Envelope<M>>,
+  scheduled: AtomicBool
+}
+
+struct Shared<M: MsgTrait, E: Error> {
+  cells: RwLock<Vec<Arc<ActorCell<M, E>>>>,
+  ready: Mutex<VecDeque<Arc<ActorCell<M, E>>>>,
+  ready_cond: Condvar,
+  seq: AtomicUsize,
+  stop: StopFlag,
+  /// The capacity and overflow policy of the mailbox of every actor.
+  bound: Option<(usize, Overflow)>,
+  /// Messages dropped by the mailboxes of actors that are gone.
+  dropped: AtomicUsize,
+  dead_letters: Arc<DeadLetters<M>>,
+  stats: DispatcherStats
+}
+
+impl<M: MsgTrait, E: Error> Shared<M, E> {
+  fn schedule(&self, cell: &Arc<ActorCell<M, E>>) {
+    // only the caller that flips the flag enqueues the cell, so a cell is
+    // never on the ready queue or in a worker more than once.
+    if !cell.scheduled.swap(true, Ordering::SeqCst) {
+      self.ready.lock().unwrap().push_back(cell.clone());
+      self.ready_cond.notify_one();
+    }
+  }
+
+  fn deliver(&self, cell: &Arc<ActorCell<M, E>>, msg: Arc<M>, reply_to: Option<ReplyTo<M>>)
+      -> Result<(), ReactErr> {
+    let res = cell.mailbox.push(Envelope {
+      msg: msg,
+      reply_to: reply_to
+    });
+    self.schedule(cell);
+    res
+  }
+
+  /// Delivers to every accepting actor, and returns the first error of a
+  /// refusing mailbox.
+  fn publish(&self, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    let msg = Arc::new(m);
+    // a blocking mailbox may wait for a worker, so the cells are unlocked
+    // first, or a waiting writer would block every worker that sends.
+    let targets: Vec<_> = self.cells.read().unwrap().iter()
+      .filter(|c| c.pair.accept(&msg)).cloned().collect();
+
+    let mut res = Ok(());
+    for cell in &targets {
+      let r = self.deliver(cell, msg.clone(), reply_to.clone());
+      if res.is_ok() {
+        res = r;
+      }
+    }
+
+    if targets.is_empty() {
+      self.dead_letters.publish(DeadLetter::new(msg, None, DeadLetterReason::Unhandled));
+    }
+    res
+  }
+
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    let cell = self.cells.read().unwrap().iter()
+      .find(|c| c.pair.uri() == to && !c.pair.is_stopped()).cloned();
+    match cell {
+      Some(cell) => self.deliver(&cell, Arc::new(m), reply_to),
+      None => Err(stopped_actor(&self.dead_letters, to, m))
+    }
+  }
+
+  /// Publishes the messages left in the mailbox of a stopped actor.
+  fn bury(&self, cell: &ActorCell<M, E>) {
+    while let Some(env) = cell.mailbox.pop() {
+      self.dead_letters.publish(DeadLetter::new(env.msg, Some(cell.pair.uri().clone()),
+                                                DeadLetterReason::ActorStopped));
+    }
+  }
+
+  fn shutdown(&self, mode: Shutdown) {
+    self.stop.request(mode);
+    let _ready = self.ready.lock().unwrap();
+    self.ready_cond.notify_all();
+  }
+
+  fn stop(&self, uri: &ActorUri) -> Result<(), ReactErr> {
+    match self.remove(uri) {
+      Some(cell) => {
+        cell.pair.stop();
+        self.bury(&cell);
+        Ok(())
+      }
+      None => Err(unknown_actor(uri))
+    }
+  }
+
+  /// Adds a started pair with a mailbox of its own.
+  fn add(&self, pair: Arc<ActorPair<M, E>>) {
+    let cell = ActorCell {
+      pair: pair,
+      mailbox: self.new_mailbox(),
+      scheduled: AtomicBool::new(false)
+    };
+    self.cells.write().unwrap().push(Arc::new(cell));
+  }
+
+  fn remove(&self, uri: &ActorUri) -> Option<Arc<ActorCell<M, E>>> {
+    let mut cells = self.cells.write().unwrap();
+    match cells.iter().position(|c| c.pair.uri() == uri) {
+      Some(idx) => {
+        let cell = cells.remove(idx);
+        cell.mailbox.close();
+        self.dropped.fetch_add(cell.mailbox.dropped(), Ordering::SeqCst);
+        Some(cell)
+      }
+      None => None
+    }
+  }
+
+  fn new_mailbox(&self) -> Mailbox<Envelope<M>> {
+    match self.bound {
+      Some((capacity, overflow)) => Mailbox::bounded(capacity, overflow),
+      None => Mailbox::unbounded()
+    }
+  }
+
+  /// Blocks until an actor is ready, or returns `None` once the pool is
+  /// stopped or drained.
+  fn next_ready(&self) -> Option<Arc<ActorCell<M, E>>> {
+    let mut ready = self.ready.lock().unwrap();
+    loop {
+      if self.stop.is_expired() {
+        return None;
+      }
+
+      if let Some(cell) = ready.pop_front() {
+        return Some(cell);
+      }
+
+      if self.stop.is_requested() {
+        // wake the other workers, so that they see the drained queue
+        self.ready_cond.notify_all();
+        return None;
+      }
+
+      let idle = self.stats.begin_idle();
+      ready = self.ready_cond.wait(ready).unwrap();
+      self.stats.end_idle(idle);
+    }
+  }
+}
+
+impl<M: MsgTrait, E: Error> Courier<M> for Weak<Shared<M, E>> {
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    match self.upgrade() {
+      Some(shared) => shared.forward(to, m, reply_to),
+      None => Err(unknown_actor(to))
+    }
+  }
+
+  fn stop(&self, to: &ActorUri) -> Result<(), ReactErr> {
+    match self.upgrade() {
+      Some(shared) => shared.stop(to),
+      None => Err(unknown_actor(to))
+    }
+  }
+}
+
+impl<M: MsgTrait, E: Error> Spawner<M, E> for Weak<Shared<M, E>> {
+  fn spawn(&self, parent: &ActorUri, name: &str, actor: Box<Actor<M, E>>,
+           factory: Option<Box<Factory<M, E>>>) -> Result<ActorRef<M>, ReactErr> {
+    let shared = match self.upgrade() {
+      Some(shared) => shared,
+      None => return Err(unknown_actor(parent))
+    };
+    let parent = match shared.cells.read().unwrap().iter().find(|c| c.pair.uri() == parent) {
+      Some(cell) => cell.pair.clone(),
+      None => return Err(unknown_actor(parent))
+    };
+    start_child(&parent, name, actor, factory, |child| shared.add(child))
+  }
+}
+
+pub struct PoolDispatcher<M: MsgTrait, E: Error> {
+  shared: Arc<Shared<M, E>>,
+  courier: Arc<Courier<M>>,
+  spawner: Arc<Spawner<M, E>>,
+  threads: Vec<JoinHandle<Result<(), E>>>
+}
+
+impl<M: MsgTrait, E: Error> PoolDispatcher<M, E> {
+  fn join_threads(&mut self) -> Result<(), E> {
+    let mut res = Ok(());
+    for t in self.threads.drain(..) {
+      let r = match t.join() {
+        Ok(r) => r,
+        Err(payload) => {
+          error!("a pool thread panicked");
+          Err(E::from_panic(panic_message(&*payload)))
+        }
+      };
+      if res.is_ok() {
+        res = r;
+      }
+    }
+    res
+  }
+}
+
+impl<M: MsgTrait, E: Error> PoolDispatcher<M, E> {
+  pub fn new(thread_num: usize) -> PoolDispatcher<M, E> {
+    PoolDispatcher::with_bound(thread_num, None)
+  }
+
+  /// Creates a pool whose actors each have a mailbox of `capacity` messages.
+  pub fn bounded(thread_num: usize, capacity: usize, overflow: Overflow) -> PoolDispatcher<M, E> {
+    PoolDispatcher::with_bound(thread_num, Some((capacity, overflow)))
+  }
+
+  fn with_bound(thread_num: usize, bound: Option<(usize, Overflow)>) -> PoolDispatcher<M, E> {
+    assert!(thread_num > 0, "a pool needs at least one thread");
+
+    let shared = Arc::new(Shared {
+      cells: RwLock::new(Vec::new()),
+      ready: Mutex::new(VecDeque::new()),
+      ready_cond: Condvar::new(),
+      seq: AtomicUsize::new(0),
+      stop: StopFlag::new(),
+      bound: bound,
+      dropped: AtomicUsize::new(0),
+      dead_letters: Arc::new(DeadLetters::new()),
+      stats: DispatcherStats::new()
+    });
+
+    let threads = (0..thread_num)
+      .map(|i| work(format!("react-pool-{}", i), shared.clone()))
+      .collect();
+
+    PoolDispatcher {
+      courier: Arc::new(Arc::downgrade(&shared)),
+      spawner: Arc::new(Arc::downgrade(&shared)),
+      shared: shared,
+      threads: threads
+    }
+  }
+}
+
+impl<M: MsgTrait, E: Error> Dispatcher<M, E> for PoolDispatcher<M, E> {
+  fn shutdown(&mut self, mode: Shutdown) {
+    debug!("shutdown enter");
+    self.shared.shutdown(mode);
+    debug!("shutdown leave");
+  }
+
+  fn join(mut self) -> Result<usize, E> {
+    let res = self.join_threads();
+
+    let mut dropped = 0;
+    let stopped: Vec<_> = self.shared.cells.write().unwrap().drain(..).collect();
+    for cell in stopped {
+      cell.pair.stop();
+      cell.mailbox.close();
+      while let Some(_) = cell.mailbox.pop() {
+        dropped += 1;
+      }
+    }
+    if dropped > 0 {
+      info!("{} messages were dropped at shutdown", dropped);
+    }
+    res.map(|_| dropped)
+  }
+
+  fn send(&self, m: M) -> Result<(), ReactErr> {
+    self.shared.publish(m, None)
+  }
+
+  fn ask(&self, m: M, timeout: Duration) -> ReplyHandle<M> {
+    let (reply_to, handle) = reply::channel(timeout);
+    match self.shared.publish(m, Some(reply_to)) {
+      Ok(()) => handle,
+      Err(e) => handle.fail(e)
+    }
+  }
+
+  fn forward(&self, to: &ActorUri, m: M, reply_to: Option<ReplyTo<M>>) -> Result<(), ReactErr> {
+    self.shared.forward(to, m, reply_to)
+  }
+
+  fn subscribe_supervised(&self, actor: Box<Actor<M, E>>, filter: Option<Box<Predicate<M>>>,
+                          supervisor: Supervisor<M, E>) -> ActorRef<M> {
+    let actor_ref = ActorRef::new(next_uri(&self.shared.seq), self.courier.clone());
+    let pair = ActorPair::new(actor_ref.clone(), actor, filter, supervisor, self.spawner.clone(),
+                              self.shared.dead_letters.clone());
+    pair.start(|pair| self.shared.add(pair));
+    actor_ref
+  }
+
+  fn unsubscribe(&self, uri: &ActorUri) -> Result<(), ReactErr> {
+    self.shared.stop(uri)
+  }
+
+  fn dropped(&self) -> usize {
+    let cells = self.shared.cells.read().unwrap();
+    self.shared.dropped.load(Ordering::SeqCst) +
+      cells.iter().map(|c| c.mailbox.dropped()).sum::<usize>()
+  }
+
+  fn mailbox_len(&self, uri: &ActorUri) -> Result<usize, ReactErr> {
+    match self.shared.cells.read().unwrap().iter().find(|c| c.pair.uri() == uri) {
+      Some(cell) => Ok(cell.mailbox.len()),
+      None => Err(unknown_actor(uri))
+    }
+  }
+
+  fn dead_letters(&self) -> &DeadLetters<M> {
+    &self.shared.dead_letters
+  }
+
+  fn metrics(&self) -> DispatcherSnapshot {
+    let cells = self.shared.cells.read().unwrap();
+    let depth = cells.iter().map(|c| c.mailbox.len()).sum();
+    self.shared.stats.snapshot(depth, cells.iter().map(|c| c.pair.snapshot()).collect())
+  }
+}
+
+impl<M: MsgTrait, E: Error> Drop for PoolDispatcher<M, E> {
+  fn drop(&mut self) {
+    if !self.threads.is_empty() {
+      self.shared.shutdown(Shutdown::Immediate);
+      let _ = self.join_threads();
+
+      let stopped: Vec<_> = self.shared.cells.write().unwrap().drain(..).collect();
+      for cell in stopped {
+        cell.pair.stop();
+        cell.mailbox.close();
+      }
+    }
+  }
+}
+
+fn work<M, E>(name: String, shared: Arc<Shared<M, E>>) -> JoinHandle<Result<(), E>>
+    where M: MsgTrait, E: Error {
+
+  thread::Builder::new().name(name).spawn(move || -> Result<(), E> {
+    mailbox::mark_consumer();
+    while let Some(cell) = shared.next_ready() {
+      for _ in 0..THROUGHPUT {
+        if shared.stop.is_expired() {
+          break;
+        }
+
+        let env = match cell.mailbox.pop() {
+          Some(env) => env,
+          None => break
+        };
+
+        match cell.pair.receive(&env.msg, env.reply_to.as_ref()) {
+          Ok(true) => shared.stats.record_message(),
+          Ok(false) => {
+            shared.dead_letters.publish(DeadLetter::new(env.msg, Some(cell.pair.uri().clone()),
+                                                        DeadLetterReason::ActorStopped));
+          }
+          Err(e) => {
+            shared.stats.record_message();
+            // an escalated failure takes the whole pool down
+            shared.shutdown(Shutdown::Immediate);
+            return Err(e);
+          }
+        }
+      }
+
+      // stopped by its supervisor, by itself or by `unsubscribe`
+      if cell.pair.is_stopped() {
+        shared.remove(cell.pair.uri());
+        shared.bury(&cell);
+      }
+
+      cell.scheduled.store(false, Ordering::SeqCst);
+      // a message pushed while the flag was still set did not schedule the
+      // cell, so check for it here.
+      if !cell.mailbox.is_empty() {
+        shared.schedule(&cell);
+      }
+    }
+
+    Ok(())
+  }).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use react::actor::{Actor, ActorContext, ActorRef};
+  use react::dispatcher::{Dispatcher, Overflow};
+  use react::dispatcher::tests::{Msg, Err, Echo, Recorder, wait_until};
+  use super::PoolDispatcher;
+
+  pub struct Sleeper {
+    context: ActorContext<Msg>
+  }
+
+  impl Actor<Msg, Err> for Sleeper {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, _: &Msg) -> Result<(), Err> {
+      thread::sleep(Duration::from_millis(500));
+      Ok(())
+    }
+  }
+
+  /// Passes every ping on to another actor, slowly.
+  struct Relay {
+    context: ActorContext<Msg>,
+    to: ActorRef<Msg>
+  }
+
+  impl Actor<Msg, Err> for Relay {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      if let Msg::Ping(n) = *m {
+        thread::sleep(Duration::from_millis(5));
+        self.to.tell(Msg::Ping(n)).ok().unwrap();
+      }
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_blocked_sender() {
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::bounded(2, 1, Overflow::Block);
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let recorder = dispatcher.subscribe(Box::new(Recorder::new(received.clone())),
+                                        Some(Box::new(|_: &Msg| false)));
+    let relay = dispatcher.subscribe(Box::new(Relay { context: ActorContext::new(), to: recorder }),
+                                     Some(Box::new(|_: &Msg| false)));
+
+    // the sender waits on the full mailbox of the relay, while subscribing
+    // waits for the cells, and the relay sends through them
+    let sender = thread::spawn(move || {
+      for i in 0..50 {
+        relay.tell(Msg::Ping(i)).ok().unwrap();
+      }
+    });
+    for _ in 0..20 {
+      dispatcher.subscribe(Box::new(Echo::new()), None);
+      thread::sleep(Duration::from_millis(1));
+    }
+    sender.join().unwrap();
+    wait_until(|| received.lock().unwrap().len() == 50);
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_ordering() {
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(4);
+    let mut received = Vec::new();
+    for _ in 0..4 {
+      let r = Arc::new(Mutex::new(Vec::new()));
+      dispatcher.subscribe(Box::new(Recorder::new(r.clone())), None);
+      received.push(r);
+    }
+
+    for i in 0..1000 {
+      dispatcher.send(Msg::Ping(i)).ok().unwrap();
+    }
+    let start = Instant::now();
+    while received.iter().any(|r| r.lock().unwrap().len() < 1000) {
+      assert!(start.elapsed() < Duration::from_secs(5));
+      thread::sleep(Duration::from_millis(1));
+    }
+
+    let expected: Vec<u32> = (0..1000).collect();
+    for r in received {
+      assert_eq!(expected, *r.lock().unwrap());
+    }
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_slow_actor() {
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    dispatcher.subscribe(Box::new(Sleeper { context: ActorContext::new() }),
+      Some(Box::new(|m: &Msg| *m == Msg::Ignore)));
+    dispatcher.subscribe(Box::new(Echo::new()), None);
+
+    dispatcher.send(Msg::Ignore).ok().unwrap();
+    let start = Instant::now();
+    let reply = dispatcher.ask(Msg::Ping(1), Duration::from_secs(5));
+    assert_eq!(Msg::Pong(1), reply.wait().ok().unwrap());
+    assert!(start.elapsed() < Duration::from_millis(250));
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+}
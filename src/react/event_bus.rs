@@ -0,0 +1,364 @@
+//!
+//! Publish and subscribe by topic.
+//!
+//! Topics are paths of segments separated by `/`, like `nodes/3/update`. A
+//! subscription pattern may use `*` for exactly one segment, and end with
+//! `**` for any number of remaining segments, including none. Patterns are
+//! kept in a tree of segments, so that publishing only walks the branches
+//! that can match instead of testing every subscriber.
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, Weak};
+
+use err::{ReactErr, ReactErrKind};
+use super::{MsgTrait, Error};
+use super::actor::{Actor, ActorRef, ActorUri};
+use super::dispatcher::{Dispatcher, DeadLetter, DeadLetterReason};
+
+pub const SEPARATOR: char = '/';
+/// Matches exactly one segment.
+pub const ANY: &'static str = "*";
+/// Matches the remaining segments, as the last segment of a pattern.
+pub const REST: &'static str = "**";
+
+fn invalid(topic: &str, why: &str) -> ReactErr {
+  ReactErr::new(ReactErrKind::InvalidTopic(format!("{:?} {}", topic, why)))
+}
+
+fn segments<'a>(topic: &'a str) -> Result<Vec<&'a str>, ReactErr> {
+  let segments: Vec<&str> = topic.split(SEPARATOR).collect();
+  if segments.iter().any(|s| s.is_empty()) {
+    return Err(invalid(topic, "has an empty segment"));
+  }
+  Ok(segments)
+}
+
+/// Splits a pattern, which may hold wildcards.
+fn pattern_segments<'a>(pattern: &'a str) -> Result<Vec<&'a str>, ReactErr> {
+  let segments = segments(pattern)?;
+  for (i, s) in segments.iter().enumerate() {
+    if *s == REST && i + 1 != segments.len() {
+      return Err(invalid(pattern, "has `**` before its last segment"));
+    }
+    if *s != ANY && *s != REST && s.contains('*') {
+      return Err(invalid(pattern, "mixes `*` with other characters in a segment"));
+    }
+  }
+  Ok(segments)
+}
+
+/// Splits a topic to publish to, which may not hold wildcards.
+fn topic_segments<'a>(topic: &'a str) -> Result<Vec<&'a str>, ReactErr> {
+  let segments = segments(topic)?;
+  if segments.iter().any(|s| s.contains('*')) {
+    return Err(invalid(topic, "has a wildcard"));
+  }
+  Ok(segments)
+}
+
+#[derive(Default)]
+struct Node {
+  /// Includes the `*` child.
+  children: HashMap<String, Node>,
+  /// The subscribers of the pattern ending here.
+  exact: Vec<ActorUri>,
+  /// The subscribers of the pattern ending here with `**`.
+  rest: Vec<ActorUri>
+}
+
+impl Node {
+  fn insert(&mut self, segments: &[&str], uri: &ActorUri) {
+    match segments.split_first() {
+      None => add_uri(&mut self.exact, uri),
+      Some((&REST, _)) => add_uri(&mut self.rest, uri),
+      Some((head, tail)) => {
+        self.children.entry(head.to_string()).or_insert_with(Node::default).insert(tail, uri)
+      }
+    }
+  }
+
+  /// Removes `uri` from the pattern, and returns whether it was there.
+  fn remove(&mut self, segments: &[&str], uri: &ActorUri) -> bool {
+    match segments.split_first() {
+      None => remove_uri(&mut self.exact, uri),
+      Some((&REST, _)) => remove_uri(&mut self.rest, uri),
+      Some((head, tail)) => {
+        let removed = match self.children.get_mut(*head) {
+          Some(child) => child.remove(tail, uri),
+          None => false
+        };
+        if self.children.get(*head).map_or(false, |c| c.is_empty()) {
+          self.children.remove(*head);
+        }
+        removed
+      }
+    }
+  }
+
+  /// Removes `uri` from every pattern, and returns how many it was in.
+  fn remove_all(&mut self, uri: &ActorUri) -> usize {
+    let mut removed = remove_uri(&mut self.exact, uri) as usize + remove_uri(&mut self.rest, uri) as usize;
+    for child in self.children.values_mut() {
+      removed += child.remove_all(uri);
+    }
+    self.children.retain(|_, c| !c.is_empty());
+    removed
+  }
+
+  fn is_empty(&self) -> bool {
+    self.children.is_empty() && self.exact.is_empty() && self.rest.is_empty()
+  }
+
+  fn collect(&self, segments: &[&str], out: &mut Vec<ActorUri>) {
+    // `**` also matches when nothing is left
+    out.extend(self.rest.iter().cloned());
+
+    match segments.split_first() {
+      None => out.extend(self.exact.iter().cloned()),
+      Some((head, tail)) => {
+        if let Some(child) = self.children.get(*head) {
+          child.collect(tail, out);
+        }
+        if let Some(child) = self.children.get(ANY) {
+          child.collect(tail, out);
+        }
+      }
+    }
+  }
+}
+
+fn add_uri(list: &mut Vec<ActorUri>, uri: &ActorUri) {
+  if !list.contains(uri) {
+    list.push(uri.clone());
+  }
+}
+
+fn remove_uri(list: &mut Vec<ActorUri>, uri: &ActorUri) -> bool {
+  match list.iter().position(|u| u == uri) {
+    Some(idx) => {
+      list.remove(idx);
+      true
+    }
+    None => false
+  }
+}
+
+/// Delivers messages to the actors subscribed to the matching topics. The
+/// actors live in the dispatcher of the system that created the bus.
+pub struct EventBus<M: MsgTrait + Clone, E: Error> {
+  root: RwLock<Node>,
+  dispatcher: Weak<Box<Dispatcher<M, E>>>
+}
+
+impl<M: MsgTrait + Clone, E: Error> EventBus<M, E> {
+  pub fn new(dispatcher: Weak<Box<Dispatcher<M, E>>>) -> EventBus<M, E> {
+    EventBus {
+      root: RwLock::new(Node::default()),
+      dispatcher: dispatcher
+    }
+  }
+
+  fn dispatcher(&self) -> Result<Arc<Box<Dispatcher<M, E>>>, ReactErr> {
+    self.dispatcher.upgrade().ok_or(ReactErr::new(ReactErrKind::Disconnected))
+  }
+
+  /// Subscribes `actor` to the dispatcher and to `pattern`, and returns a
+  /// handle to it. It only gets the messages published through the bus.
+  pub fn subscribe(&self, pattern: &str, actor: Box<Actor<M, E>>) -> Result<ActorRef<M>, ReactErr> {
+    let segments = pattern_segments(pattern)?;
+    let actor_ref = self.dispatcher()?.subscribe(actor, Some(Box::new(|_: &M| false)));
+    self.root.write().unwrap().insert(&segments, actor_ref.path());
+    Ok(actor_ref)
+  }
+
+  /// Subscribes the actor at `uri`, which already lives in the dispatcher,
+  /// to `pattern` as well.
+  pub fn subscribe_uri(&self, pattern: &str, uri: &ActorUri) -> Result<(), ReactErr> {
+    let segments = pattern_segments(pattern)?;
+    self.root.write().unwrap().insert(&segments, uri);
+    Ok(())
+  }
+
+  /// Removes the subscription of `uri` to `pattern`, and returns whether
+  /// there was one. The actor stays in the dispatcher.
+  pub fn unsubscribe(&self, pattern: &str, uri: &ActorUri) -> Result<bool, ReactErr> {
+    let segments = pattern_segments(pattern)?;
+    Ok(self.root.write().unwrap().remove(&segments, uri))
+  }
+
+  /// Removes every subscription of `uri`, and returns how many there were.
+  pub fn unsubscribe_all(&self, uri: &ActorUri) -> usize {
+    self.root.write().unwrap().remove_all(uri)
+  }
+
+  /// The actors subscribed to a pattern that matches `topic`, each once.
+  pub fn subscribers(&self, topic: &str) -> Result<Vec<ActorUri>, ReactErr> {
+    let segments = topic_segments(topic)?;
+    let mut uris = Vec::new();
+    self.root.read().unwrap().collect(&segments, &mut uris);
+
+    let mut seen = Vec::with_capacity(uris.len());
+    for uri in uris {
+      if !seen.contains(&uri) {
+        seen.push(uri);
+      }
+    }
+    Ok(seen)
+  }
+
+  /// Sends `m` to every actor subscribed to `topic`, and returns how many
+  /// there were. A message nobody subscribed to goes to the dead letters, as
+  /// does the copy for an actor that has left the dispatcher, which is then
+  /// unsubscribed. A send that
+  /// fails otherwise does not keep `m` from the other subscribers; the first
+  /// such error is returned once they all had their turn.
+  pub fn publish(&self, topic: &str, m: M) -> Result<usize, ReactErr> {
+    let dispatcher = self.dispatcher()?;
+    let uris = self.subscribers(topic)?;
+
+    let mut sent = 0;
+    let mut failed = None;
+    for uri in &uris {
+      match dispatcher.send_to(uri, m.clone()) {
+        Ok(()) => sent += 1,
+        Err(ref e) if e.kind() == &ReactErrKind::UnknownActor(uri.display()) => {
+          debug!("{} is gone, unsubscribing it from the event bus", uri.display());
+          self.unsubscribe_all(uri);
+        }
+        Err(e) => {
+          warn!("could not publish to {}: {}", uri.display(), e);
+          if failed.is_none() {
+            failed = Some(e);
+          }
+        }
+      }
+    }
+
+    if let Some(e) = failed {
+      return Err(e);
+    }
+    if uris.is_empty() {
+      dispatcher.dead_letters().publish(DeadLetter::new(Arc::new(m), None, DeadLetterReason::Unhandled));
+    }
+    Ok(sent)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::Duration;
+
+  use err::ReactErrKind;
+  use react::ActorSystem;
+  use react::dispatcher::{CallingThreadDispatcher, PoolDispatcher, Overflow, DeadLetterReason};
+  use react::dispatcher::tests::{Msg, Err, Echo, Recorder, wait_until};
+
+  fn system() -> ActorSystem<Msg, Err> {
+    ActorSystem::with_dispatcher("test", Box::new(CallingThreadDispatcher::new()))
+  }
+
+  #[test]
+  fn test_wildcards() {
+    let system = system();
+    let bus = system.new_event_bus();
+    let exact = Arc::new(Mutex::new(Vec::new()));
+    let any = Arc::new(Mutex::new(Vec::new()));
+    let rest = Arc::new(Mutex::new(Vec::new()));
+    bus.subscribe("nodes/1/update", Box::new(Recorder::new(exact.clone()))).ok().unwrap();
+    bus.subscribe("nodes/*/update", Box::new(Recorder::new(any.clone()))).ok().unwrap();
+    bus.subscribe("nodes/**", Box::new(Recorder::new(rest.clone()))).ok().unwrap();
+
+    assert_eq!(3, bus.publish("nodes/1/update", Msg::Ping(1)).ok().unwrap());
+    assert_eq!(2, bus.publish("nodes/2/update", Msg::Ping(2)).ok().unwrap());
+    assert_eq!(1, bus.publish("nodes/2/remove", Msg::Ping(3)).ok().unwrap());
+    assert_eq!(1, bus.publish("nodes", Msg::Ping(4)).ok().unwrap());
+    assert_eq!(0, bus.publish("members/1/update", Msg::Ping(5)).ok().unwrap());
+
+    assert_eq!(vec![1], *exact.lock().unwrap());
+    assert_eq!(vec![1, 2], *any.lock().unwrap());
+    assert_eq!(vec![1, 2, 3, 4], *rest.lock().unwrap());
+
+    // the subscribers get nothing sent to the dispatcher directly
+    system.dispatcher().send(Msg::Ping(6)).ok().unwrap();
+    assert_eq!(vec![1, 2, 3, 4], *rest.lock().unwrap());
+  }
+
+  #[test]
+  fn test_unsubscribe() {
+    let system = system();
+    let bus = system.new_event_bus();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let uri = bus.subscribe("a/b", Box::new(Recorder::new(received.clone()))).ok().unwrap().path().clone();
+    bus.subscribe_uri("a/*", &uri).ok().unwrap();
+    bus.subscribe_uri("c", &uri).ok().unwrap();
+
+    // one message per actor, however many patterns match
+    assert_eq!(1, bus.publish("a/b", Msg::Ping(1)).ok().unwrap());
+    assert!(bus.unsubscribe("a/b", &uri).ok().unwrap());
+    assert!(!bus.unsubscribe("a/b", &uri).ok().unwrap());
+    assert_eq!(1, bus.publish("a/b", Msg::Ping(2)).ok().unwrap());
+    assert_eq!(2, bus.unsubscribe_all(&uri));
+    assert_eq!(0, bus.publish("a/b", Msg::Ping(3)).ok().unwrap());
+    assert_eq!(vec![1, 2], *received.lock().unwrap());
+  }
+
+  #[test]
+  fn test_gone_and_dead_letters() {
+    let system = system();
+    let bus = system.new_event_bus();
+    let letters = system.dead_letters();
+    let actor = bus.subscribe("a", Box::new(Recorder::new(Arc::new(Mutex::new(Vec::new()))))).ok().unwrap();
+    system.dispatcher().unsubscribe(actor.path()).ok().unwrap();
+
+    assert_eq!(0, bus.publish("a", Msg::Ping(1)).ok().unwrap());
+    assert!(bus.subscribers("a").ok().unwrap().is_empty());
+    let letter = letters.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(&Msg::Ping(1), letter.message());
+    assert_eq!(Some(actor.path()), letter.recipient());
+    assert_eq!(DeadLetterReason::ActorStopped, letter.reason());
+
+    assert_eq!(0, bus.publish("a", Msg::Ping(2)).ok().unwrap());
+    let letter = letters.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(&Msg::Ping(2), letter.message());
+    assert_eq!(DeadLetterReason::Unhandled, letter.reason());
+  }
+
+  #[test]
+  fn test_full_subscriber() {
+    let system = ActorSystem::with_dispatcher("test", Box::new(PoolDispatcher::bounded(3, 1, Overflow::Fail)));
+    let bus = system.new_event_bus();
+    let first = Arc::new(Mutex::new(Vec::new()));
+    let last = Arc::new(Mutex::new(Vec::new()));
+    bus.subscribe("a", Box::new(Recorder::new(first.clone()))).ok().unwrap();
+    let busy = bus.subscribe("a", Box::new(Echo::new())).ok().unwrap();
+    bus.subscribe("a", Box::new(Recorder::new(last.clone()))).ok().unwrap();
+
+    // the echo sleeps on one message and has another one waiting
+    busy.tell(Msg::Sleep(300)).ok().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    busy.tell(Msg::Ping(0)).ok().unwrap();
+
+    let err = bus.publish("a", Msg::Ping(1)).err().unwrap();
+    assert_eq!(&ReactErrKind::MailboxFull, err.kind());
+    wait_until(|| first.lock().unwrap().len() == 1 && last.lock().unwrap().len() == 1);
+    assert_eq!(vec![1], *first.lock().unwrap());
+    assert_eq!(vec![1], *last.lock().unwrap());
+    assert_eq!(3, bus.subscribers("a").ok().unwrap().len());
+  }
+
+  #[test]
+  fn test_invalid() {
+    let system = system();
+    let bus = system.new_event_bus();
+    let uri = system.dispatcher().subscribe(Box::new(Recorder::new(Arc::new(Mutex::new(Vec::new())))), None).path().clone();
+
+    for pattern in &["", "a//b", "a/**/b", "a/b*"] {
+      let err = bus.subscribe_uri(pattern, &uri).err().unwrap();
+      assert!(match *err.kind() { ReactErrKind::InvalidTopic(_) => true, _ => false }, "{}", pattern);
+    }
+    assert!(bus.publish("a/*", Msg::Ping(1)).is_err());
+  }
+}
@@ -0,0 +1,450 @@
+//!
+//! Finite-state machines as actors.
+//!
+//! An `FsmActor` holds the data of a machine, and `Fsm` runs it as an
+//! `Actor` in one of its declared states. Every message goes to the handler
+//! registered for the current state with `when`, which answers with a
+//! `Transition`. Moving to a state calls `on_transition`, publishes a
+//! `TransitionEvent` to the observers, and arms the timeout of the new
+//! state if it has one: when the machine is still there once it expires,
+//! the scheduler sends the timeout message, which the handler of the state
+//! gets like any other. Leaving the state cancels it. Every move also
+//! starts a new generation, which the timeout message carries, so that a
+//! timeout already on its way when its state was left is dropped instead
+//! of reaching the next state.
+//!
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::time::Duration;
+
+use super::{MsgTrait, Error};
+use super::actor::{Actor, ActorContext};
+use super::scheduler::{Cancellable, SchedulerRef};
+
+/// The states of a machine, usually a plain enum.
+pub trait FsmState: 'static + Clone + Eq + Hash + Debug + Send + Sync {}
+
+impl<S: 'static + Clone + Eq + Hash + Debug + Send + Sync> FsmState for S {}
+
+pub enum Transition<S: FsmState> {
+  /// Stays in the current state, whose timeout keeps running.
+  Stay,
+  /// Moves to the state. Going to the current state enters it again, which
+  /// restarts its timeout.
+  Goto(S),
+  /// Stops the machine, like unsubscribing it.
+  Stop
+}
+
+/// Handles a message in one state.
+pub type Handler<A, S, M, E> = fn(&mut A, &M) -> Result<Transition<S>, E>;
+
+pub trait FsmActor<M: MsgTrait, E: Error>: Send + Sync {
+  type State: FsmState;
+
+  fn context(&self) -> &ActorContext<M>;
+
+  /// Called when the machine moves from one state to another, before the
+  /// observers hear of it.
+  fn on_transition(&mut self, _from: &Self::State, _to: &Self::State) {}
+
+  /// The generation of a timeout message built by the function given to
+  /// `Fsm::with_timeout`, and `None` for any other message. A machine with
+  /// timeouts tells them apart here, or a stale one reaches the next state.
+  fn timeout_generation(&self, _m: &M) -> Option<u64> {
+    None
+  }
+
+  /// Handles a message in a state without a handler. By default it is
+  /// ignored.
+  fn on_unhandled(&mut self, state: &Self::State, _m: &M) -> Result<Transition<Self::State>, E> {
+    debug!("unhandled message in state {:?}", state);
+    Ok(Transition::Stay)
+  }
+
+  fn post_stop(&mut self) {}
+}
+
+/// A move of a machine, as its observers see it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransitionEvent<S: FsmState> {
+  from: S,
+  to: S
+}
+
+impl<S: FsmState> TransitionEvent<S> {
+  pub fn new(from: S, to: S) -> TransitionEvent<S> {
+    TransitionEvent {
+      from: from,
+      to: to
+    }
+  }
+
+  pub fn from(&self) -> &S {
+    &self.from
+  }
+
+  pub fn to(&self) -> &S {
+    &self.to
+  }
+}
+
+/// The observers of a machine, which stay reachable once the machine is
+/// subscribed.
+pub struct Observers<S: FsmState> {
+  subscribers: Arc<Mutex<Vec<Sender<TransitionEvent<S>>>>>
+}
+
+impl<S: FsmState> Observers<S> {
+  /// Returns a receiver of the transitions from now on. It is unsubscribed
+  /// when dropped.
+  pub fn subscribe(&self) -> Receiver<TransitionEvent<S>> {
+    let (tx, rx) = mpsc::channel();
+    self.subscribers.lock().unwrap().push(tx);
+    rx
+  }
+
+  fn publish(&self, event: TransitionEvent<S>) {
+    self.subscribers.lock().unwrap().retain(|tx| tx.send(event.clone()).is_ok());
+  }
+}
+
+impl<S: FsmState> Clone for Observers<S> {
+  fn clone(&self) -> Observers<S> {
+    Observers {
+      subscribers: self.subscribers.clone()
+    }
+  }
+}
+
+struct Timeout<M> {
+  after: Duration,
+  /// Builds the message from the generation of the state.
+  make: fn(u64) -> M
+}
+
+pub struct Fsm<M: MsgTrait, E: Error, A: FsmActor<M, E>> {
+  actor: A,
+  state: A::State,
+  handlers: HashMap<A::State, Handler<A, A::State, M, E>>,
+  timeouts: HashMap<A::State, Timeout<M>>,
+  scheduler: Option<SchedulerRef<M>>,
+  /// The timeout of the current state, while it runs.
+  timer: Option<Cancellable>,
+  /// Grows with every move, including to the current state.
+  generation: u64,
+  observers: Observers<A::State>,
+  _types: PhantomData<E>
+}
+
+impl<M: MsgTrait, E: Error, A: FsmActor<M, E>> Fsm<M, E, A> {
+  /// Creates a machine in the `initial` state, without handlers.
+  pub fn new(actor: A, initial: A::State) -> Fsm<M, E, A> {
+    Fsm {
+      actor: actor,
+      state: initial,
+      handlers: HashMap::new(),
+      timeouts: HashMap::new(),
+      scheduler: None,
+      timer: None,
+      generation: 0,
+      observers: Observers {
+        subscribers: Arc::new(Mutex::new(Vec::new()))
+      },
+      _types: PhantomData
+    }
+  }
+
+  /// Handles the messages of `state` with `handler`, in place of the one
+  /// registered before.
+  pub fn when(mut self, state: A::State, handler: Handler<A, A::State, M, E>) -> Fsm<M, E, A> {
+    self.handlers.insert(state, handler);
+    self
+  }
+
+  /// Sends the message built by `timeout` once the machine has been in
+  /// `state` for `after`. The message carries the generation it is given,
+  /// for `FsmActor::timeout_generation`. Timeouts need `with_scheduler`.
+  pub fn with_timeout(mut self, state: A::State, after: Duration, timeout: fn(u64) -> M) -> Fsm<M, E, A> {
+    self.timeouts.insert(state, Timeout {
+      after: after,
+      make: timeout
+    });
+    self
+  }
+
+  pub fn with_scheduler(mut self, scheduler: SchedulerRef<M>) -> Fsm<M, E, A> {
+    self.scheduler = Some(scheduler);
+    self
+  }
+
+  pub fn observers(&self) -> Observers<A::State> {
+    self.observers.clone()
+  }
+
+  pub fn state(&self) -> &A::State {
+    &self.state
+  }
+
+  pub fn actor(&self) -> &A {
+    &self.actor
+  }
+
+  fn apply(&mut self, transition: Transition<A::State>) {
+    match transition {
+      Transition::Stay => {}
+      Transition::Goto(to) => {
+        self.cancel_timer();
+        self.generation += 1;
+        let from = mem::replace(&mut self.state, to.clone());
+        debug!("transition from {:?} to {:?}", from, to);
+        self.actor.on_transition(&from, &to);
+        self.observers.publish(TransitionEvent::new(from, to));
+        self.arm_timer();
+      }
+      Transition::Stop => {
+        self.cancel_timer();
+        self.generation += 1;
+        if let Some(self_ref) = self.actor.context().self_ref() {
+          // the dispatcher finishes the actor once this message is handled
+          if let Err(e) = self_ref.stop() {
+            warn!("cannot stop the machine at {}: {}", self_ref.path().display(), e);
+          }
+        }
+      }
+    }
+  }
+
+  fn arm_timer(&mut self) {
+    let timeout = match self.timeouts.get(&self.state) {
+      Some(timeout) => timeout,
+      None => return
+    };
+    let self_ref = match self.actor.context().self_ref() {
+      Some(self_ref) => self_ref,
+      None => return
+    };
+    match self.scheduler {
+      Some(ref scheduler) => {
+        self.timer = Some(scheduler.tell_after(timeout.after, &self_ref, (timeout.make)(self.generation)));
+      }
+      None => error!("no scheduler for the timeout of state {:?}", self.state)
+    }
+  }
+
+  fn cancel_timer(&mut self) {
+    if let Some(timer) = self.timer.take() {
+      timer.cancel();
+    }
+  }
+}
+
+impl<M: MsgTrait, E: Error, A: FsmActor<M, E>> Actor<M, E> for Fsm<M, E, A> {
+  fn context(&self) -> &ActorContext<M> {
+    self.actor.context()
+  }
+
+  fn on_receive(&mut self, m: &M) -> Result<(), E> {
+    match self.actor.timeout_generation(m) {
+      Some(generation) if generation != self.generation => {
+        debug!("dropping a timeout of generation {} in {}", generation, self.generation);
+        return Ok(());
+      }
+      _ => {}
+    }
+
+    let transition = match self.handlers.get(&self.state).cloned() {
+      Some(handler) => handler(&mut self.actor, m)?,
+      None => self.actor.on_unhandled(&self.state, m)?
+    };
+    self.apply(transition);
+    Ok(())
+  }
+
+  fn pre_start(&mut self) {
+    self.arm_timer();
+  }
+
+  fn post_stop(&mut self) {
+    self.cancel_timer();
+    self.actor.post_stop();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::sync::mpsc::Receiver;
+  use std::thread;
+  use std::time::Duration;
+
+  use react::{ActorSystem, ActorRef};
+  use react::actor::ActorContext;
+  use react::dispatcher::tests::{Msg, Err, wait_until};
+  use super::{FsmActor, Fsm, Transition, TransitionEvent};
+
+  #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+  enum Gate {
+    Locked,
+    Unlocked,
+    Broken
+  }
+
+  /// A turnstile: a coin (`Ping`) unlocks it, a push (`Pong`) locks it
+  /// again, and so does a timeout (`Sleep`). `WhoAmI` stops it.
+  struct Turnstile {
+    context: ActorContext<Msg>,
+    coins: u32,
+    transitions: Arc<Mutex<Vec<(Gate, Gate)>>>,
+    unhandled: Arc<Mutex<Vec<Gate>>>
+  }
+
+  impl Turnstile {
+    fn locked(&mut self, m: &Msg) -> Result<Transition<Gate>, Err> {
+      match *m {
+        Msg::Ping(n) => {
+          self.coins += n;
+          Ok(Transition::Goto(Gate::Unlocked))
+        }
+        Msg::WhoAmI => Ok(Transition::Stop),
+        Msg::Fail => Ok(Transition::Goto(Gate::Broken)),
+        _ => Ok(Transition::Stay)
+      }
+    }
+
+    fn unlocked(&mut self, m: &Msg) -> Result<Transition<Gate>, Err> {
+      match *m {
+        Msg::Pong(_) | Msg::Sleep(_) => Ok(Transition::Goto(Gate::Locked)),
+        _ => Ok(Transition::Stay)
+      }
+    }
+  }
+
+  impl FsmActor<Msg, Err> for Turnstile {
+    type State = Gate;
+
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_transition(&mut self, from: &Gate, to: &Gate) {
+      self.transitions.lock().unwrap().push((*from, *to));
+    }
+
+    fn timeout_generation(&self, m: &Msg) -> Option<u64> {
+      match *m {
+        Msg::Sleep(generation) => Some(generation),
+        _ => None
+      }
+    }
+
+    fn on_unhandled(&mut self, state: &Gate, _: &Msg) -> Result<Transition<Gate>, Err> {
+      self.unhandled.lock().unwrap().push(*state);
+      Ok(Transition::Stay)
+    }
+  }
+
+  struct Fixture {
+    system: ActorSystem<Msg, Err>,
+    transitions: Arc<Mutex<Vec<(Gate, Gate)>>>,
+    unhandled: Arc<Mutex<Vec<Gate>>>,
+    events: Receiver<TransitionEvent<Gate>>
+  }
+
+  fn turnstile(timeout: Duration) -> (Fixture, ActorRef<Msg>) {
+    let system = ActorSystem::new("test");
+    let transitions = Arc::new(Mutex::new(Vec::new()));
+    let unhandled = Arc::new(Mutex::new(Vec::new()));
+    let actor = Turnstile {
+      context: ActorContext::new(),
+      coins: 0,
+      transitions: transitions.clone(),
+      unhandled: unhandled.clone()
+    };
+
+    let fsm = Fsm::new(actor, Gate::Locked)
+      .when(Gate::Locked, Turnstile::locked)
+      .when(Gate::Unlocked, Turnstile::unlocked)
+      .with_timeout(Gate::Unlocked, timeout, Msg::Sleep)
+      .with_scheduler(system.scheduler().handle());
+    let events = fsm.observers().subscribe();
+    let gate = system.dispatcher().subscribe(Box::new(fsm), None);
+
+    let fixture = Fixture {
+      system: system,
+      transitions: transitions,
+      unhandled: unhandled,
+      events: events
+    };
+    (fixture, gate)
+  }
+
+  #[test]
+  fn test_transitions() {
+    let (fixture, gate) = turnstile(Duration::from_secs(60));
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    gate.tell(Msg::Pong(0)).ok().unwrap();
+    gate.tell(Msg::Fail).ok().unwrap();
+    // no handler for a broken turnstile
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    wait_until(|| fixture.unhandled.lock().unwrap().len() == 1);
+
+    let expected = vec![(Gate::Locked, Gate::Unlocked), (Gate::Unlocked, Gate::Locked),
+                        (Gate::Locked, Gate::Broken)];
+    assert_eq!(expected, *fixture.transitions.lock().unwrap());
+    let observed: Vec<_> = fixture.events.try_iter().map(|e| (*e.from(), *e.to())).collect();
+    assert_eq!(expected, observed);
+    assert_eq!(vec![Gate::Broken], *fixture.unhandled.lock().unwrap());
+    drop(fixture.system);
+  }
+
+  #[test]
+  fn test_timeout() {
+    let (fixture, gate) = turnstile(Duration::from_millis(20));
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    let event = fixture.events.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(TransitionEvent::new(Gate::Locked, Gate::Unlocked), event);
+    // locked again by the timeout
+    let event = fixture.events.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(TransitionEvent::new(Gate::Unlocked, Gate::Locked), event);
+
+    // leaving the state cancels its timeout
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    gate.tell(Msg::Pong(0)).ok().unwrap();
+    wait_until(|| fixture.transitions.lock().unwrap().len() == 4);
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(0, fixture.system.scheduler().pending());
+    assert_eq!(4, fixture.transitions.lock().unwrap().len());
+  }
+
+  #[test]
+  fn test_stale_timeout() {
+    let (fixture, gate) = turnstile(Duration::from_secs(60));
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    // a timeout of the locked state, as if it was sent before the coin
+    gate.tell(Msg::Sleep(0)).ok().unwrap();
+    gate.tell(Msg::Ping(1)).ok().unwrap();
+    // the timeout of the current generation still locks it
+    gate.tell(Msg::Sleep(1)).ok().unwrap();
+
+    wait_until(|| fixture.transitions.lock().unwrap().len() >= 2);
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(vec![(Gate::Locked, Gate::Unlocked), (Gate::Unlocked, Gate::Locked)],
+               *fixture.transitions.lock().unwrap());
+  }
+
+  #[test]
+  fn test_stop() {
+    let (fixture, gate) = turnstile(Duration::from_secs(60));
+    gate.tell(Msg::WhoAmI).ok().unwrap();
+    wait_until(|| gate.tell(Msg::Ping(1)).is_err());
+    assert!(fixture.transitions.lock().unwrap().is_empty());
+  }
+}
@@ -0,0 +1,337 @@
+//!
+//! Runtime metrics of dispatchers and actors.
+//!
+//! Every actor counts the messages it handles and its failures, and keeps a
+//! histogram of the time spent in `on_receive`. Every dispatcher counts the
+//! messages it delivers and the time its threads spend waiting for work.
+//! `ActorSystem::metrics` takes a snapshot of all of it, which `prometheus`
+//! renders in the Prometheus text format.
+//!
+
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use super::actor::ActorUri;
+
+/// The upper bounds of the latency buckets, in microseconds. A last bucket
+/// takes everything slower.
+pub const LATENCY_BUCKETS: [u64; 11] =
+  [10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000];
+
+fn micros(d: Duration) -> u64 {
+  d.as_secs() * 1_000_000 + (d.subsec_nanos() / 1_000) as u64
+}
+
+fn from_micros(us: u64) -> Duration {
+  Duration::new(us / 1_000_000, ((us % 1_000_000) * 1_000) as u32)
+}
+
+fn seconds(d: Duration) -> f64 {
+  d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/// A latency histogram over `LATENCY_BUCKETS`.
+pub struct Histogram {
+  counts: Vec<AtomicUsize>,
+  count: AtomicUsize,
+  /// The sum of all samples, in microseconds.
+  sum: AtomicUsize
+}
+
+impl Histogram {
+  pub fn new() -> Histogram {
+    Histogram {
+      counts: (0..LATENCY_BUCKETS.len() + 1).map(|_| AtomicUsize::new(0)).collect(),
+      count: AtomicUsize::new(0),
+      sum: AtomicUsize::new(0)
+    }
+  }
+
+  pub fn record(&self, d: Duration) {
+    let us = micros(d);
+    let idx = LATENCY_BUCKETS.iter().position(|&b| us <= b).unwrap_or(LATENCY_BUCKETS.len());
+    self.counts[idx].fetch_add(1, Ordering::Relaxed);
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self.sum.fetch_add(us as usize, Ordering::Relaxed);
+  }
+
+  pub fn snapshot(&self) -> HistogramSnapshot {
+    HistogramSnapshot {
+      counts: self.counts.iter().map(|c| c.load(Ordering::Relaxed)).collect(),
+      count: self.count.load(Ordering::Relaxed),
+      sum: from_micros(self.sum.load(Ordering::Relaxed) as u64)
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+  /// The number of samples in each of `LATENCY_BUCKETS`, and then in the
+  /// last bucket.
+  pub counts: Vec<usize>,
+  pub count: usize,
+  pub sum: Duration
+}
+
+impl HistogramSnapshot {
+  /// The upper bound of the bucket that holds the `q` quantile, or `None`
+  /// if it falls in the last bucket or there is no sample.
+  pub fn quantile(&self, q: f64) -> Option<Duration> {
+    if self.count == 0 {
+      return None;
+    }
+
+    let rank = (q * self.count as f64).ceil().max(1.0) as usize;
+    let mut seen = 0;
+    for (i, &c) in self.counts.iter().enumerate() {
+      seen += c;
+      if seen >= rank {
+        return LATENCY_BUCKETS.get(i).map(|&us| from_micros(us));
+      }
+    }
+    None
+  }
+}
+
+/// The live counters of an actor.
+pub struct ActorStats {
+  processed: AtomicUsize,
+  errors: AtomicUsize,
+  latency: Histogram
+}
+
+impl ActorStats {
+  pub fn new() -> ActorStats {
+    ActorStats {
+      processed: AtomicUsize::new(0),
+      errors: AtomicUsize::new(0),
+      latency: Histogram::new()
+    }
+  }
+
+  /// Records a call of `on_receive` that took `elapsed`.
+  pub fn record(&self, elapsed: Duration, failed: bool) {
+    self.processed.fetch_add(1, Ordering::Relaxed);
+    if failed {
+      self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    self.latency.record(elapsed);
+  }
+
+  pub fn snapshot(&self, uri: &ActorUri) -> ActorSnapshot {
+    ActorSnapshot {
+      uri: uri.clone(),
+      processed: self.processed.load(Ordering::Relaxed),
+      errors: self.errors.load(Ordering::Relaxed),
+      latency: self.latency.snapshot()
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActorSnapshot {
+  pub uri: ActorUri,
+  /// The messages handled, including the failed ones.
+  pub processed: usize,
+  pub errors: usize,
+  /// The time spent in `on_receive`.
+  pub latency: HistogramSnapshot
+}
+
+struct Idle {
+  total: Duration,
+  /// When each of the threads waiting now started to.
+  waiting: Vec<Instant>
+}
+
+/// The live counters of a dispatcher.
+pub struct DispatcherStats {
+  started: Instant,
+  processed: AtomicUsize,
+  idle: Mutex<Idle>
+}
+
+impl DispatcherStats {
+  pub fn new() -> DispatcherStats {
+    DispatcherStats {
+      started: Instant::now(),
+      processed: AtomicUsize::new(0),
+      idle: Mutex::new(Idle {
+        total: Duration::from_secs(0),
+        waiting: Vec::new()
+      })
+    }
+  }
+
+  /// Records a message handed to an actor.
+  pub fn record_message(&self) {
+    self.processed.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Records a thread starting to wait for work, and returns the token for
+  /// `end_idle`.
+  pub fn begin_idle(&self) -> Instant {
+    let now = Instant::now();
+    self.idle.lock().unwrap().waiting.push(now);
+    now
+  }
+
+  pub fn end_idle(&self, since: Instant) {
+    let mut idle = self.idle.lock().unwrap();
+    if let Some(idx) = idle.waiting.iter().position(|&s| s == since) {
+      idle.waiting.swap_remove(idx);
+    }
+    idle.total += since.elapsed();
+  }
+
+  pub fn snapshot(&self, mailbox_depth: usize, actors: Vec<ActorSnapshot>) -> DispatcherSnapshot {
+    let uptime = self.started.elapsed();
+    let processed = self.processed.load(Ordering::Relaxed);
+    let throughput = if uptime > Duration::from_secs(0) {
+      processed as f64 / seconds(uptime)
+    } else {
+      0.0
+    };
+
+    // a thread waiting now counts up to now
+    let idle = {
+      let idle = self.idle.lock().unwrap();
+      idle.waiting.iter().fold(idle.total, |total, since| total + since.elapsed())
+    };
+
+    DispatcherSnapshot {
+      uptime: uptime,
+      mailbox_depth: mailbox_depth,
+      processed: processed,
+      throughput: throughput,
+      idle: idle,
+      actors: actors
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DispatcherSnapshot {
+  pub uptime: Duration,
+  /// The messages waiting in all mailboxes.
+  pub mailbox_depth: usize,
+  /// The messages handed to actors so far. A broadcast counts once for
+  /// every actor that handles it, whatever the dispatcher, and a message
+  /// that ends in the dead letters counts for none.
+  pub processed: usize,
+  /// The messages processed per second, on average since the start.
+  pub throughput: f64,
+  /// The time the threads of the dispatcher spent waiting for work, summed
+  /// over all threads.
+  pub idle: Duration,
+  pub actors: Vec<ActorSnapshot>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+  pub system: String,
+  pub dispatcher: DispatcherSnapshot
+}
+
+fn escape(value: &str) -> String {
+  value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+  writeln!(out, "# HELP {} {}", name, help).unwrap();
+  writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Renders `snapshot` in the Prometheus text exposition format.
+pub fn prometheus(snapshot: &Snapshot) -> String {
+  let mut out = String::new();
+  let system = escape(&snapshot.system);
+  let d = &snapshot.dispatcher;
+
+  header(&mut out, "react_dispatcher_mailbox_depth", "gauge", "Messages waiting in the mailboxes.");
+  writeln!(out, "react_dispatcher_mailbox_depth{{system=\"{}\"}} {}", system, d.mailbox_depth).unwrap();
+  header(&mut out, "react_dispatcher_processed_total", "counter", "Messages handed to actors.");
+  writeln!(out, "react_dispatcher_processed_total{{system=\"{}\"}} {}", system, d.processed).unwrap();
+  header(&mut out, "react_dispatcher_throughput", "gauge", "Messages processed per second since the start.");
+  writeln!(out, "react_dispatcher_throughput{{system=\"{}\"}} {}", system, d.throughput).unwrap();
+  header(&mut out, "react_dispatcher_idle_seconds_total", "counter", "Time the threads waited for work.");
+  writeln!(out, "react_dispatcher_idle_seconds_total{{system=\"{}\"}} {}", system, seconds(d.idle)).unwrap();
+
+  header(&mut out, "react_actor_processed_total", "counter", "Messages handled by an actor.");
+  for a in &d.actors {
+    writeln!(out, "react_actor_processed_total{{system=\"{}\",actor=\"{}\"}} {}",
+             system, escape(&a.uri.display()), a.processed).unwrap();
+  }
+  header(&mut out, "react_actor_errors_total", "counter", "Messages an actor failed to handle.");
+  for a in &d.actors {
+    writeln!(out, "react_actor_errors_total{{system=\"{}\",actor=\"{}\"}} {}",
+             system, escape(&a.uri.display()), a.errors).unwrap();
+  }
+  header(&mut out, "react_actor_receive_seconds", "histogram", "Time spent in on_receive.");
+  for a in &d.actors {
+    let labels = format!("system=\"{}\",actor=\"{}\"", system, escape(&a.uri.display()));
+    let mut cumulative = 0;
+    for (i, &c) in a.latency.counts.iter().enumerate() {
+      cumulative += c;
+      let le = match LATENCY_BUCKETS.get(i) {
+        Some(&us) => format!("{}", us as f64 / 1e6),
+        None => "+Inf".to_owned()
+      };
+      writeln!(out, "react_actor_receive_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, cumulative).unwrap();
+    }
+    writeln!(out, "react_actor_receive_seconds_sum{{{}}} {}", labels, seconds(a.latency.sum)).unwrap();
+    writeln!(out, "react_actor_receive_seconds_count{{{}}} {}", labels, a.latency.count).unwrap();
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use react::actor::ActorUri;
+  use super::{Histogram, ActorStats, DispatcherStats, Snapshot, from_micros, prometheus};
+
+  #[test]
+  fn test_histogram() {
+    let h = Histogram::new();
+    for us in &[5, 20, 20, 700, 2_000_000] {
+      h.record(from_micros(*us));
+    }
+
+    let s = h.snapshot();
+    assert_eq!(5, s.count);
+    assert_eq!(from_micros(2_000_745), s.sum);
+    assert_eq!(vec![1, 2, 0, 0, 1, 0, 0, 0, 0, 0, 0, 1], s.counts);
+    assert_eq!(Some(from_micros(50)), s.quantile(0.5));
+    assert_eq!(Some(from_micros(1_000)), s.quantile(0.8));
+    assert_eq!(None, s.quantile(1.0));
+  }
+
+  #[test]
+  fn test_prometheus() {
+    let actor = ActorStats::new();
+    actor.record(from_micros(30), false);
+    actor.record(from_micros(300), true);
+    let dispatcher = DispatcherStats::new();
+    dispatcher.record_message();
+    dispatcher.record_message();
+
+    let uri = ActorUri::local("user/0");
+    let snapshot = Snapshot {
+      system: "te\"st".to_owned(),
+      dispatcher: dispatcher.snapshot(3, vec![actor.snapshot(&uri)])
+    };
+    let text = prometheus(&snapshot);
+    let labels = format!("system=\"te\\\"st\",actor=\"{}\"", uri.display());
+
+    assert!(text.contains("# TYPE react_actor_receive_seconds histogram\n"));
+    assert!(text.contains("react_dispatcher_mailbox_depth{system=\"te\\\"st\"} 3\n"));
+    assert!(text.contains("react_dispatcher_processed_total{system=\"te\\\"st\"} 2\n"));
+    assert!(text.contains(&format!("react_actor_processed_total{{{}}} 2\n", labels)));
+    assert!(text.contains(&format!("react_actor_errors_total{{{}}} 1\n", labels)));
+    assert!(text.contains(&format!("react_actor_receive_seconds_bucket{{{},le=\"0.00005\"}} 1\n", labels)));
+    assert!(text.contains(&format!("react_actor_receive_seconds_bucket{{{},le=\"+Inf\"}} 2\n", labels)));
+    assert!(text.contains(&format!("react_actor_receive_seconds_count{{{}}} 2\n", labels)));
+  }
+}
@@ -3,37 +3,193 @@
 //!
 
 pub mod actor;
+pub mod cluster;
+pub mod codec;
 pub mod dispatcher;
+pub mod event_bus;
+pub mod fsm;
+pub mod metrics;
+pub mod persistence;
+pub mod remote;
+pub mod reply;
+pub mod route;
+pub mod scheduler;
+pub mod supervision;
+pub mod testkit;
 
+use std::net::SocketAddr;
 use std::sync::{Arc};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
 
 use env_logger;
-use rustc_serialize::Decodable;
+use rustc_serialize::{Encodable, Decodable};
+use rustc_serialize::json::Json;
 
-pub use self::dispatcher::Dispatcher;
-pub use self::actor::Actor;
+pub use self::dispatcher::{Dispatcher, PoolDispatcher, Shutdown, Overflow, DeadLetter, DeadLetterReason};
+pub use self::actor::{Actor, ActorRef, ActorUri};
+pub use self::cluster::{Cluster, ClusterConfig};
+pub use self::event_bus::EventBus;
+pub use self::fsm::{Fsm, FsmActor, Transition};
+pub use self::reply::ReplyHandle;
+pub use self::route::{Router, RouterActor};
+pub use self::supervision::{Directive, Supervisor};
 
+use err::ReactErr;
 use self::dispatcher::AsyncDispatcher;
+use self::codec::Codecs;
+use self::metrics::Snapshot;
+use self::remote::Transport;
+use self::scheduler::{Scheduler, Cancellable};
 
-pub trait MsgTrait: 'static + Sync + Send + Decodable {}
-pub trait Error: 'static + Sized + Sync + Send {}
+pub trait MsgTrait: 'static + Sync + Send + Encodable + Decodable {
+  /// Names the type of the message in its encoding, so that a peer can
+  /// refuse a message of another type. Empty skips the check.
+  fn type_tag() -> &'static str {
+    ""
+  }
+
+  /// The version of the encoding, to bump when the message changes.
+  fn version() -> u16 {
+    0
+  }
+
+  /// Rewrites the value tree of a message encoded with another `version`
+  /// into the current one, before it is decoded. The default keeps it as it
+  /// is, which is enough while fields are only added as `Option`s.
+  fn upgrade(_version: u16, value: Json) -> Result<Json, String> {
+    Ok(value)
+  }
+}
+pub trait Error: 'static + Sized + Sync + Send {
+  /// Turns a panic of an actor into a failure, for its supervisor to decide
+  /// on like any other.
+  fn from_panic(message: String) -> Self;
+}
 
 pub type Predicate<T> = Fn(&T) -> bool;
 
 pub struct ActorSystem<M: MsgTrait, E: Error> {
-  dispatcher: Arc<Box<Dispatcher<M, E>>>  
+  name: String,
+  dispatcher: Arc<Box<Dispatcher<M, E>>>,
+  transport: Option<Transport<M>>,
+  scheduler: Scheduler<M, E>
 }
 
 impl<M: MsgTrait, E: Error> ActorSystem<M, E> {
   pub fn new(name: &str) -> ActorSystem<M, E> {
+    ActorSystem::with_dispatcher(name, Box::new(AsyncDispatcher::new()))
+  }
+
+  /// Creates an actor system on top of the given dispatcher, for example a
+  /// `PoolDispatcher` to run actors on several threads.
+  pub fn with_dispatcher(name: &str, dispatcher: Box<Dispatcher<M, E>>) -> ActorSystem<M, E> {
+    let dispatcher = Arc::new(dispatcher);
+    let scheduler = Scheduler::new(Arc::downgrade(&dispatcher));
     ActorSystem {
-      dispatcher: Arc::new(Box::new(AsyncDispatcher::new()) as Box<Dispatcher<M, E>>)
+      name: name.to_owned(),
+      dispatcher: dispatcher,
+      transport: None,
+      scheduler: scheduler
     } 
   }
 
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
   pub fn dispatcher(&self) -> Arc<Box<Dispatcher<M, E>>> {
     self.dispatcher.clone()
   }
+
+  pub fn scheduler(&self) -> &Scheduler<M, E> {
+    &self.scheduler
+  }
+
+  /// A snapshot of the counters of the dispatcher and its actors. See
+  /// `metrics::prometheus` to export it.
+  pub fn metrics(&self) -> Snapshot {
+    Snapshot {
+      system: self.name.clone(),
+      dispatcher: self.dispatcher.metrics()
+    }
+  }
+
+  /// Subscribes to the messages that could not be delivered from now on.
+  pub fn dead_letters(&self) -> Receiver<DeadLetter<M>> {
+    self.dispatcher.dead_letters().subscribe()
+  }
+
+  /// Sends `m` to every accepting actor once `delay` has passed.
+  pub fn send_after(&self, delay: Duration, m: M) -> Cancellable {
+    self.scheduler.send_after(delay, m)
+  }
+
+  /// Accepts messages from other systems on `host:port`, and returns the
+  /// address actually bound. Port 0 picks a free port.
+  pub fn listen(&mut self, host: &str, port: i32) -> Result<SocketAddr, ReactErr> {
+    self.listen_with_codecs(host, port, Arc::new(Codecs::json()))
+  }
+
+  /// Like `listen`, but sends messages with the default codec of `codecs`.
+  pub fn listen_with_codecs(&mut self, host: &str, port: i32, codecs: Arc<Codecs>)
+      -> Result<SocketAddr, ReactErr> {
+    let transport = Transport::bind_with_codecs(host, port, Arc::downgrade(&self.dispatcher), codecs)?;
+    let addr = transport.local_addr();
+    self.transport = Some(transport);
+    Ok(addr)
+  }
+
+  /// The address other systems reach this one at, if it listens.
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.transport.as_ref().map(|t| t.local_addr())
+  }
+
+  /// Sends `m` to the actor at `to`, over the network if `to` lives in
+  /// another system.
+  pub fn send_to(&self, to: &ActorUri, m: M) -> Result<(), ReactErr> {
+    match self.transport {
+      Some(ref transport) if !self.is_local(to) => transport.send_to(to, &m),
+      _ => self.dispatcher.send_to(to, m)
+    }
+  }
+
+  fn is_local(&self, uri: &ActorUri) -> bool {
+    if uri.port() == 0 {
+      return true;
+    }
+
+    match self.local_addr() {
+      Some(addr) => {
+        let host = uri.host_name();
+        uri.port() == addr.port() as i32 &&
+          (host == "localhost" || host == addr.ip().to_string())
+      }
+      None => false
+    }
+  }
+}
+
+impl<M: MsgTrait + Clone, E: Error> ActorSystem<M, E> {
+  /// Subscribes `routees` and a router in front of them, and returns a
+  /// handle to the router. The routees only get what the router forwards.
+  pub fn router(&self, router: Box<Router<M>>, routees: Vec<Box<Actor<M, E>>>) -> ActorRef<M> {
+    let uris = routees.into_iter()
+      .map(|actor| self.dispatcher.subscribe(actor, Some(Box::new(|_: &M| false))).path().clone())
+      .collect();
+    let actor = RouterActor::new(router, uris, Arc::downgrade(&self.dispatcher));
+    self.dispatcher.subscribe(Box::new(actor), None)
+  }
+
+  /// Sends `m` to every accepting actor every `interval`.
+  pub fn send_every(&self, interval: Duration, m: M) -> Cancellable {
+    self.scheduler.send_every(interval, m)
+  }
+
+  /// Creates an event bus whose subscribers live in this system.
+  pub fn new_event_bus(&self) -> EventBus<M, E> {
+    EventBus::new(Arc::downgrade(&self.dispatcher))
+  }
 }
 
 #[cfg(test)]
@@ -54,7 +210,11 @@ mod tests {
     Fatal
   } 
 
-  impl Error for Err {}
+  impl Error for Err {
+    fn from_panic(_: String) -> Err {
+      Err::Fatal
+    }
+  }
   unsafe impl Send for Err {}
   unsafe impl Sync for Err {}
 
@@ -0,0 +1,418 @@
+//!
+//! An append-only journal of events on local disk.
+//!
+//! Every persistence id has a journal file `<id>.journal` and a snapshot
+//! file `<id>.snapshot` in the journal directory. Both hold frames with a
+//! four-byte length prefix, like the remote transport, and every frame is
+//! a sequence number and a value, encoded with the codecs of the journal.
+//! A crash in the middle of an append leaves a torn frame at the end of the
+//! journal, which is cut off on the next open. An append that fails without
+//! a crash cuts its own frames off right away.
+//!
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rustc_serialize::{Encodable, Decodable};
+
+use err::ReactErr;
+use react::codec::{Codecs, codec_err};
+use react::remote::{read_frame, write_frame};
+
+/// When appended events are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+  /// After every append. Nothing persisted is lost in a crash.
+  Always,
+  /// After every `n` appends.
+  Every(usize),
+  /// On the first append once `interval` has passed since the last sync.
+  Interval(Duration),
+  /// Never, leaving it to the operating system.
+  Never
+}
+
+fn encode<T: Encodable>(codecs: &Codecs, seq: u64, value: &T) -> Result<Vec<u8>, ReactErr> {
+  codecs.encode("", 0, &(seq, value))
+}
+
+fn decode<T: Decodable>(codecs: &Codecs, payload: &[u8]) -> Result<(u64, T), ReactErr> {
+  codecs.decode(payload).map(|(_, frame)| frame)
+}
+
+/// Decodes only the sequence number of a frame.
+fn decode_seq(codecs: &Codecs, payload: &[u8]) -> Result<u64, ReactErr> {
+  let (_, value) = codecs.decode_value(payload)?;
+  value.as_array()
+    .and_then(|a| a.first())
+    .and_then(|seq| seq.as_u64())
+    .ok_or(codec_err("a frame without a sequence number"))
+}
+
+/// Reads the frames of `path` until its end, and returns them with the
+/// offset right after the last whole frame.
+fn read_frames(path: &Path) -> Result<(Vec<Vec<u8>>, u64), ReactErr> {
+  let file = match File::open(path) {
+    Ok(file) => file,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+    Err(e) => return Err(ReactErr::from(e))
+  };
+
+  let mut reader = BufReader::new(file);
+  let mut frames = Vec::new();
+  let mut end = 0;
+  loop {
+    match read_frame(&mut reader) {
+      Ok(frame) => {
+        end += 4 + frame.len() as u64;
+        frames.push(frame);
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+      Err(e) => return Err(ReactErr::from(e))
+    }
+  }
+  Ok((frames, end))
+}
+
+/// A file that can be cut back to a shorter length.
+trait Truncate: Write + Seek {
+  fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Truncate for File {
+  fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.set_len(len)
+  }
+}
+
+/// Writes `buf` at the end of `file`. A write that fails midway is cut off
+/// again, so that the next one does not land after torn bytes.
+fn append_all<F: Truncate>(file: &mut F, buf: &[u8]) -> io::Result<()> {
+  let end = file.seek(SeekFrom::End(0))?;
+  if let Err(e) = file.write_all(buf) {
+    file.truncate(end)?;
+    file.seek(SeekFrom::Start(end))?;
+    return Err(e);
+  }
+  Ok(())
+}
+
+/// Replaces `path` with `frames` atomically, through a temporary file.
+fn rewrite(path: &Path, frames: &[Vec<u8>]) -> Result<(), ReactErr> {
+  let tmp = path.with_extension("tmp");
+  {
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    for frame in frames {
+      write_frame(&mut w, frame)?;
+    }
+    w.flush()?;
+    w.get_ref().sync_all()?;
+  }
+  fs::rename(&tmp, path)?;
+  Ok(())
+}
+
+pub struct FileJournal {
+  path: PathBuf,
+  snapshot_path: PathBuf,
+  file: File,
+  fsync: Fsync,
+  codecs: Arc<Codecs>,
+  /// The sequence number of the last event appended.
+  seq: u64,
+  unsynced: usize,
+  last_sync: Instant
+}
+
+impl FileJournal {
+  /// Opens the journal of `id` in `dir`, creating both if needed. It writes
+  /// JSON.
+  pub fn open(dir: &Path, id: &str, fsync: Fsync) -> Result<FileJournal, ReactErr> {
+    FileJournal::open_with_codecs(dir, id, fsync, Arc::new(Codecs::json()))
+  }
+
+  /// Opens the journal of `id` in `dir`, writing with the default codec of
+  /// `codecs`. A journal written with another codec stays readable as long
+  /// as `codecs` has it too.
+  pub fn open_with_codecs(dir: &Path, id: &str, fsync: Fsync, codecs: Arc<Codecs>)
+      -> Result<FileJournal, ReactErr> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.journal", id));
+    let snapshot_path = dir.join(format!("{}.snapshot", id));
+
+    // the last event tells the next sequence number, and a compacted journal
+    // may be empty, which leaves it to the snapshot
+    let (frames, end) = read_frames(&path)?;
+    let mut seq = match frames.last() {
+      Some(frame) => decode_seq(&codecs, frame)?,
+      None => 0
+    };
+    if let Some(frame) = read_frames(&snapshot_path)?.0.last() {
+      seq = seq.max(decode_seq(&codecs, frame)?);
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).create(true).open(&path)?;
+    if file.metadata()?.len() > end {
+      warn!("cutting a torn event off the end of {}", path.display());
+      file.set_len(end)?;
+      file.sync_all()?;
+    }
+    file.seek(SeekFrom::End(0))?;
+
+    Ok(FileJournal {
+      path: path,
+      snapshot_path: snapshot_path,
+      file: file,
+      fsync: fsync,
+      codecs: codecs,
+      seq: seq,
+      unsynced: 0,
+      last_sync: Instant::now()
+    })
+  }
+
+  /// The sequence number of the last event.
+  pub fn seq(&self) -> u64 {
+    self.seq
+  }
+
+  /// Appends `events`, and returns the sequence number of the last one.
+  pub fn append<T: Encodable>(&mut self, events: &[T]) -> Result<u64, ReactErr> {
+    let mut buf = Vec::new();
+    let mut seq = self.seq;
+    for event in events {
+      seq += 1;
+      write_frame(&mut buf, &encode(&self.codecs, seq, event)?)?;
+    }
+    // one write per batch, so that a crash tears at most its last frame
+    append_all(&mut self.file, &buf)?;
+    self.seq = seq;
+
+    self.unsynced += 1;
+    let sync = match self.fsync {
+      Fsync::Always => true,
+      Fsync::Every(n) => self.unsynced >= n,
+      Fsync::Interval(interval) => self.last_sync.elapsed() >= interval,
+      Fsync::Never => false
+    };
+    if sync {
+      self.sync()?;
+    }
+    Ok(seq)
+  }
+
+  /// Forces the appended events to disk.
+  pub fn sync(&mut self) -> Result<(), ReactErr> {
+    self.file.sync_data()?;
+    self.unsynced = 0;
+    self.last_sync = Instant::now();
+    Ok(())
+  }
+
+  /// Calls `f` with every event after `from`, in order, and returns the
+  /// sequence number of the last one.
+  pub fn replay<T, F>(&self, from: u64, mut f: F) -> Result<u64, ReactErr>
+      where T: Decodable, F: FnMut(u64, T) {
+    let mut last = from;
+    for frame in read_frames(&self.path)?.0 {
+      let (seq, event) = decode(&self.codecs, &frame)?;
+      if seq > from {
+        f(seq, event);
+        last = seq;
+      }
+    }
+    Ok(last)
+  }
+
+  /// Saves `state` as the state after the event `seq`, replacing the last
+  /// snapshot.
+  pub fn save_snapshot<S: Encodable>(&mut self, seq: u64, state: &S) -> Result<(), ReactErr> {
+    rewrite(&self.snapshot_path, &[encode(&self.codecs, seq, state)?])
+  }
+
+  /// Loads the last snapshot and the sequence number it was taken at.
+  pub fn load_snapshot<S: Decodable>(&self) -> Result<Option<(u64, S)>, ReactErr> {
+    match read_frames(&self.snapshot_path)?.0.pop() {
+      Some(frame) => decode(&self.codecs, &frame).map(Some),
+      None => Ok(None)
+    }
+  }
+
+  /// Drops the events up to `seq` from the journal, and returns how many
+  /// there were. They must be covered by a snapshot first.
+  pub fn compact(&mut self, seq: u64) -> Result<usize, ReactErr> {
+    let frames = read_frames(&self.path)?.0;
+    let total = frames.len();
+    let mut kept = Vec::with_capacity(total);
+    for frame in frames {
+      if decode_seq(&self.codecs, &frame)? > seq {
+        kept.push(frame);
+      }
+    }
+    let dropped = total - kept.len();
+
+    self.sync()?;
+    rewrite(&self.path, &kept)?;
+    self.file = OpenOptions::new().append(true).open(&self.path)?;
+    Ok(dropped)
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use std::env;
+  use std::fs::{self, OpenOptions};
+  use std::io::{self, Cursor, Seek, SeekFrom, Write};
+  use std::path::PathBuf;
+  use std::sync::Arc;
+  use std::time::{SystemTime, UNIX_EPOCH};
+
+  use react::codec::Codecs;
+  use super::{FileJournal, Fsync, Truncate, append_all};
+
+  /// A fresh directory, removed when dropped.
+  pub struct TempDir(pub PathBuf);
+
+  impl TempDir {
+    /// `name` keeps the tests that run at the same time apart.
+    pub fn new(name: &str) -> TempDir {
+      let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+      let dir = env::temp_dir().join(format!("radish-journal-{}-{}", name, nanos));
+      let _ = fs::remove_dir_all(&dir);
+      TempDir(dir)
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = fs::remove_dir_all(&self.0);
+    }
+  }
+
+  fn events(journal: &FileJournal, from: u64) -> Vec<(u64, String)> {
+    let mut events = Vec::new();
+    journal.replay(from, |seq, e: String| events.push((seq, e))).ok().unwrap();
+    events
+  }
+
+  #[test]
+  fn test_append_replay() {
+    let dir = TempDir::new("append");
+    {
+      let mut journal = FileJournal::open(&dir.0, "a", Fsync::Always).ok().unwrap();
+      assert_eq!(2, journal.append(&["x", "y"]).ok().unwrap());
+      assert_eq!(3, journal.append(&["z"]).ok().unwrap());
+    }
+
+    let journal = FileJournal::open(&dir.0, "a", Fsync::Never).ok().unwrap();
+    assert_eq!(3, journal.seq());
+    assert_eq!(vec![(2, "y".to_owned()), (3, "z".to_owned())], events(&journal, 1));
+  }
+
+  #[test]
+  fn test_torn_frame() {
+    let dir = TempDir::new("torn");
+    {
+      let mut journal = FileJournal::open(&dir.0, "a", Fsync::Every(2)).ok().unwrap();
+      journal.append(&["x"]).ok().unwrap();
+    }
+    // a crash in the middle of the next append
+    let path = dir.0.join("a.journal");
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[0, 0, 0, 9, b'[']).unwrap();
+
+    let mut journal = FileJournal::open(&dir.0, "a", Fsync::Always).ok().unwrap();
+    assert_eq!(1, journal.seq());
+    journal.append(&["y"]).ok().unwrap();
+    assert_eq!(vec![(1, "x".to_owned()), (2, "y".to_owned())], events(&journal, 0));
+  }
+
+  /// A file that is full after `space` more bytes.
+  struct FullFile {
+    data: Cursor<Vec<u8>>,
+    space: usize
+  }
+
+  impl Write for FullFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      if self.space == 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "no space left"));
+      }
+      let n = buf.len().min(self.space);
+      self.space -= n;
+      self.data.write(&buf[..n])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  impl Seek for FullFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+      self.data.seek(pos)
+    }
+  }
+
+  impl Truncate for FullFile {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+      self.data.get_mut().truncate(len as usize);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_failed_append() {
+    let mut file = FullFile { data: Cursor::new(Vec::new()), space: 8 };
+    append_all(&mut file, b"abc").unwrap();
+    // the part that fit is cut off again
+    assert!(append_all(&mut file, b"defghi").is_err());
+    assert_eq!(b"abc".to_vec(), *file.data.get_ref());
+
+    file.space = 3;
+    append_all(&mut file, b"def").unwrap();
+    assert_eq!(b"abcdef".to_vec(), *file.data.get_ref());
+  }
+
+  #[test]
+  fn test_snapshot_compact() {
+    let dir = TempDir::new("compact");
+    let mut journal = FileJournal::open(&dir.0, "a", Fsync::Always).ok().unwrap();
+    assert_eq!(None, journal.load_snapshot::<u32>().ok().unwrap());
+    journal.append(&["x", "y", "z"]).ok().unwrap();
+
+    journal.save_snapshot(2, &7u32).ok().unwrap();
+    assert_eq!(Some((2, 7u32)), journal.load_snapshot().ok().unwrap());
+    assert_eq!(2, journal.compact(2).ok().unwrap());
+    journal.append(&["w"]).ok().unwrap();
+    assert_eq!(vec![(3, "z".to_owned()), (4, "w".to_owned())], events(&journal, 0));
+
+    // the sequence survives a journal compacted down to nothing
+    journal.save_snapshot(4, &9u32).ok().unwrap();
+    journal.compact(4).ok().unwrap();
+    drop(journal);
+    let journal = FileJournal::open(&dir.0, "a", Fsync::Always).ok().unwrap();
+    assert_eq!(4, journal.seq());
+    assert!(events(&journal, 0).is_empty());
+  }
+
+  #[test]
+  fn test_codecs() {
+    let dir = TempDir::new("codecs");
+    {
+      let codecs = Arc::new(Codecs::binary());
+      let mut journal = FileJournal::open_with_codecs(&dir.0, "a", Fsync::Always, codecs).ok().unwrap();
+      journal.append(&["x", "y"]).ok().unwrap();
+      journal.save_snapshot(1, &7u32).ok().unwrap();
+    }
+
+    // a journal written in binary is still readable after switching to JSON
+    let mut journal = FileJournal::open(&dir.0, "a", Fsync::Always).ok().unwrap();
+    assert_eq!(2, journal.seq());
+    journal.append(&["z"]).ok().unwrap();
+    assert_eq!(Some((1, 7u32)), journal.load_snapshot().ok().unwrap());
+    assert_eq!(vec![(2, "y".to_owned()), (3, "z".to_owned())], events(&journal, 1));
+  }
+}
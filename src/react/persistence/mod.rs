@@ -0,0 +1,349 @@
+//!
+//! Event-sourced actors.
+//!
+//! A `PersistentActor` turns each message into events instead of changing
+//! its state directly. The events are appended to the journal of the actor
+//! first, and applied only then, so that the state can be rebuilt after a
+//! restart by replaying them. `Persistent` wraps such an actor into an
+//! `Actor` for a dispatcher: it recovers the state in `pre_start`, from the
+//! last snapshot and the events after it, and takes a new snapshot every
+//! few events if configured to.
+//!
+
+pub mod journal;
+
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustc_serialize::{Encodable, Decodable};
+
+use err::ReactErr;
+use super::{MsgTrait, Error};
+use super::actor::{Actor, ActorContext};
+use super::codec::Codecs;
+
+pub use self::journal::{FileJournal, Fsync};
+
+pub trait PersistentActor<M: MsgTrait, E: Error>: Send + Sync {
+  type Event: Encodable + Decodable;
+  type Snapshot: Encodable + Decodable;
+
+  fn context(&self) -> &ActorContext<M>;
+
+  /// Names the journal of the actor. It must stay the same across restarts.
+  fn persistence_id(&self) -> String;
+
+  /// Turns a message into the events to persist, which may be none.
+  fn on_command(&mut self, m: &M) -> Result<Vec<Self::Event>, E>;
+
+  /// Applies a persisted event to the state, both after `on_command` and
+  /// while recovering.
+  fn apply(&mut self, event: &Self::Event);
+
+  /// Called once the events of `m` are persisted and applied, for example
+  /// to reply.
+  fn on_persisted(&mut self, _m: &M, _events: &[Self::Event]) {}
+
+  fn snapshot(&self) -> Self::Snapshot;
+
+  /// Replaces the state with a snapshot, before the events after it are
+  /// replayed.
+  fn restore(&mut self, snapshot: Self::Snapshot);
+
+  /// Called once the state is recovered, with the sequence number of the
+  /// last event.
+  fn on_recovered(&mut self, _seq: u64) {}
+
+  fn post_stop(&mut self) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct JournalConfig {
+  dir: PathBuf,
+  fsync: Fsync,
+  codecs: Arc<Codecs>,
+  snapshot_every: Option<u64>,
+  compact: bool
+}
+
+impl JournalConfig {
+  /// Keeps the journals in `dir` as JSON, syncs every append, and never
+  /// takes a snapshot.
+  pub fn new<P: AsRef<Path>>(dir: P) -> JournalConfig {
+    JournalConfig {
+      dir: dir.as_ref().to_path_buf(),
+      fsync: Fsync::Always,
+      codecs: Arc::new(Codecs::json()),
+      snapshot_every: None,
+      compact: false
+    }
+  }
+
+  pub fn with_fsync(mut self, fsync: Fsync) -> JournalConfig {
+    self.fsync = fsync;
+    self
+  }
+
+  pub fn with_codecs(mut self, codecs: Arc<Codecs>) -> JournalConfig {
+    self.codecs = codecs;
+    self
+  }
+
+  /// Takes a snapshot once `events` events were persisted since the last
+  /// one. With `compact`, the events it covers are then dropped from the
+  /// journal.
+  pub fn with_snapshots(mut self, events: u64, compact: bool) -> JournalConfig {
+    assert!(events > 0, "snapshots need at least one event between them");
+    self.snapshot_every = Some(events);
+    self.compact = compact;
+    self
+  }
+}
+
+/// Runs a `PersistentActor` as an `Actor`. A journal that cannot be opened,
+/// read or written fails the message being handled with the error converted
+/// to `E`, for the supervisor to decide.
+pub struct Persistent<M: MsgTrait, E: Error, A: PersistentActor<M, E>> {
+  actor: A,
+  config: JournalConfig,
+  journal: Option<FileJournal>,
+  /// Why the recovery failed, until a message reports it.
+  failure: Option<ReactErr>,
+  since_snapshot: u64,
+  _types: PhantomData<(M, E)>
+}
+
+impl<M, E, A> Persistent<M, E, A> where M: MsgTrait, E: Error + From<ReactErr>, A: PersistentActor<M, E> {
+  pub fn new(actor: A, config: JournalConfig) -> Persistent<M, E, A> {
+    Persistent {
+      actor: actor,
+      config: config,
+      journal: None,
+      failure: None,
+      since_snapshot: 0,
+      _types: PhantomData
+    }
+  }
+
+  pub fn actor(&self) -> &A {
+    &self.actor
+  }
+
+  fn recover(&mut self) -> Result<(), ReactErr> {
+    let id = self.actor.persistence_id();
+    let journal = FileJournal::open_with_codecs(&self.config.dir, &id, self.config.fsync,
+                                                self.config.codecs.clone())?;
+
+    let from = match journal.load_snapshot()? {
+      Some((seq, snapshot)) => {
+        self.actor.restore(snapshot);
+        seq
+      }
+      None => 0
+    };
+    let actor = &mut self.actor;
+    let last = journal.replay(from, |_, event: A::Event| actor.apply(&event))?;
+
+    debug!("{} recovered up to event {}, {} of them replayed", id, last, last - from);
+    self.since_snapshot = last - from;
+    self.journal = Some(journal);
+    self.actor.on_recovered(last);
+    Ok(())
+  }
+
+  fn persist(&mut self, m: &M) -> Result<(), E> {
+    if let Some(e) = self.failure.take() {
+      return Err(E::from(e));
+    }
+    // the failure of the last recovery was reported, so try again
+    if self.journal.is_none() {
+      self.recover().map_err(E::from)?;
+    }
+
+    let events = self.actor.on_command(m)?;
+    if events.is_empty() {
+      self.actor.on_persisted(m, &events);
+      return Ok(());
+    }
+
+    self.journal.as_mut().unwrap().append(&events).map_err(E::from)?;
+    for event in &events {
+      self.actor.apply(event);
+    }
+    self.since_snapshot += events.len() as u64;
+    self.actor.on_persisted(m, &events);
+
+    if self.config.snapshot_every.map_or(false, |n| self.since_snapshot >= n) {
+      self.take_snapshot().map_err(E::from)?;
+    }
+    Ok(())
+  }
+
+  fn take_snapshot(&mut self) -> Result<(), ReactErr> {
+    let journal = self.journal.as_mut().unwrap();
+    let seq = journal.seq();
+    journal.save_snapshot(seq, &self.actor.snapshot())?;
+    self.since_snapshot = 0;
+
+    if self.config.compact {
+      let dropped = journal.compact(seq)?;
+      debug!("{} compacted {} events", self.actor.persistence_id(), dropped);
+    }
+    Ok(())
+  }
+}
+
+impl<M, E, A> Actor<M, E> for Persistent<M, E, A>
+    where M: MsgTrait, E: Error + From<ReactErr>, A: PersistentActor<M, E> {
+  fn context(&self) -> &ActorContext<M> {
+    self.actor.context()
+  }
+
+  fn on_receive(&mut self, m: &M) -> Result<(), E> {
+    self.persist(m)
+  }
+
+  fn pre_start(&mut self) {
+    if let Err(e) = self.recover() {
+      error!("cannot recover {}: {}", self.actor.persistence_id(), e);
+      self.failure = Some(e);
+    }
+  }
+
+  fn post_stop(&mut self) {
+    if let Some(ref mut journal) = self.journal {
+      if let Err(e) = journal.sync() {
+        error!("cannot sync the journal of {}: {}", self.actor.persistence_id(), e);
+      }
+    }
+    self.actor.post_stop();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs::{self, File};
+  use std::sync::{Arc, Mutex};
+
+  use err::ReactErr;
+  use react::actor::ActorContext;
+  use react::dispatcher::{Dispatcher, CallingThreadDispatcher};
+  use react::dispatcher::tests::{Msg, Err};
+  use super::{PersistentActor, Persistent, JournalConfig, FileJournal, Fsync};
+  use super::journal::tests::TempDir;
+
+  impl From<ReactErr> for Err {
+    fn from(_: ReactErr) -> Err {
+      Err::Fatal
+    }
+  }
+
+  /// Sums the pings it receives, and reports the sum on every message.
+  struct Counter {
+    context: ActorContext<Msg>,
+    sum: u32,
+    sums: Arc<Mutex<Vec<u32>>>
+  }
+
+  impl Counter {
+    fn new(sums: Arc<Mutex<Vec<u32>>>) -> Counter {
+      Counter {
+        context: ActorContext::new(),
+        sum: 0,
+        sums: sums
+      }
+    }
+  }
+
+  impl PersistentActor<Msg, Err> for Counter {
+    type Event = u32;
+    type Snapshot = u32;
+
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn persistence_id(&self) -> String {
+      "counter".to_owned()
+    }
+
+    fn on_command(&mut self, m: &Msg) -> Result<Vec<u32>, Err> {
+      match *m {
+        Msg::Ping(n) => Ok(vec![n]),
+        Msg::Fail => Err(Err::Fatal),
+        _ => Ok(Vec::new())
+      }
+    }
+
+    fn apply(&mut self, event: &u32) {
+      self.sum += *event;
+    }
+
+    fn on_persisted(&mut self, _: &Msg, _: &[u32]) {
+      self.sums.lock().unwrap().push(self.sum);
+    }
+
+    fn snapshot(&self) -> u32 {
+      self.sum
+    }
+
+    fn restore(&mut self, snapshot: u32) {
+      self.sum = snapshot;
+    }
+  }
+
+  fn run(config: &JournalConfig, pings: &[u32]) -> Vec<u32> {
+    let sums = Arc::new(Mutex::new(Vec::new()));
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    dispatcher.subscribe(Box::new(Persistent::new(Counter::new(sums.clone()), config.clone())), None);
+    for &n in pings {
+      dispatcher.send(Msg::Ping(n)).ok().unwrap();
+    }
+    dispatcher.send(Msg::Ignore).ok().unwrap();
+    let sums = sums.lock().unwrap().clone();
+    sums
+  }
+
+  #[test]
+  fn test_recover() {
+    let dir = TempDir::new("recover");
+    let config = JournalConfig::new(&dir.0).with_fsync(Fsync::Every(2));
+    assert_eq!(vec![1, 3, 6, 6], run(&config, &[1, 2, 3]));
+    assert_eq!(vec![10, 10], run(&config, &[4]));
+  }
+
+  #[test]
+  fn test_snapshots() {
+    let dir = TempDir::new("snapshots");
+    let config = JournalConfig::new(&dir.0).with_snapshots(2, true);
+    assert_eq!(vec![1, 3, 6, 6], run(&config, &[1, 2, 3]));
+
+    // the snapshot covers the first two events, which compaction dropped
+    let journal = FileJournal::open(&dir.0, "counter", Fsync::Never).ok().unwrap();
+    assert_eq!(Some((2, 3u32)), journal.load_snapshot().ok().unwrap());
+    let mut replayed = Vec::new();
+    journal.replay(0, |seq, e: u32| replayed.push((seq, e))).ok().unwrap();
+    assert_eq!(vec![(3, 3)], replayed);
+    drop(journal);
+
+    assert_eq!(vec![10, 10], run(&config, &[4]));
+  }
+
+  #[test]
+  fn test_failed_recovery() {
+    let dir = TempDir::new("failed");
+    // a file where the journal directory should be
+    File::create(&dir.0).unwrap();
+    let config = JournalConfig::new(&dir.0);
+
+    let dispatcher: CallingThreadDispatcher<Msg, Err> = CallingThreadDispatcher::new();
+    let sums = Arc::new(Mutex::new(Vec::new()));
+    let uri = dispatcher.subscribe(Box::new(Persistent::new(Counter::new(sums.clone()), config)), None).path().clone();
+    // the default supervisor resumes, and the next message retries
+    dispatcher.send_to(&uri, Msg::Ping(1)).ok().unwrap();
+    dispatcher.send_to(&uri, Msg::Ping(1)).ok().unwrap();
+    assert!(sums.lock().unwrap().is_empty());
+    fs::remove_file(&dir.0).unwrap();
+  }
+}
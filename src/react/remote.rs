@@ -0,0 +1,421 @@
+//!
+//! TCP transport between actor systems.
+//!
+//! Every message travels as one frame: a 4-byte big-endian length followed
+//! by the target path and the message, encoded with the codecs of the
+//! transport under the type tag and version of the message. A system keeps
+//! one outgoing connection per peer, written by its own thread, which
+//! reconnects with a growing backoff when the connection fails. Messages
+//! sent while a peer is down wait for the reconnect.
+//!
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::marker::PhantomData;
+use std::net::{self, IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rustc_serialize::json::Json;
+
+use err::{ReactErr, ReactErrKind};
+use super::{MsgTrait, Error};
+use super::actor::ActorUri;
+use super::codec::{self, Codecs, codec_err};
+use super::dispatcher::Dispatcher;
+
+/// Frames longer than this are refused, as they are most likely garbage.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+const MIN_BACKOFF_MS: u64 = 10;
+const MAX_BACKOFF_MS: u64 = 1000;
+
+pub fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+  if payload.len() > MAX_FRAME_LEN {
+    return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too long"));
+  }
+
+  let len = payload.len() as u32;
+  let header = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+  w.write_all(&header)?;
+  w.write_all(payload)?;
+  w.flush()
+}
+
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+  let mut header = [0u8; 4];
+  r.read_exact(&mut header)?;
+  let len = header.iter().fold(0usize, |len, b| (len << 8) | *b as usize);
+  if len > MAX_FRAME_LEN {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too long"));
+  }
+
+  let mut payload = vec![0u8; len];
+  r.read_exact(&mut payload)?;
+  Ok(payload)
+}
+
+/// Encodes a message for the actor at `path`, or for every accepting actor
+/// if `path` is `None`.
+fn encode<M: MsgTrait>(codecs: &Codecs, path: Option<&str>, m: &M) -> Result<Vec<u8>, ReactErr> {
+  codecs.encode(M::type_tag(), M::version(), &(path, m))
+}
+
+fn decode<M: MsgTrait>(codecs: &Codecs, payload: &[u8]) -> Result<(Option<String>, M), ReactErr> {
+  let (header, value) = codecs.decode_value(payload)?;
+  let mut pair = match value {
+    Json::Array(pair) => pair,
+    _ => Vec::new()
+  };
+  if pair.len() != 2 {
+    return Err(codec_err("a frame without a path and a message"));
+  }
+  let m = codec::decode_msg_value(&header, pair.pop().unwrap())?;
+  let path = codec::from_json(pair.pop().unwrap())?;
+  Ok((path, m))
+}
+
+struct Peer {
+  tx: Sender<Vec<u8>>,
+  /// Makes the writer give up on the frames it still holds.
+  closed: Arc<AtomicBool>,
+  thread: JoinHandle<()>
+}
+
+pub struct Transport<M: MsgTrait> {
+  local_addr: SocketAddr,
+  stop: Arc<AtomicBool>,
+  peers: Mutex<HashMap<String, Peer>>,
+  /// The open accepted connections by id, to close them on shutdown.
+  inbound: Arc<Mutex<HashMap<usize, TcpStream>>>,
+  listener: Option<JoinHandle<()>>,
+  codecs: Arc<Codecs>,
+  _msg: PhantomData<M>
+}
+
+impl<M: MsgTrait> Transport<M> {
+  /// Listens on `host:port` and delivers the incoming messages to
+  /// `dispatcher`. Port 0 picks a free port. It sends JSON.
+  pub fn bind<E: Error>(host: &str, port: i32, dispatcher: Weak<Box<Dispatcher<M, E>>>)
+      -> Result<Transport<M>, ReactErr> {
+    Transport::bind_with_codecs(host, port, dispatcher, Arc::new(Codecs::json()))
+  }
+
+  /// Like `bind`, but sends with the default codec of `codecs`, and accepts
+  /// every codec it has.
+  pub fn bind_with_codecs<E: Error>(host: &str, port: i32, dispatcher: Weak<Box<Dispatcher<M, E>>>,
+                                    codecs: Arc<Codecs>) -> Result<Transport<M>, ReactErr> {
+    let listener = TcpListener::bind(&format!("{}:{}", host, port)[..])?;
+    let local_addr = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let inbound = Arc::new(Mutex::new(HashMap::new()));
+
+    let accept_stop = stop.clone();
+    let accept_inbound = inbound.clone();
+    let accept_codecs = codecs.clone();
+    let thread = thread::Builder::new()
+      .name(format!("react-remote-{}", local_addr.port()))
+      .spawn(move || accept(listener, accept_stop, accept_inbound, dispatcher, accept_codecs))?;
+
+    Ok(Transport {
+      local_addr: local_addr,
+      stop: stop,
+      peers: Mutex::new(HashMap::new()),
+      inbound: inbound,
+      listener: Some(thread),
+      codecs: codecs,
+      _msg: PhantomData
+    })
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  /// Sends `m` to the actor at `to` in the system listening on the host and
+  /// port of `to`.
+  pub fn send_to(&self, to: &ActorUri, m: &M) -> Result<(), ReactErr> {
+    let frame = encode(&self.codecs, Some(to.path()), m)?;
+    self.post(to.host_name(), to.port(), frame)
+  }
+
+  /// Sends `m` to every accepting actor of the system at `host:port`.
+  pub fn publish(&self, host: &str, port: i32, m: &M) -> Result<(), ReactErr> {
+    let frame = encode(&self.codecs, None, m)?;
+    self.post(host, port, frame)
+  }
+
+  fn post(&self, host: &str, port: i32, frame: Vec<u8>) -> Result<(), ReactErr> {
+    let addr = format!("{}:{}", host, port);
+    let mut peers = self.peers.lock().unwrap();
+    if !peers.contains_key(&addr) {
+      let (tx, rx) = mpsc::channel();
+      let stop = self.stop.clone();
+      let closed = Arc::new(AtomicBool::new(false));
+      let peer_closed = closed.clone();
+      let peer_addr = addr.clone();
+      let thread = thread::Builder::new()
+        .name(format!("react-remote-{}", addr))
+        .spawn(move || write_loop(peer_addr, rx, stop, peer_closed))?;
+      peers.insert(addr.clone(), Peer { tx: tx, closed: closed, thread: thread });
+    }
+
+    peers[&addr].tx.send(frame).map_err(|_| ReactErr::new(ReactErrKind::Disconnected))
+  }
+
+  /// Closes the connection to `host:port` and drops the messages still
+  /// waiting for it, for a peer that is gone for good.
+  pub fn forget(&self, host: &str, port: i32) {
+    if let Some(peer) = self.peers.lock().unwrap().remove(&format!("{}:{}", host, port)) {
+      peer.closed.store(true, Ordering::SeqCst);
+    }
+  }
+}
+
+impl<M: MsgTrait> Drop for Transport<M> {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::SeqCst);
+
+    // wake up the listener, which is blocked in accept
+    let ip = match self.local_addr.ip() {
+      IpAddr::V4(ref ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+      ip => ip
+    };
+    let _ = TcpStream::connect(SocketAddr::new(ip, self.local_addr.port()));
+    if let Some(thread) = self.listener.take() {
+      if thread.join().is_err() {
+        error!("the listener thread panicked");
+      }
+    }
+
+    for (_, stream) in self.inbound.lock().unwrap().drain() {
+      let _ = stream.shutdown(net::Shutdown::Both);
+    }
+    for (_, peer) in self.peers.lock().unwrap().drain() {
+      peer.closed.store(true, Ordering::SeqCst);
+      drop(peer.tx);
+      if peer.thread.join().is_err() {
+        error!("a writer thread panicked");
+      }
+    }
+  }
+}
+
+fn accept<M, E>(listener: TcpListener, stop: Arc<AtomicBool>, inbound: Arc<Mutex<HashMap<usize, TcpStream>>>,
+                dispatcher: Weak<Box<Dispatcher<M, E>>>, codecs: Arc<Codecs>)
+    where M: MsgTrait, E: Error {
+  for (id, stream) in listener.incoming().enumerate() {
+    if stop.load(Ordering::SeqCst) {
+      break;
+    }
+
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(e) => {
+        warn!("cannot accept a connection: {}", e);
+        continue;
+      }
+    };
+    match stream.try_clone() {
+      Ok(clone) => {
+        inbound.lock().unwrap().insert(id, clone);
+      }
+      Err(e) => warn!("cannot track a connection: {}", e)
+    }
+
+    let inbound = inbound.clone();
+    let dispatcher = dispatcher.clone();
+    let codecs = codecs.clone();
+    thread::spawn(move || {
+      read_loop(stream, dispatcher, codecs);
+      // the connection is over, and so is its clone
+      if let Some(clone) = inbound.lock().unwrap().remove(&id) {
+        let _ = clone.shutdown(net::Shutdown::Both);
+      }
+    });
+  }
+}
+
+fn read_loop<M, E>(mut stream: TcpStream, dispatcher: Weak<Box<Dispatcher<M, E>>>, codecs: Arc<Codecs>)
+    where M: MsgTrait, E: Error {
+  loop {
+    let payload = match read_frame(&mut stream) {
+      Ok(payload) => payload,
+      Err(e) => {
+        if e.kind() != io::ErrorKind::UnexpectedEof {
+          debug!("closing a connection: {}", e);
+        }
+        return;
+      }
+    };
+    let dispatcher = match dispatcher.upgrade() {
+      Some(dispatcher) => dispatcher,
+      None => return
+    };
+
+    let res = decode(&codecs, &payload).and_then(|(path, m): (Option<String>, M)| {
+      match path {
+        Some(path) => dispatcher.send_to(&ActorUri::local(&path), m),
+        None => dispatcher.send(m)
+      }
+    });
+    if let Err(e) = res {
+      warn!("cannot deliver a remote message: {}", e);
+    }
+  }
+}
+
+fn write_loop(addr: String, rx: Receiver<Vec<u8>>, stop: Arc<AtomicBool>, closed: Arc<AtomicBool>) {
+  let mut stream: Option<TcpStream> = None;
+  let mut backoff = MIN_BACKOFF_MS;
+
+  // ends once the transport drops the sender
+  for frame in rx.iter() {
+    loop {
+      if stop.load(Ordering::SeqCst) || closed.load(Ordering::SeqCst) {
+        return;
+      }
+
+      if stream.is_none() {
+        match TcpStream::connect(&addr[..]) {
+          Ok(s) => {
+            let _ = s.set_nodelay(true);
+            stream = Some(s);
+            backoff = MIN_BACKOFF_MS;
+          }
+          Err(e) => {
+            debug!("cannot connect to {}, retrying in {}ms: {}", addr, backoff, e);
+            thread::sleep(Duration::from_millis(backoff));
+            backoff = cmp::min(backoff * 2, MAX_BACKOFF_MS);
+            continue;
+          }
+        }
+      }
+
+      match write_frame(stream.as_mut().unwrap(), &frame) {
+        Ok(()) => break,
+        Err(e) => {
+          warn!("lost the connection to {}: {}", addr, e);
+          stream = None;
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+  use std::net::TcpStream;
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use react::ActorSystem;
+  use react::actor::ActorUri;
+  use react::codec::{Codecs, BinaryCodec};
+  use react::dispatcher::{Dispatcher, AsyncDispatcher};
+  use react::dispatcher::tests::{Msg, Err, Recorder, wait_until};
+  use super::{Transport, read_frame, write_frame};
+
+  #[test]
+  fn test_framing() {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, b"hello").unwrap();
+    write_frame(&mut buf, b"").unwrap();
+    assert_eq!(&[0, 0, 0, 5], &buf[..4]);
+
+    let mut r = Cursor::new(buf);
+    assert_eq!(b"hello".to_vec(), read_frame(&mut r).unwrap());
+    assert_eq!(Vec::<u8>::new(), read_frame(&mut r).unwrap());
+    assert!(read_frame(&mut r).is_err());
+  }
+
+  fn listening(received: Arc<Mutex<Vec<u32>>>) -> (ActorSystem<Msg, Err>, ActorUri) {
+    listening_with(received, Codecs::json())
+  }
+
+  fn listening_with(received: Arc<Mutex<Vec<u32>>>, codecs: Codecs) -> (ActorSystem<Msg, Err>, ActorUri) {
+    let mut system = ActorSystem::new("server");
+    let path = system.dispatcher().subscribe(Box::new(Recorder::new(received)), None).path().clone();
+    let addr = system.listen_with_codecs("127.0.0.1", 0, Arc::new(codecs)).ok().unwrap();
+    (system, ActorUri::new("127.0.0.1", addr.port() as i32, path.path()))
+  }
+
+  #[test]
+  fn test_remote_send() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let (_server, uri) = listening(received.clone());
+
+    let mut client: ActorSystem<Msg, Err> = ActorSystem::new("client");
+    client.listen("127.0.0.1", 0).ok().unwrap();
+    for i in 0..100 {
+      client.send_to(&uri, Msg::Ping(i)).ok().unwrap();
+    }
+    wait_until(|| received.lock().unwrap().len() == 100);
+    assert_eq!((0..100).collect::<Vec<u32>>(), *received.lock().unwrap());
+  }
+
+  #[test]
+  fn test_mixed_codecs() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let (_server, uri) = listening_with(received.clone(), Codecs::new(Box::new(BinaryCodec)));
+
+    // the server only reads binary, so it drops what the JSON client sends
+    let mut json: ActorSystem<Msg, Err> = ActorSystem::new("json");
+    json.listen("127.0.0.1", 0).ok().unwrap();
+    json.send_to(&uri, Msg::Ping(1)).ok().unwrap();
+
+    let mut binary: ActorSystem<Msg, Err> = ActorSystem::new("binary");
+    binary.listen_with_codecs("127.0.0.1", 0, Arc::new(Codecs::binary())).ok().unwrap();
+    binary.send_to(&uri, Msg::Ping(2)).ok().unwrap();
+    wait_until(|| !received.lock().unwrap().is_empty());
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(vec![2], *received.lock().unwrap());
+  }
+
+  #[test]
+  fn test_closed_connection() {
+    let dispatcher: Arc<Box<Dispatcher<Msg, Err>>> = Arc::new(Box::new(AsyncDispatcher::new()));
+    let transport: Transport<Msg> = Transport::bind("127.0.0.1", 0, Arc::downgrade(&dispatcher)).ok().unwrap();
+
+    let client = TcpStream::connect(transport.local_addr()).unwrap();
+    wait_until(|| transport.inbound.lock().unwrap().len() == 1);
+    // the server lets go of a connection the client closes
+    drop(client);
+    wait_until(|| transport.inbound.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_reconnect() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let (server, uri) = listening(received.clone());
+    let mut client: ActorSystem<Msg, Err> = ActorSystem::new("client");
+    client.listen("127.0.0.1", 0).ok().unwrap();
+
+    client.send_to(&uri, Msg::Ping(0)).ok().unwrap();
+    wait_until(|| received.lock().unwrap().len() == 1);
+    drop(server);
+
+    // a new server on the same port
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut server: ActorSystem<Msg, Err> = ActorSystem::new("server");
+    server.dispatcher().subscribe(Box::new(Recorder::new(received.clone())), None);
+    server.listen("127.0.0.1", uri.port()).ok().unwrap();
+
+    // messages written into the dead connection are lost until the client
+    // notices and reconnects
+    let start = Instant::now();
+    let mut i = 1;
+    while received.lock().unwrap().is_empty() {
+      assert!(start.elapsed() < Duration::from_secs(5), "no reconnect");
+      client.send_to(&uri, Msg::Ping(i)).ok().unwrap();
+      i += 1;
+      thread::sleep(Duration::from_millis(10));
+    }
+  }
+}
@@ -0,0 +1,137 @@
+//!
+//! Reply channels for request/response style messaging.
+//!
+//! An ask creates a pair of `ReplyTo` and `ReplyHandle`. The `ReplyTo` half
+//! travels with the message to the actors, and the requester keeps the
+//! `ReplyHandle` to wait for or poll the answer.
+//!
+
+use std::sync::mpsc::{self, Sender, Receiver, TryRecvError, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use err::{ReactErr, ReactErrKind};
+
+pub fn channel<M: Send>(timeout: Duration) -> (ReplyTo<M>, ReplyHandle<M>) {
+  let (tx, rx) = mpsc::channel();
+  let reply_to = ReplyTo {
+    tx: tx
+  };
+  let handle = ReplyHandle {
+    rx: rx,
+    deadline: Instant::now() + timeout,
+    failure: None
+  };
+
+  (reply_to, handle)
+}
+
+pub struct ReplyTo<M: Send> {
+  tx: Sender<M>
+}
+
+impl<M: Send> ReplyTo<M> {
+  pub fn send(&self, m: M) -> Result<(), ReactErr> {
+    self.tx.send(m).map_err(|_| ReactErr::new(ReactErrKind::Disconnected))
+  }
+}
+
+impl<M: Send> Clone for ReplyTo<M> {
+  fn clone(&self) -> ReplyTo<M> {
+    ReplyTo {
+      tx: self.tx.clone()
+    }
+  }
+}
+
+pub struct ReplyHandle<M: Send> {
+  rx: Receiver<M>,
+  deadline: Instant,
+  failure: Option<ReactErr>
+}
+
+impl<M: Send> ReplyHandle<M> {
+  /// Makes the handle fail with `err`, for an ask that could not be sent.
+  pub fn fail(mut self, err: ReactErr) -> ReplyHandle<M> {
+    self.failure = Some(err);
+    self
+  }
+
+  /// Blocks until a reply arrives or the deadline of the ask passes.
+  pub fn wait(self) -> Result<M, ReactErr> {
+    if let Some(err) = self.failure {
+      return Err(err);
+    }
+
+    let now = Instant::now();
+    let remain = if self.deadline > now {
+      self.deadline - now
+    } else {
+      Duration::from_millis(0)
+    };
+
+    match self.rx.recv_timeout(remain) {
+      Ok(m) => Ok(m),
+      Err(RecvTimeoutError::Timeout) => Err(ReactErr::timeout()),
+      Err(RecvTimeoutError::Disconnected) => Err(ReactErr::new(ReactErrKind::NoReply))
+    }
+  }
+
+  /// Returns the reply if it has already arrived, or `None` if it is still
+  /// pending. Fails once the deadline has passed.
+  pub fn poll(&self) -> Result<Option<M>, ReactErr> {
+    if let Some(ref err) = self.failure {
+      return Err(err.clone());
+    }
+
+    match self.rx.try_recv() {
+      Ok(m) => Ok(Some(m)),
+      Err(TryRecvError::Empty) => {
+        if Instant::now() >= self.deadline {
+          Err(ReactErr::timeout())
+        } else {
+          Ok(None)
+        }
+      }
+      Err(TryRecvError::Disconnected) => Err(ReactErr::new(ReactErrKind::NoReply))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::thread;
+  use std::time::Duration;
+
+  use err::ReactErrKind;
+  use super::channel;
+
+  #[test]
+  fn test_wait() {
+    let (reply_to, handle) = channel(Duration::from_secs(5));
+    thread::spawn(move || {
+      reply_to.send(7).ok().unwrap();
+    });
+    assert_eq!(7, handle.wait().ok().unwrap());
+  }
+
+  #[test]
+  fn test_poll() {
+    let (reply_to, handle) = channel(Duration::from_secs(5));
+    assert_eq!(None, handle.poll().ok().unwrap());
+    reply_to.send(7).ok().unwrap();
+    assert_eq!(Some(7), handle.poll().ok().unwrap());
+  }
+
+  #[test]
+  fn test_timeout() {
+    let (_reply_to, handle) = channel::<i32>(Duration::from_millis(10));
+    assert_eq!(&ReactErrKind::Timeout, handle.wait().err().unwrap().kind());
+  }
+
+  #[test]
+  fn test_no_reply() {
+    let (reply_to, handle) = channel::<i32>(Duration::from_secs(5));
+    drop(reply_to);
+    assert_eq!(&ReactErrKind::NoReply, handle.poll().err().unwrap().kind());
+  }
+}
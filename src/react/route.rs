@@ -1,5 +1,397 @@
+//!
+//! Routers spread the messages sent to them over a group of routees.
+//!
+//! A `RouterActor` is subscribed like any other actor, and forwards every
+//! message it receives to the routees its `Router` picks. Asks are forwarded
+//! with their reply channel, so that a routee answers the original sender.
+//!
 
-pub trait Router<M: 'static + Sync + Send + Decodable, E: 'static + Sized + Sync + Send> {
-  fn tx(&self, m: &M) -> Result<(), E>;
-  fn rx(&self) -> Result<M, E>;
-}
\ No newline at end of file
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Weak;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use err::ReactErrKind;
+use super::{MsgTrait, Error};
+use super::actor::{Actor, ActorContext, ActorUri};
+use super::dispatcher::Dispatcher;
+
+/// The number of points each routee gets on the ring of `ConsistentHash`.
+pub const VIRTUAL_NODES: usize = 100;
+
+/// The routees of a router, as seen by its `Router`.
+pub struct Routees<'a> {
+  uris: &'a [ActorUri],
+  mailbox_len: &'a Fn(&ActorUri) -> usize
+}
+
+impl<'a> Routees<'a> {
+  pub fn new(uris: &'a [ActorUri], mailbox_len: &'a Fn(&ActorUri) -> usize) -> Routees<'a> {
+    Routees {
+      uris: uris,
+      mailbox_len: mailbox_len
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.uris.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.uris.is_empty()
+  }
+
+  pub fn uris(&self) -> &[ActorUri] {
+    self.uris
+  }
+
+  /// The number of messages waiting for the routee at `idx`.
+  pub fn mailbox_len(&self, idx: usize) -> usize {
+    (self.mailbox_len)(&self.uris[idx])
+  }
+}
+
+pub trait Router<M: MsgTrait>: Send + Sync {
+  /// Picks the routees that get `m`, by their index in `routees`.
+  fn route(&mut self, m: &M, routees: &Routees) -> Vec<usize>;
+}
+
+/// Sends each message to the next routee in turn.
+pub struct RoundRobin {
+  next: usize
+}
+
+impl RoundRobin {
+  pub fn new() -> RoundRobin {
+    RoundRobin {
+      next: 0
+    }
+  }
+}
+
+impl<M: MsgTrait> Router<M> for RoundRobin {
+  fn route(&mut self, _: &M, routees: &Routees) -> Vec<usize> {
+    if routees.is_empty() {
+      return Vec::new();
+    }
+
+    let idx = self.next % routees.len();
+    self.next = idx + 1;
+    vec![idx]
+  }
+}
+
+/// Sends each message to a routee picked at random.
+pub struct Random {
+  state: u64
+}
+
+impl Random {
+  pub fn new() -> Random {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    Random::with_seed(now.as_secs() ^ now.subsec_nanos() as u64)
+  }
+
+  pub fn with_seed(seed: u64) -> Random {
+    Random {
+      // xorshift never leaves zero
+      state: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed }
+    }
+  }
+
+  /// The next number of the sequence.
+  pub fn next(&mut self) -> u64 {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 7;
+    self.state ^= self.state << 17;
+    self.state
+  }
+}
+
+impl<M: MsgTrait> Router<M> for Random {
+  fn route(&mut self, _: &M, routees: &Routees) -> Vec<usize> {
+    if routees.is_empty() {
+      return Vec::new();
+    }
+
+    vec![(self.next() % routees.len() as u64) as usize]
+  }
+}
+
+/// Sends each message to every routee.
+pub struct Broadcast;
+
+impl<M: MsgTrait> Router<M> for Broadcast {
+  fn route(&mut self, _: &M, routees: &Routees) -> Vec<usize> {
+    (0..routees.len()).collect()
+  }
+}
+
+/// Sends the messages with the same key to the same routee. When a routee
+/// joins or leaves, only the keys of that routee move.
+pub struct ConsistentHash<M, K: Hash> {
+  key: Box<Fn(&M) -> K + Send + Sync>,
+  /// The routees the ring was built for.
+  uris: Vec<ActorUri>,
+  ring: BTreeMap<u64, usize>
+}
+
+impl<M, K: Hash> ConsistentHash<M, K> {
+  /// Routes on the key that `key` extracts from a message.
+  pub fn new(key: Box<Fn(&M) -> K + Send + Sync>) -> ConsistentHash<M, K> {
+    ConsistentHash {
+      key: key,
+      uris: Vec::new(),
+      ring: BTreeMap::new()
+    }
+  }
+
+  fn build(&mut self, uris: &[ActorUri]) {
+    self.ring.clear();
+    for (idx, uri) in uris.iter().enumerate() {
+      for vnode in 0..VIRTUAL_NODES {
+        self.ring.insert(hash(&(uri.display(), vnode)), idx);
+      }
+    }
+    self.uris = uris.to_vec();
+  }
+}
+
+fn hash<T: Hash>(t: &T) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  t.hash(&mut hasher);
+  hasher.finish()
+}
+
+impl<M: MsgTrait, K: Hash> Router<M> for ConsistentHash<M, K> {
+  fn route(&mut self, m: &M, routees: &Routees) -> Vec<usize> {
+    if routees.is_empty() {
+      return Vec::new();
+    }
+    if self.uris.as_slice() != routees.uris() {
+      self.build(routees.uris());
+    }
+
+    let h = hash(&(self.key)(m));
+    let point = self.ring.range(h..).next().or_else(|| self.ring.iter().next());
+    point.map(|(_, idx)| *idx).into_iter().collect()
+  }
+}
+
+/// Sends each message to the routee with the fewest waiting messages.
+pub struct SmallestMailbox;
+
+impl<M: MsgTrait> Router<M> for SmallestMailbox {
+  fn route(&mut self, _: &M, routees: &Routees) -> Vec<usize> {
+    (0..routees.len()).min_by_key(|idx| routees.mailbox_len(*idx)).into_iter().collect()
+  }
+}
+
+/// An actor that forwards its messages to routees subscribed to the same
+/// dispatcher. The routees should not accept messages by themselves, so
+/// that they only get what the router forwards.
+pub struct RouterActor<M: MsgTrait + Clone, E: Error> {
+  context: ActorContext<M>,
+  router: Box<Router<M>>,
+  routees: Vec<ActorUri>,
+  dispatcher: Weak<Box<Dispatcher<M, E>>>
+}
+
+impl<M: MsgTrait + Clone, E: Error> RouterActor<M, E> {
+  pub fn new(router: Box<Router<M>>, routees: Vec<ActorUri>,
+             dispatcher: Weak<Box<Dispatcher<M, E>>>) -> RouterActor<M, E> {
+    RouterActor {
+      context: ActorContext::new(),
+      router: router,
+      routees: routees,
+      dispatcher: dispatcher
+    }
+  }
+
+  pub fn routees(&self) -> &[ActorUri] {
+    &self.routees
+  }
+}
+
+impl<M: MsgTrait + Clone, E: Error> Actor<M, E> for RouterActor<M, E> {
+  fn context(&self) -> &ActorContext<M> {
+    &self.context
+  }
+
+  fn on_receive(&mut self, m: &M) -> Result<(), E> {
+    let dispatcher = match self.dispatcher.upgrade() {
+      Some(dispatcher) => dispatcher,
+      None => return Ok(())
+    };
+
+    let targets = {
+      let mailbox_len = |uri: &ActorUri| dispatcher.mailbox_len(uri).unwrap_or(usize::max_value());
+      let routees = Routees::new(&self.routees, &mailbox_len);
+      self.router.route(m, &routees)
+    };
+    if targets.is_empty() {
+      warn!("no routee for a message to the router");
+    }
+
+    let reply_to = self.context.reply_to();
+    let mut gone = Vec::new();
+    for idx in targets {
+      let uri = &self.routees[idx];
+      if let Err(e) = dispatcher.forward(uri, m.clone(), reply_to.clone()) {
+        warn!("cannot route to {}: {}", uri.display(), e);
+        if let ReactErrKind::UnknownActor(_) = *e.kind() {
+          gone.push(uri.clone());
+        }
+      }
+    }
+    // routees that were unsubscribed or stopped leave the group
+    self.routees.retain(|uri| !gone.contains(uri));
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use react::{ActorSystem, PoolDispatcher};
+  use react::actor::{Actor, ActorUri};
+  use react::dispatcher::tests::{Msg, Err, Echo, Recorder, wait_until};
+  use super::{Router, Routees, RoundRobin, Random, Broadcast, ConsistentHash, SmallestMailbox};
+
+  fn uris(n: usize) -> Vec<ActorUri> {
+    (0..n).map(|i| ActorUri::local(&format!("user/{}", i))).collect()
+  }
+
+  fn route<R: Router<Msg>>(router: &mut R, m: &Msg, uris: &[ActorUri]) -> Vec<usize> {
+    let mailbox_len = |_: &ActorUri| 0;
+    router.route(m, &Routees::new(uris, &mailbox_len))
+  }
+
+  fn recorders(system: &ActorSystem<Msg, Err>, router: Box<Router<Msg>>, n: usize)
+      -> (ActorUri, Vec<Arc<Mutex<Vec<u32>>>>) {
+    let received: Vec<_> = (0..n).map(|_| Arc::new(Mutex::new(Vec::new()))).collect();
+    let routees = received.iter().map(|r| Box::new(Recorder::new(r.clone())) as Box<Actor<Msg, Err>>).collect();
+    let uri = system.router(router, routees).path().clone();
+    (uri, received)
+  }
+
+  #[test]
+  fn test_round_robin() {
+    let system = ActorSystem::with_dispatcher("test", Box::new(PoolDispatcher::new(2)));
+    let (uri, received) = recorders(&system, Box::new(RoundRobin::new()), 3);
+
+    for i in 0..6 {
+      system.dispatcher().send_to(&uri, Msg::Ping(i)).ok().unwrap();
+    }
+    wait_until(|| received.iter().all(|r| r.lock().unwrap().len() == 2));
+    assert_eq!(vec![0, 3], *received[0].lock().unwrap());
+    assert_eq!(vec![1, 4], *received[1].lock().unwrap());
+    assert_eq!(vec![2, 5], *received[2].lock().unwrap());
+  }
+
+  #[test]
+  fn test_broadcast() {
+    let system = ActorSystem::new("test");
+    let (uri, received) = recorders(&system, Box::new(Broadcast), 3);
+
+    for i in 0..3 {
+      system.dispatcher().send_to(&uri, Msg::Ping(i)).ok().unwrap();
+    }
+    wait_until(|| received.iter().all(|r| r.lock().unwrap().len() == 3));
+  }
+
+  #[test]
+  fn test_random() {
+    let mut router = Random::with_seed(7);
+    let uris = uris(3);
+    let mut counts = vec![0; 3];
+    for i in 0..300 {
+      for idx in route(&mut router, &Msg::Ping(i), &uris) {
+        counts[idx] += 1;
+      }
+    }
+    assert_eq!(300, counts.iter().sum::<usize>());
+    assert!(counts.iter().all(|c| *c > 50), "counts: {:?}", counts);
+  }
+
+  #[test]
+  fn test_consistent_hash() {
+    let mut router = ConsistentHash::new(Box::new(|m: &Msg| {
+      match *m {
+        Msg::Ping(i) => i % 10,
+        _ => 0
+      }
+    }));
+    let three = uris(3);
+    let before: Vec<_> = (0..100).map(|i| route(&mut router, &Msg::Ping(i), &three)).collect();
+    for i in 0..100 {
+      assert_eq!(before[(i % 10) as usize], before[i as usize]);
+    }
+
+    // a new routee only takes keys over, the others keep theirs
+    let four = uris(4);
+    for i in 0..100 {
+      let after = route(&mut router, &Msg::Ping(i), &four);
+      assert!(after == before[i as usize] || after == vec![3]);
+    }
+  }
+
+  #[test]
+  fn test_smallest_mailbox() {
+    let uris = uris(3);
+    let mailbox_len = |uri: &ActorUri| if uri.path() == "user/1" { 0 } else { 5 };
+    let routees = Routees::new(&uris, &mailbox_len);
+    assert_eq!(vec![1], SmallestMailbox.route(&Msg::Ping(0), &routees));
+  }
+
+  #[test]
+  fn test_ask() {
+    let system = ActorSystem::new("test");
+    let routees: Vec<Box<Actor<Msg, Err>>> = vec![Box::new(Echo::new()), Box::new(Echo::new())];
+    system.router(Box::new(SmallestMailbox), routees);
+
+    // the router is the only actor accepting broadcast messages
+    let reply = system.dispatcher().ask(Msg::Ping(1), Duration::from_secs(5));
+    assert_eq!(Msg::Pong(1), reply.wait().ok().unwrap());
+  }
+
+  #[test]
+  fn test_smallest_mailbox_queued() {
+    let system = ActorSystem::new("test");
+    let (uri, received) = recorders(&system, Box::new(SmallestMailbox), 2);
+    let dispatcher = system.dispatcher();
+    let sleeper = dispatcher.subscribe(Box::new(Echo::new()), None);
+    let busy = ActorUri::local("user/0");
+
+    // the queue holds the routed ping before the two pings of routee 0
+    sleeper.tell(Msg::Sleep(100)).ok().unwrap();
+    dispatcher.send_to(&uri, Msg::Ping(1)).ok().unwrap();
+    dispatcher.send_to(&busy, Msg::Ping(100)).ok().unwrap();
+    dispatcher.send_to(&busy, Msg::Ping(101)).ok().unwrap();
+    assert_eq!(2, dispatcher.mailbox_len(&busy).ok().unwrap());
+    assert_eq!(0, dispatcher.mailbox_len(&ActorUri::local("user/1")).ok().unwrap());
+
+    wait_until(|| received[1].lock().unwrap().len() == 1);
+    wait_until(|| received[0].lock().unwrap().len() == 2);
+    assert_eq!(vec![1], *received[1].lock().unwrap());
+    assert_eq!(0, dispatcher.mailbox_len(&busy).ok().unwrap());
+  }
+
+  #[test]
+  fn test_unsubscribed_routee() {
+    let system = ActorSystem::new("test");
+    let (uri, received) = recorders(&system, Box::new(RoundRobin::new()), 2);
+    let routee = ActorUri::local("user/0");
+    system.dispatcher().unsubscribe(&routee).ok().unwrap();
+
+    for i in 0..4 {
+      system.dispatcher().send_to(&uri, Msg::Ping(i)).ok().unwrap();
+    }
+    wait_until(|| received[1].lock().unwrap().len() == 3);
+    assert_eq!(vec![1, 2, 3], *received[1].lock().unwrap());
+  }
+}
@@ -0,0 +1,370 @@
+//!
+//! Delayed and periodic messages.
+//!
+//! A scheduler keeps its timers in a heap ordered by deadline, and fires
+//! them from a single thread that sleeps until the earliest one is due.
+//! Cancelled timers are dropped when they come up. Actors schedule their
+//! own messages through a `SchedulerRef`.
+//!
+
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, Condvar, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::{MsgTrait, Error};
+use super::actor::{ActorUri, ActorRef};
+use super::dispatcher::Dispatcher;
+
+/// Cancels a scheduled message. Clones cancel the same timer.
+#[derive(Clone)]
+pub struct Cancellable {
+  cancelled: Arc<AtomicBool>
+}
+
+impl Cancellable {
+  fn new() -> Cancellable {
+    Cancellable {
+      cancelled: Arc::new(AtomicBool::new(false))
+    }
+  }
+
+  /// Stops the timer. A message already being delivered still arrives.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+}
+
+enum Target<M: MsgTrait> {
+  /// Every accepting actor.
+  All,
+  Uri(ActorUri),
+  Ref(ActorRef<M>)
+}
+
+struct Timer<M: MsgTrait> {
+  deadline: Instant,
+  /// Keeps timers with the same deadline in the order they were scheduled.
+  seq: u64,
+  interval: Option<Duration>,
+  to: Target<M>,
+  /// Makes the message of each firing, or `None` once there is no more.
+  make: Box<FnMut() -> Option<M> + Send>,
+  handle: Cancellable
+}
+
+impl<M: MsgTrait> PartialEq for Timer<M> {
+  fn eq(&self, other: &Timer<M>) -> bool {
+    self.deadline == other.deadline && self.seq == other.seq
+  }
+}
+
+impl<M: MsgTrait> Eq for Timer<M> {}
+
+impl<M: MsgTrait> PartialOrd for Timer<M> {
+  fn partial_cmp(&self, other: &Timer<M>) -> Option<CmpOrdering> {
+    Some(self.cmp(other))
+  }
+}
+
+// the heap pops the greatest, so the earliest deadline compares greatest
+impl<M: MsgTrait> Ord for Timer<M> {
+  fn cmp(&self, other: &Timer<M>) -> CmpOrdering {
+    (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+  }
+}
+
+struct Timers<M: MsgTrait> {
+  heap: BinaryHeap<Timer<M>>,
+  seq: u64,
+  stopped: bool
+}
+
+struct Shared<M: MsgTrait> {
+  timers: Mutex<Timers<M>>,
+  cond: Condvar
+}
+
+impl<M: MsgTrait> Shared<M> {
+  fn schedule(&self, delay: Duration, interval: Option<Duration>, to: Target<M>,
+              make: Box<FnMut() -> Option<M> + Send>) -> Cancellable {
+    let handle = Cancellable::new();
+    let mut timers = self.timers.lock().unwrap();
+    timers.seq += 1;
+    let timer = Timer {
+      deadline: Instant::now() + delay,
+      seq: timers.seq,
+      interval: interval,
+      to: to,
+      make: make,
+      handle: handle.clone()
+    };
+    timers.heap.push(timer);
+    self.cond.notify_one();
+    handle
+  }
+}
+
+/// A handle for actors to schedule messages to themselves or to others. It
+/// does not keep the scheduler alive, and once the scheduler is gone, the
+/// timers it returns are cancelled right away.
+pub struct SchedulerRef<M: MsgTrait> {
+  shared: Weak<Shared<M>>
+}
+
+impl<M: MsgTrait> SchedulerRef<M> {
+  /// Sends `m` to the actor of `to` once `delay` has passed.
+  pub fn tell_after(&self, delay: Duration, to: &ActorRef<M>, m: M) -> Cancellable {
+    let mut m = Some(m);
+    match self.shared.upgrade() {
+      Some(shared) => shared.schedule(delay, None, Target::Ref(to.clone()), Box::new(move || m.take())),
+      None => {
+        let handle = Cancellable::new();
+        handle.cancel();
+        handle
+      }
+    }
+  }
+}
+
+impl<M: MsgTrait> Clone for SchedulerRef<M> {
+  fn clone(&self) -> SchedulerRef<M> {
+    SchedulerRef {
+      shared: self.shared.clone()
+    }
+  }
+}
+
+pub struct Scheduler<M: MsgTrait, E: Error> {
+  shared: Arc<Shared<M>>,
+  thread: Option<JoinHandle<()>>,
+  _dispatcher: PhantomData<E>
+}
+
+impl<M: MsgTrait, E: Error> Scheduler<M, E> {
+  /// Starts the timer thread, which delivers to `dispatcher`.
+  pub fn new(dispatcher: Weak<Box<Dispatcher<M, E>>>) -> Scheduler<M, E> {
+    let shared = Arc::new(Shared {
+      timers: Mutex::new(Timers {
+        heap: BinaryHeap::new(),
+        seq: 0,
+        stopped: false
+      }),
+      cond: Condvar::new()
+    });
+
+    let timer_shared = shared.clone();
+    let thread = thread::Builder::new()
+      .name("react-scheduler".to_owned())
+      .spawn(move || run(timer_shared, dispatcher))
+      .unwrap();
+
+    Scheduler {
+      shared: shared,
+      thread: Some(thread),
+      _dispatcher: PhantomData
+    }
+  }
+
+  /// A handle for actors, which they can keep.
+  pub fn handle(&self) -> SchedulerRef<M> {
+    SchedulerRef {
+      shared: Arc::downgrade(&self.shared)
+    }
+  }
+
+  /// Sends `m` to every accepting actor once `delay` has passed.
+  pub fn send_after(&self, delay: Duration, m: M) -> Cancellable {
+    let mut m = Some(m);
+    self.shared.schedule(delay, None, Target::All, Box::new(move || m.take()))
+  }
+
+  /// Sends `m` to the actor at `to` once `delay` has passed.
+  pub fn send_to_after(&self, delay: Duration, to: &ActorUri, m: M) -> Cancellable {
+    let mut m = Some(m);
+    self.shared.schedule(delay, None, Target::Uri(to.clone()), Box::new(move || m.take()))
+  }
+
+  /// The number of timers waiting, including cancelled ones that have not
+  /// come up yet.
+  pub fn pending(&self) -> usize {
+    self.shared.timers.lock().unwrap().heap.len()
+  }
+}
+
+impl<M: MsgTrait + Clone, E: Error> Scheduler<M, E> {
+  /// Sends `m` to every accepting actor every `interval`, starting after
+  /// the first interval.
+  pub fn send_every(&self, interval: Duration, m: M) -> Cancellable {
+    self.shared.schedule(interval, Some(interval), Target::All, Box::new(move || Some(m.clone())))
+  }
+
+  /// Sends `m` to the actor at `to` every `interval`, starting after the
+  /// first interval.
+  pub fn send_to_every(&self, interval: Duration, to: &ActorUri, m: M) -> Cancellable {
+    self.shared.schedule(interval, Some(interval), Target::Uri(to.clone()), Box::new(move || Some(m.clone())))
+  }
+}
+
+impl<M: MsgTrait, E: Error> Drop for Scheduler<M, E> {
+  fn drop(&mut self) {
+    self.shared.timers.lock().unwrap().stopped = true;
+    self.shared.cond.notify_one();
+    if let Some(thread) = self.thread.take() {
+      if thread.join().is_err() {
+        error!("the scheduler thread panicked");
+      }
+    }
+  }
+}
+
+fn run<M: MsgTrait, E: Error>(shared: Arc<Shared<M>>, dispatcher: Weak<Box<Dispatcher<M, E>>>) {
+  loop {
+    let mut timer = {
+      let mut timers = shared.timers.lock().unwrap();
+      loop {
+        if timers.stopped {
+          return;
+        }
+
+        let now = Instant::now();
+        let wait = match timers.heap.peek() {
+          Some(timer) if timer.deadline <= now => None,
+          Some(timer) => Some(timer.deadline - now),
+          // anything scheduled notifies the condition
+          None => Some(Duration::from_secs(3600))
+        };
+        match wait {
+          Some(wait) => timers = shared.cond.wait_timeout(timers, wait).unwrap().0,
+          None => break
+        }
+      }
+      timers.heap.pop().unwrap()
+    };
+
+    if timer.handle.is_cancelled() {
+      continue;
+    }
+    let dispatcher = match dispatcher.upgrade() {
+      Some(dispatcher) => dispatcher,
+      None => return
+    };
+
+    if let Some(m) = (timer.make)() {
+      let res = match timer.to {
+        Target::All => dispatcher.send(m),
+        Target::Uri(ref to) => dispatcher.send_to(to, m),
+        Target::Ref(ref to) => to.tell(m)
+      };
+      if let Err(e) = res {
+        warn!("cannot deliver a scheduled message: {}", e);
+        // nobody is left to receive the next ones
+        match timer.to {
+          Target::All => {}
+          _ => timer.handle.cancel()
+        }
+      }
+    }
+
+    if let Some(interval) = timer.interval {
+      if !timer.handle.is_cancelled() {
+        // a late timer skips the firings it has missed
+        let now = Instant::now();
+        timer.deadline = if timer.deadline + interval > now {
+          timer.deadline + interval
+        } else {
+          now + interval
+        };
+        shared.timers.lock().unwrap().heap.push(timer);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::{Duration, Instant};
+
+  use react::ActorSystem;
+  use react::dispatcher::tests::{Msg, Err, Recorder, wait_until};
+
+  fn recording() -> (ActorSystem<Msg, Err>, Arc<Mutex<Vec<u32>>>) {
+    let system = ActorSystem::new("test");
+    let received = Arc::new(Mutex::new(Vec::new()));
+    system.dispatcher().subscribe(Box::new(Recorder::new(received.clone())), None);
+    (system, received)
+  }
+
+  #[test]
+  fn test_send_after() {
+    let (system, received) = recording();
+    let start = Instant::now();
+    system.send_after(Duration::from_millis(100), Msg::Ping(2));
+    system.send_after(Duration::from_millis(50), Msg::Ping(1));
+
+    wait_until(|| received.lock().unwrap().len() == 2);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(vec![1, 2], *received.lock().unwrap());
+  }
+
+  #[test]
+  fn test_send_every() {
+    let (system, received) = recording();
+    let handle = system.send_every(Duration::from_millis(10), Msg::Ping(0));
+
+    wait_until(|| received.lock().unwrap().len() >= 5);
+    handle.cancel();
+    thread::sleep(Duration::from_millis(30));
+    let count = received.lock().unwrap().len();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(count, received.lock().unwrap().len());
+    assert_eq!(0, system.scheduler().pending());
+  }
+
+  #[test]
+  fn test_cancel() {
+    let (system, received) = recording();
+    let handle = system.send_after(Duration::from_millis(50), Msg::Ping(1));
+    system.send_after(Duration::from_millis(100), Msg::Ping(2));
+    handle.cancel();
+
+    wait_until(|| received.lock().unwrap().len() == 1);
+    assert_eq!(vec![2], *received.lock().unwrap());
+  }
+
+  #[test]
+  fn test_send_to_after() {
+    let system: ActorSystem<Msg, Err> = ActorSystem::new("test");
+    let r1 = Arc::new(Mutex::new(Vec::new()));
+    let r2 = Arc::new(Mutex::new(Vec::new()));
+    system.dispatcher().subscribe(Box::new(Recorder::new(r1.clone())), None);
+    let uri = system.dispatcher().subscribe(Box::new(Recorder::new(r2.clone())), None).path().clone();
+
+    system.scheduler().send_to_after(Duration::from_millis(10), &uri, Msg::Ping(1));
+    wait_until(|| r2.lock().unwrap().len() == 1);
+    assert!(r1.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_tell_after() {
+    let (system, received) = recording();
+    let recorder = system.dispatcher().subscribe(Box::new(Recorder::new(received.clone())),
+                                                 Some(Box::new(|_: &Msg| false)));
+    let scheduler = system.scheduler().handle();
+    scheduler.tell_after(Duration::from_millis(10), &recorder, Msg::Ping(1));
+    wait_until(|| received.lock().unwrap().len() == 1);
+
+    // a handle outliving its scheduler only hands out cancelled timers
+    drop(system);
+    assert!(scheduler.tell_after(Duration::from_millis(10), &recorder, Msg::Ping(2)).is_cancelled());
+  }
+}
@@ -0,0 +1,259 @@
+//!
+//! Supervision decides what happens to an actor whose `on_receive` fails.
+//!
+//! The decider of a `Supervisor` is called with the error and answers with
+//! a `Directive`. Restarts are limited: an actor that needs more than
+//! `max_retries` restarts within `within` is stopped instead.
+//!
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{MsgTrait, Error};
+use super::actor::Actor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Directive {
+  /// Keep the actor and its state, and go on with the next message.
+  Resume,
+  /// Replace the actor with a fresh instance built by the factory.
+  Restart,
+  /// Stop this actor and its children. It receives no more messages.
+  Stop,
+  /// Fail the parent with the error, as if it were its own, or the
+  /// dispatcher for an actor without one.
+  Escalate
+}
+
+pub type Decider<E> = Fn(&E) -> Directive + Send + Sync;
+pub type Factory<M, E> = Fn() -> Box<Actor<M, E>> + Send + Sync;
+
+pub const DEFAULT_MAX_RETRIES: usize = 10;
+
+pub struct Supervisor<M: MsgTrait, E: Error> {
+  decider: Box<Decider<E>>,
+  factory: Option<Box<Factory<M, E>>>,
+  max_retries: usize,
+  within: Duration,
+  restarts: VecDeque<Instant>
+}
+
+impl<M: MsgTrait, E: Error> Supervisor<M, E> {
+  pub fn new(decider: Box<Decider<E>>) -> Supervisor<M, E> {
+    Supervisor {
+      decider: decider,
+      factory: None,
+      max_retries: DEFAULT_MAX_RETRIES,
+      within: Duration::from_secs(60),
+      restarts: VecDeque::new()
+    }
+  }
+
+  /// Resumes the actor on every error. This is what `subscribe` uses.
+  pub fn resume() -> Supervisor<M, E> {
+    Supervisor::new(Box::new(|_: &E| Directive::Resume))
+  }
+
+  pub fn stop() -> Supervisor<M, E> {
+    Supervisor::new(Box::new(|_: &E| Directive::Stop))
+  }
+
+  pub fn escalate() -> Supervisor<M, E> {
+    Supervisor::new(Box::new(|_: &E| Directive::Escalate))
+  }
+
+  /// Restarts the actor with instances built by `factory` on every error.
+  pub fn restart(factory: Box<Factory<M, E>>) -> Supervisor<M, E> {
+    Supervisor::new(Box::new(|_: &E| Directive::Restart)).with_factory(factory)
+  }
+
+  pub fn with_factory(mut self, factory: Box<Factory<M, E>>) -> Supervisor<M, E> {
+    self.factory = Some(factory);
+    self
+  }
+
+  /// Allows at most `max_retries` restarts within `within`.
+  pub fn with_limit(mut self, max_retries: usize, within: Duration) -> Supervisor<M, E> {
+    self.max_retries = max_retries;
+    self.within = within;
+    self
+  }
+
+  /// Decides on `err`. `Restart` turns into `Stop` when there is no factory
+  /// or the restart limit has been reached.
+  pub fn decide(&mut self, err: &E) -> Directive {
+    let directive = (self.decider)(err);
+    self.limit(directive)
+  }
+
+  /// Applies the restart limits to a directive decided elsewhere, like by
+  /// the parent of the actor.
+  pub fn limit(&mut self, directive: Directive) -> Directive {
+    match directive {
+      Directive::Restart => {
+        if self.factory.is_none() {
+          warn!("no actor factory to restart with, stopping the actor");
+          Directive::Stop
+        } else if !self.allow_restart() {
+          warn!("more than {} restarts within {:?}, stopping the actor",
+            self.max_retries, self.within);
+          Directive::Stop
+        } else {
+          Directive::Restart
+        }
+      }
+      directive => directive
+    }
+  }
+
+  pub fn new_actor(&self) -> Option<Box<Actor<M, E>>> {
+    self.factory.as_ref().map(|f| f())
+  }
+
+  fn allow_restart(&mut self) -> bool {
+    let now = Instant::now();
+    while self.restarts.front().map_or(false, |t| now.duration_since(*t) > self.within) {
+      self.restarts.pop_front();
+    }
+
+    if self.restarts.len() < self.max_retries {
+      self.restarts.push_back(now);
+      true
+    } else {
+      false
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use err::ReactErrKind;
+  use react::actor::{Actor, ActorContext};
+  use react::dispatcher::{Dispatcher, AsyncDispatcher, PoolDispatcher};
+  use react::dispatcher::tests::{Msg, Err};
+  use super::{Directive, Supervisor};
+
+  /// Answers pings with the number of messages it has seen so far.
+  pub struct Counter {
+    context: ActorContext<Msg>,
+    count: u32
+  }
+
+  impl Counter {
+    pub fn new() -> Counter {
+      Counter {
+        context: ActorContext::new(),
+        count: 0
+      }
+    }
+  }
+
+  impl Actor<Msg, Err> for Counter {
+    fn context(&self) -> &ActorContext<Msg> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Msg) -> Result<(), Err> {
+      self.count += 1;
+      match *m {
+        Msg::Fail => Err(Err::Fatal),
+        _ => {
+          self.context.reply(Msg::Pong(self.count)).ok().unwrap();
+          Ok(())
+        }
+      }
+    }
+  }
+
+  fn ping<D: Dispatcher<Msg, Err>>(dispatcher: &D) -> Result<Msg, ReactErrKind> {
+    dispatcher.ask(Msg::Ping(0), Duration::from_secs(5)).wait().map_err(|e| e.kind().clone())
+  }
+
+  #[test]
+  fn test_resume() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe(Box::new(Counter::new()), None);
+
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    assert_eq!(Ok(Msg::Pong(2)), ping(&dispatcher));
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_restart() {
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    dispatcher.subscribe_supervised(Box::new(Counter::new()), None,
+      Supervisor::restart(Box::new(|| Box::new(Counter::new()))));
+
+    assert_eq!(Ok(Msg::Pong(1)), ping(&dispatcher));
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    assert_eq!(Ok(Msg::Pong(1)), ping(&dispatcher));
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_restart_limit() {
+    let mut dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    let supervisor = Supervisor::restart(Box::new(|| Box::new(Counter::new())))
+      .with_limit(2, Duration::from_secs(60));
+    dispatcher.subscribe_supervised(Box::new(Counter::new()), None, supervisor);
+
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    assert_eq!(Ok(Msg::Pong(1)), ping(&dispatcher));
+
+    // the third failure exceeds the limit and stops the actor
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    assert_eq!(Err(ReactErrKind::NoReply), ping(&dispatcher));
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_stop() {
+    let mut dispatcher: PoolDispatcher<Msg, Err> = PoolDispatcher::new(2);
+    dispatcher.subscribe_supervised(Box::new(Counter::new()), None, Supervisor::stop());
+    dispatcher.subscribe(Box::new(Counter::new()), Some(Box::new(|m: &Msg| *m != Msg::Fail)));
+
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    // only the second actor is left to answer
+    assert_eq!(Ok(Msg::Pong(1)), ping(&dispatcher));
+    assert_eq!(Ok(Msg::Pong(2)), ping(&dispatcher));
+
+    dispatcher.stop();
+    dispatcher.join().ok().unwrap();
+  }
+
+  #[test]
+  fn test_escalate() {
+    let dispatcher: AsyncDispatcher<Msg, Err> = AsyncDispatcher::new();
+    dispatcher.subscribe_supervised(Box::new(Counter::new()), None, Supervisor::escalate());
+
+    dispatcher.send(Msg::Fail).ok().unwrap();
+    assert!(dispatcher.join().is_err());
+  }
+
+  #[test]
+  fn test_decider() {
+    let mut supervisor: Supervisor<Msg, Err> = Supervisor::new(Box::new(|e: &Err| {
+      match *e {
+        Err::Fatal => Directive::Escalate,
+        Err::Panicked(_) => Directive::Restart
+      }
+    }));
+    assert_eq!(Directive::Escalate, supervisor.decide(&Err::Fatal));
+  }
+
+  #[test]
+  fn test_restart_without_factory() {
+    let mut supervisor: Supervisor<Msg, Err> = Supervisor::new(Box::new(|_: &Err| Directive::Restart));
+    assert_eq!(Directive::Stop, supervisor.decide(&Err::Panicked("boom".to_owned())));
+  }
+}
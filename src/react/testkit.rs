@@ -0,0 +1,267 @@
+//!
+//! Helpers for testing actors.
+//!
+//! A `TestProbe` is an actor that hands what it receives over to the test,
+//! which then asserts on it with timeouts instead of sleeps. A `TestKit`
+//! owns the actor system of a test, and shuts it down at the end.
+//!
+
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{ActorSystem, MsgTrait, Error, Predicate};
+use super::actor::{Actor, ActorContext, ActorRef, ActorUri};
+use super::dispatcher::{Dispatcher, CallingThreadDispatcher};
+
+struct ProbeActor<M: MsgTrait> {
+  context: ActorContext<M>,
+  tx: Mutex<Sender<M>>
+}
+
+impl<M: MsgTrait + Clone, E: Error> Actor<M, E> for ProbeActor<M> {
+  fn context(&self) -> &ActorContext<M> {
+    &self.context
+  }
+
+  fn on_receive(&mut self, m: &M) -> Result<(), E> {
+    // the probe may be gone before the actor
+    let _ = self.tx.lock().unwrap().send(m.clone());
+    Ok(())
+  }
+}
+
+/// Receives messages like any actor, and lets the test expect them. The
+/// expectations panic when they are not met.
+pub struct TestProbe<M: MsgTrait> {
+  actor_ref: ActorRef<M>,
+  rx: Receiver<M>
+}
+
+impl<M: MsgTrait + Clone> TestProbe<M> {
+  /// Subscribes a probe that accepts every message.
+  pub fn new<E: Error>(dispatcher: &Dispatcher<M, E>) -> TestProbe<M> {
+    TestProbe::with_filter(dispatcher, None)
+  }
+
+  pub fn with_filter<E: Error>(dispatcher: &Dispatcher<M, E>, filter: Option<Box<Predicate<M>>>)
+      -> TestProbe<M> {
+    let (tx, rx) = mpsc::channel();
+    let actor: ProbeActor<M> = ProbeActor {
+      context: ActorContext::new(),
+      tx: Mutex::new(tx)
+    };
+    let actor_ref = dispatcher.subscribe(Box::new(actor), filter);
+
+    TestProbe {
+      actor_ref: actor_ref,
+      rx: rx
+    }
+  }
+
+  /// The address of the probe actor.
+  pub fn uri(&self) -> &ActorUri {
+    self.actor_ref.path()
+  }
+
+  /// A handle to the probe actor, to pass where a reference is expected.
+  pub fn actor_ref(&self) -> &ActorRef<M> {
+    &self.actor_ref
+  }
+
+  /// Returns the next message, waiting at most `timeout` for it.
+  pub fn expect_msg(&self, timeout: Duration) -> M {
+    match self.rx.recv_timeout(timeout) {
+      Ok(m) => m,
+      Err(RecvTimeoutError::Timeout) => panic!("no message within {:?}", timeout),
+      Err(RecvTimeoutError::Disconnected) => panic!("the probe actor is gone")
+    }
+  }
+
+  /// Checks that no message arrives within `duration`.
+  pub fn expect_no_msg(&self, duration: Duration) {
+    if let Ok(_) = self.rx.recv_timeout(duration) {
+      panic!("unexpected message within {:?}", duration);
+    }
+  }
+
+  /// Returns the next `n` messages, which must all arrive within `timeout`.
+  pub fn receive_n(&self, n: usize, timeout: Duration) -> Vec<M> {
+    let deadline = Instant::now() + timeout;
+    (0..n).map(|i| {
+      match self.rx.recv_timeout(remaining(deadline)) {
+        Ok(m) => m,
+        Err(_) => panic!("received {} of {} messages within {:?}", i, n, timeout)
+      }
+    }).collect()
+  }
+
+  /// Skips messages until one satisfies `f`, and returns it. It must
+  /// arrive within `timeout`.
+  pub fn fish_for_message<F: Fn(&M) -> bool>(&self, timeout: Duration, f: F) -> M {
+    let deadline = Instant::now() + timeout;
+    loop {
+      match self.rx.recv_timeout(remaining(deadline)) {
+        Ok(m) => {
+          if f(&m) {
+            return m;
+          }
+        }
+        Err(_) => panic!("no matching message within {:?}", timeout)
+      }
+    }
+  }
+}
+
+fn remaining(deadline: Instant) -> Duration {
+  let now = Instant::now();
+  if deadline > now {
+    deadline - now
+  } else {
+    Duration::from_millis(0)
+  }
+}
+
+/// How long `TestKit` waits for the dispatcher of its system to stop.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Owns the actor system of a test, and shuts it down when dropped.
+pub struct TestKit<M: MsgTrait, E: Error> {
+  system: Option<ActorSystem<M, E>>
+}
+
+impl<M: MsgTrait, E: Error> TestKit<M, E> {
+  /// A system on the default dispatcher.
+  pub fn new() -> TestKit<M, E> {
+    TestKit::with_system(ActorSystem::new("test"))
+  }
+
+  /// A system on a `CallingThreadDispatcher`, which handles every message
+  /// before `send` returns.
+  pub fn calling_thread() -> TestKit<M, E> {
+    TestKit::with_system(ActorSystem::with_dispatcher("test", Box::new(CallingThreadDispatcher::new())))
+  }
+
+  pub fn with_system(system: ActorSystem<M, E>) -> TestKit<M, E> {
+    TestKit {
+      system: Some(system)
+    }
+  }
+
+  pub fn system(&self) -> &ActorSystem<M, E> {
+    self.system.as_ref().unwrap()
+  }
+
+  pub fn dispatcher(&self) -> Arc<Box<Dispatcher<M, E>>> {
+    self.system().dispatcher()
+  }
+
+  /// Stops the system, and waits until all its actors have stopped. Panics
+  /// if the dispatcher is still held elsewhere after `SHUTDOWN_TIMEOUT`.
+  pub fn shutdown(mut self) {
+    self.stop();
+  }
+
+  fn stop(&mut self) {
+    let system = match self.system.take() {
+      Some(system) => system,
+      None => return
+    };
+    let dispatcher = Arc::downgrade(&system.dispatcher());
+    drop(system);
+
+    // the last holder of the dispatcher stops it when it lets go
+    let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+    while dispatcher.upgrade().is_some() {
+      if Instant::now() >= deadline {
+        panic!("the dispatcher did not stop within {:?}", SHUTDOWN_TIMEOUT);
+      }
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+}
+
+impl<M: MsgTrait + Clone, E: Error> TestKit<M, E> {
+  pub fn probe(&self) -> TestProbe<M> {
+    TestProbe::new(&**self.dispatcher())
+  }
+}
+
+impl<M: MsgTrait, E: Error> Drop for TestKit<M, E> {
+  fn drop(&mut self) {
+    if !thread::panicking() {
+      self.stop();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+  use std::time::Duration;
+
+  use react::actor::tests::Lifecycle;
+  use react::dispatcher::tests::{Msg, Err, Echo};
+  use super::{TestKit, TestProbe};
+
+  #[test]
+  fn test_expect_msg() {
+    let kit: TestKit<Msg, Err> = TestKit::new();
+    let probe = kit.probe();
+    kit.dispatcher().send(Msg::Ping(1)).ok().unwrap();
+    assert_eq!(Msg::Ping(1), probe.expect_msg(Duration::from_secs(5)));
+    probe.expect_no_msg(Duration::from_millis(50));
+  }
+
+  #[test]
+  #[should_panic(expected = "no message")]
+  fn test_expect_msg_timeout() {
+    let kit: TestKit<Msg, Err> = TestKit::new();
+    let probe = kit.probe();
+    probe.expect_msg(Duration::from_millis(10));
+  }
+
+  #[test]
+  #[should_panic(expected = "unexpected message")]
+  fn test_expect_no_msg() {
+    let kit: TestKit<Msg, Err> = TestKit::calling_thread();
+    let probe = kit.probe();
+    kit.dispatcher().send(Msg::Ping(1)).ok().unwrap();
+    probe.expect_no_msg(Duration::from_millis(10));
+  }
+
+  #[test]
+  fn test_receive_n() {
+    let kit: TestKit<Msg, Err> = TestKit::calling_thread();
+    let probe = kit.probe();
+    for i in 0..3 {
+      kit.dispatcher().send(Msg::Ping(i)).ok().unwrap();
+    }
+    assert_eq!(vec![Msg::Ping(0), Msg::Ping(1), Msg::Ping(2)],
+      probe.receive_n(3, Duration::from_secs(5)));
+  }
+
+  #[test]
+  fn test_fish_for_message() {
+    let kit: TestKit<Msg, Err> = TestKit::new();
+    let probe = TestProbe::with_filter(&**kit.dispatcher(),
+      Some(Box::new(|m: &Msg| *m != Msg::Ignore)));
+    kit.dispatcher().subscribe(Box::new(Echo::new()), None);
+
+    kit.dispatcher().send(Msg::Ignore).ok().unwrap();
+    kit.dispatcher().send(Msg::Ping(1)).ok().unwrap();
+    kit.dispatcher().send(Msg::Ping(2)).ok().unwrap();
+    assert_eq!(Msg::Ping(2), probe.fish_for_message(Duration::from_secs(5), |m| *m == Msg::Ping(2)));
+  }
+
+  #[test]
+  fn test_shutdown() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let kit: TestKit<Msg, Err> = TestKit::new();
+    kit.dispatcher().subscribe(Box::new(Lifecycle::new(events.clone())), None);
+
+    kit.shutdown();
+    assert_eq!(vec!["pre_start", "post_stop"], *events.lock().unwrap());
+  }
+}
@@ -2,62 +2,84 @@ extern crate radish;
 extern crate env_logger;
 #[macro_use] extern crate log;
 extern crate rustc_serialize;
-  
-use std::thread;
-use std::time::Duration;
+
+use std::sync::{Arc, Mutex};
 
 use radish::react::{Actor, MsgTrait, Error};
+use radish::react::actor::ActorContext;
+use radish::react::dispatcher::{Dispatcher, CallingThreadDispatcher};
+
+  #[derive(RustcDecodable, RustcEncodable)]
+  pub enum Message {
+    Ask(String),
+    Others
+  }
+
+  impl Message {
+    pub fn ask(message: &str) -> Message {
+      Message::Ask(message.to_owned())
+    }
+  }
+
+  impl MsgTrait for Message {}
+  unsafe impl Send for Message {}
+  unsafe impl Sync for Message {}
 
-  // #[derive(RustcDecodable, RustcEncodable)]
-  // pub enum Message {
-  //   Ask(String),
-  //   Others
-  // }
-
-  // impl Message {
-  //   pub fn ask(message: &str) -> Message {
-  //     Message::Ask(message.to_owned())
-  //   }
-  // }
-
-  // impl MsgTrait for Message {}
-  // unsafe impl Send for Message {}
-  // unsafe impl Sync for Message {}
-
-  // pub struct TestActor;
-
-  // pub enum ActorErr {
-  //   Err(String)
-  // }
-  // impl Error for ActorErr {}
-  // unsafe impl Send for ActorErr {}
-  // unsafe impl Sync for ActorErr {}
-
-  // impl Actor<Message, ActorErr> for TestActor {
-  //   fn on_receive(&mut self, m: &Message) -> Result<(), ActorErr> {
-  //     match *m {
-  //       Message::Ask(ref s) => {
-  //         debug!(">>> received: {}", s);
-  //         Ok(())
-  //       }
-  //       _ => panic!("unknown message")
-  //     }
-  //   }
-  // }
+  pub struct TestActor {
+    context: ActorContext<Message>,
+    received: Arc<Mutex<Vec<String>>>
+  }
+
+  #[derive(Debug, PartialEq)]
+  pub enum ActorErr {
+    Err(String)
+  }
+  impl Error for ActorErr {
+    fn from_panic(message: String) -> ActorErr {
+      ActorErr::Err(message)
+    }
+  }
+  unsafe impl Send for ActorErr {}
+  unsafe impl Sync for ActorErr {}
+
+  impl Actor<Message, ActorErr> for TestActor {
+    fn context(&self) -> &ActorContext<Message> {
+      &self.context
+    }
+
+    fn on_receive(&mut self, m: &Message) -> Result<(), ActorErr> {
+      match *m {
+        Message::Ask(ref s) => {
+          debug!(">>> received: {}", s);
+          self.received.lock().unwrap().push(s.clone());
+          Ok(())
+        }
+        _ => Err(ActorErr::Err("unknown message".to_owned()))
+      }
+    }
+  }
 
   #[test]
   fn test() {
-    env_logger::init().unwrap();
+    let _ = env_logger::init();
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut dispatcher: CallingThreadDispatcher<Message, ActorErr> = CallingThreadDispatcher::new();
+    dispatcher.subscribe(Box::new(TestActor {
+      context: ActorContext::new(),
+      received: received.clone()
+    }), None);
 
-    // let actors: Vec<Box<Actor<Message, ActorErr>>> = vec![Box::new(TestActor)];
-    // let mut dispatcher = Dispatcher::new(actors);
+    dispatcher.send(Message::ask("abc")).ok().unwrap();
+    assert_eq!(vec!["abc".to_owned()], *received.lock().unwrap());
 
-    // let time = Duration::from_secs(5);
-    // dispatcher.send(Message::Ask("abc".to_owned()));
-    // thread::sleep(time);
+    // the default supervisor resumes the actor
+    dispatcher.send(Message::Others).ok().unwrap();
+    dispatcher.send(Message::ask("def")).ok().unwrap();
+    assert_eq!(vec!["abc".to_owned(), "def".to_owned()], *received.lock().unwrap());
 
-    // debug!("after sleep");
-    // dispatcher.stop();
-    // debug!("after stop");
-    // dispatcher.join().ok().unwrap();
-  }
\ No newline at end of file
+    debug!("before stop");
+    dispatcher.stop();
+    debug!("after stop");
+    assert_eq!(0, dispatcher.join().ok().unwrap());
+  }